@@ -0,0 +1,405 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Result;
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::{Method, Request, Response, StatusCode};
+
+use crate::api::find_image_by_hash;
+use crate::auth::{current_user, forbidden_response};
+use crate::context::ServerContext;
+use crate::db::{Album, ImageInfo};
+use crate::gallery::{image_to_json, mark_favourite, user_favourites};
+use crate::manage::{manage_response, ManageError};
+use crate::smart::{smart_album_hits, smart_album_to_json};
+use crate::web::{
+    error_response, html_response, json_response, not_found_response, query_params, Action,
+    WebServer,
+};
+
+/// Resolves the images of an album in its order. Images that aren't part of
+/// the gallery tree at the moment, e.g. because they're in the trash, are
+/// left out until they're back.
+pub fn album_images(context: &ServerContext, album: &Album) -> Vec<Arc<ImageInfo>> {
+    album
+        .hashes
+        .iter()
+        .filter_map(|hash| find_image_by_hash(context, hash))
+        .collect()
+}
+
+/// Builds the summary of an album shared by the html views and the api. The
+/// first image stands in as the cover until one has been picked.
+pub fn album_to_json(album: &Album) -> Json {
+    let mut album_dict = BTreeMap::new();
+    album_dict.insert("id".to_string(), album.id.to_json());
+    album_dict.insert("title".to_string(), album.title.to_json());
+    album_dict.insert("description".to_string(), album.description.to_json());
+    album_dict.insert("imagecount".to_string(), album.hashes.len().to_json());
+    album_dict.insert(
+        "cover".to_string(),
+        album
+            .cover
+            .as_ref()
+            .or_else(|| album.hashes.first())
+            .cloned()
+            .to_json(),
+    );
+    Json::Object(album_dict)
+}
+
+/// Builds the full description of an album, along with its images.
+fn album_details(
+    context: &ServerContext,
+    album: &Album,
+    favourites: &BTreeSet<String>,
+) -> BTreeMap<String, Json> {
+    let mut details = match album_to_json(album) {
+        Json::Object(x) => x,
+        _ => BTreeMap::new(),
+    };
+
+    details.insert(
+        "images".to_string(),
+        Json::Array(
+            album_images(context, album)
+                .iter()
+                .map(|x| mark_favourite(image_to_json(context, x), x, favourites))
+                .collect(),
+        ),
+    );
+
+    details
+}
+
+fn create_album(
+    context: &ServerContext,
+    params: &BTreeMap<String, String>,
+) -> std::result::Result<Json, ManageError> {
+    let title = params
+        .get("title")
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .ok_or(ManageError::BadRequest("No title specified"))?;
+    let description = params
+        .get("description")
+        .map(|x| x.trim().to_string())
+        .unwrap_or_default();
+
+    let id = context
+        .datastore
+        .create_album(title.clone(), description.clone())?;
+
+    Ok(album_to_json(&Album {
+        id,
+        title,
+        description,
+        cover: None,
+        hashes: Vec::new(),
+    }))
+}
+
+/// Changes the title, description or cover of an album, whichever are
+/// given. An empty cover goes back to using the first image.
+fn edit_album(
+    context: &ServerContext,
+    mut album: Album,
+    params: &BTreeMap<String, String>,
+) -> std::result::Result<Json, ManageError> {
+    if let Some(title) = params.get("title") {
+        let title = title.trim();
+        if title.is_empty() {
+            return Err(ManageError::BadRequest("Title can't be empty"));
+        }
+        album.title = title.to_string();
+    }
+    if let Some(description) = params.get("description") {
+        album.description = description.trim().to_string();
+    }
+    if let Some(cover) = params.get("cover") {
+        if !cover.is_empty() && !album.hashes.contains(cover) {
+            return Err(ManageError::BadRequest("Cover must be part of the album"));
+        }
+        album.cover = Some(cover.clone()).filter(|x| !x.is_empty());
+    }
+
+    context.datastore.update_album(album.clone())?;
+
+    Ok(album_to_json(&album))
+}
+
+fn add_image(
+    context: &ServerContext,
+    album: Album,
+    hash: &str,
+) -> std::result::Result<Json, ManageError> {
+    let image =
+        find_image_by_hash(context, hash).ok_or(ManageError::BadRequest("Unknown image"))?;
+    if album.hashes.contains(&image.hash) {
+        return Err(ManageError::Conflict("Image is in that album already"));
+    }
+
+    context
+        .datastore
+        .add_album_image(album.id, image.hash.clone())?;
+
+    Ok(image_to_json(context, &image))
+}
+
+fn remove_image(
+    context: &ServerContext,
+    album: Album,
+    hash: &str,
+) -> std::result::Result<Json, ManageError> {
+    if !album.hashes.iter().any(|x| x == hash) {
+        return Err(ManageError::BadRequest("Image is not in that album"));
+    }
+
+    context
+        .datastore
+        .remove_album_image(album.id, hash.to_string())?;
+
+    let mut result_dict = BTreeMap::new();
+    result_dict.insert("removed".to_string(), hash.to_json());
+    Ok(Json::Object(result_dict))
+}
+
+/// Reorders an album from a comma separated list of hashes, as sent after
+/// images have been dragged around.
+fn order_images(
+    context: &ServerContext,
+    album: Album,
+    hashes: &str,
+) -> std::result::Result<Json, ManageError> {
+    let mut order: Vec<String> = Vec::new();
+    for hash in hashes
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+    {
+        if !album.hashes.iter().any(|x| x == hash) {
+            return Err(ManageError::BadRequest("Image is not in that album"));
+        }
+        if !order.iter().any(|x| x == hash) {
+            order.push(hash.to_string());
+        }
+    }
+
+    context.datastore.order_album_images(album.id, order)?;
+
+    let album = context
+        .datastore
+        .find_album(album.id)?
+        .ok_or(ManageError::Conflict("Album was deleted"))?;
+    Ok(album_to_json(&album))
+}
+
+fn delete_album(context: &ServerContext, album: Album) -> std::result::Result<Json, ManageError> {
+    context.datastore.delete_album(album.id)?;
+
+    let mut result_dict = BTreeMap::new();
+    result_dict.insert("deleted".to_string(), album.id.to_json());
+    Ok(Json::Object(result_dict))
+}
+
+/// Lists the albums along with the smart albums, and shows a single album
+/// with the same grid as the galleries.
+pub struct AlbumAction {}
+
+impl AlbumAction {
+    pub fn new() -> AlbumAction {
+        AlbumAction {}
+    }
+
+    fn list(
+        &self,
+        request: Request,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let albums = match context.datastore.find_albums() {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to read albums"),
+        };
+
+        let smart_albums = match context.datastore.find_smart_albums() {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to read smart albums"),
+        };
+        let mut smart_summaries = Vec::new();
+        for album in &smart_albums {
+            match smart_album_hits(&context, album) {
+                Ok(hits) => smart_summaries.push(smart_album_to_json(album, &hits)),
+                Err(_) => return error_response(request, "Search failed"),
+            }
+        }
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("has_albums".to_string(), (!albums.is_empty()).to_json());
+        result_dict.insert(
+            "albums".to_string(),
+            Json::Array(albums.iter().map(album_to_json).collect()),
+        );
+        result_dict.insert(
+            "has_smart_albums".to_string(),
+            (!smart_summaries.is_empty()).to_json(),
+        );
+        result_dict.insert("smart_albums".to_string(), Json::Array(smart_summaries));
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("albums", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+
+    fn show(
+        &self,
+        request: Request,
+        id: u32,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let album = match context.datastore.find_album(id) {
+            Ok(Some(x)) => x,
+            Ok(None) => return not_found_response(request, "Album not found"),
+            Err(_) => return error_response(request, "Failed to read album"),
+        };
+
+        let favourites = user_favourites(&request, &context);
+        let mut result_dict = album_details(&context, &album, &favourites);
+        result_dict.insert("album_id".to_string(), album.id.to_json());
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("album", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+}
+
+impl Action for AlbumAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/albums/?$|^/album/([0-9]+)$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/albums.html").to_string();
+        server.register_template("albums", tpl_data);
+
+        let tpl_data = include_str!("templates/album.html").to_string();
+        server.register_template("album", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        match caps.get(1).and_then(|x| x.as_str().parse::<u32>().ok()) {
+            Some(id) => self.show(request, id, context, handlebars),
+            None => self.list(request, context, handlebars),
+        }
+    }
+}
+
+/// Lists, creates and changes albums. Reading is open to everyone who can
+/// see the galleries, changes require a logged in user.
+pub struct ApiAlbumAction {}
+
+impl ApiAlbumAction {
+    pub fn new() -> ApiAlbumAction {
+        ApiAlbumAction {}
+    }
+}
+
+impl Action for ApiAlbumAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(
+            r"^/api/v1/albums/?$|^/api/v1/album/([0-9]+)(?:/(edit|delete|add|remove|order))?$",
+        )
+        .unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let id = caps.get(1).and_then(|x| x.as_str().parse::<u32>().ok());
+        let operation = caps.get(2).map(|x| x.as_str());
+        let params = query_params(request.url());
+
+        let album = match id {
+            Some(id) => match context.datastore.find_album(id) {
+                Ok(Some(x)) => Some(x),
+                Ok(None) => return not_found_response(request, "Album not found"),
+                Err(_) => return error_response(request, "Failed to read album"),
+            },
+            None => None,
+        };
+
+        if *request.method() != Method::Post {
+            if operation.is_some() {
+                let _ = request.respond(Response::empty(StatusCode(405)));
+                return Ok(());
+            }
+
+            return match album {
+                Some(album) => {
+                    let favourites = user_favourites(&request, &context);
+                    let details = album_details(&context, &album, &favourites);
+                    json_response(request, &Json::Object(details))
+                }
+                None => match context.datastore.find_albums() {
+                    Ok(albums) => json_response(
+                        request,
+                        &Json::Array(albums.iter().map(album_to_json).collect()),
+                    ),
+                    Err(_) => error_response(request, "Failed to read albums"),
+                },
+            };
+        }
+
+        if current_user(&request, &context).is_none() {
+            return forbidden_response(request, &context);
+        }
+
+        let result = match (album, operation) {
+            (None, _) => create_album(&context, &params),
+            (Some(album), Some("edit")) => edit_album(&context, album, &params),
+            (Some(album), Some("delete")) => delete_album(&context, album),
+            (Some(album), Some("add")) => match params.get("hash") {
+                Some(hash) => add_image(&context, album, hash),
+                None => Err(ManageError::BadRequest("No image specified")),
+            },
+            (Some(album), Some("remove")) => match params.get("hash") {
+                Some(hash) => remove_image(&context, album, hash),
+                None => Err(ManageError::BadRequest("No image specified")),
+            },
+            (Some(album), Some("order")) => match params.get("hashes") {
+                Some(hashes) => order_images(&context, album, hashes),
+                None => Err(ManageError::BadRequest("No order specified")),
+            },
+            _ => Err(ManageError::BadRequest("Unknown operation")),
+        };
+
+        manage_response(request, result)
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::{Method, Request};
+
+use crate::auth::{current_user, forbidden_response};
+use crate::context::ServerContext;
+use crate::db::ImageInfo;
+use crate::file::ImageGallery;
+use crate::gallery::{
+    gallery_page_url, gallery_to_json, image_relative_path, image_to_json, mark_favourite,
+    page_params, stack_to_json, user_favourites, ImageFilter, GALLERY_PAGE_SIZE,
+};
+use crate::map::{bounds_to_json, cluster_hits, hit_bounds, located_params, MAX_ZOOM};
+use crate::metadata::meta_to_json;
+use crate::places::{self, parts_from_captures, place_hits, place_label, place_path};
+use crate::search::{facets, search_images, DEFAULT_SEARCH_LIMIT};
+use crate::sort::{gallery_sort, SortKey, SortOrder};
+use crate::stack::build_stacks;
+use crate::timeline::{buckets_to_json, timeline_page_url, Period};
+use crate::web::{
+    error_response, json_response, not_found_response, query_params, url_decode, Action, WebServer,
+};
+
+/// Finds the gallery at `path`, where an empty path denotes the root gallery.
+fn find_gallery(root_gallery: Arc<ImageGallery>, path: &PathBuf) -> Option<Arc<ImageGallery>> {
+    if path.as_os_str().is_empty() {
+        Some(root_gallery)
+    } else {
+        root_gallery.find_gallery_from_name(path)
+    }
+}
+
+/// Returns the first copy of an image with the given hash that is currently
+/// part of the gallery tree. The image table keeps entries for files that
+/// have since been removed, so these are skipped.
+pub fn find_image_by_hash(context: &ServerContext, hash: &str) -> Option<Arc<ImageInfo>> {
+    let root_gallery = context.get_root_gallery().ok()?;
+    let candidates = context
+        .datastore
+        .find_images_by_hash(hash.to_string())
+        .ok()?;
+
+    candidates
+        .into_iter()
+        .filter_map(|info| {
+            let parent = image_relative_path(context, &info)
+                .parent()
+                .map(|x| x.to_path_buf())
+                .unwrap_or_default();
+            root_gallery.find_image(&parent, &info.name)
+        })
+        .next()
+}
+
+/// Builds the full description of an image, including everything stored
+/// about it in the datastore.
+pub fn image_details(context: &ServerContext, image: &ImageInfo) -> BTreeMap<String, Json> {
+    let mut details = match image_to_json(context, image) {
+        Json::Object(x) => x,
+        _ => BTreeMap::new(),
+    };
+
+    details.insert("id".to_string(), image.id.to_json());
+
+    if let Ok(Some(meta)) = context.datastore.find_image_meta(image.id) {
+        details.insert("meta".to_string(), meta_to_json(&meta));
+    }
+
+    let mut urls = BTreeMap::new();
+    urls.insert(
+        "thumb".to_string(),
+        format!("/image/{}/thumb", image.hash).to_json(),
+    );
+    urls.insert(
+        "preview".to_string(),
+        format!("/image/{}/preview", image.hash).to_json(),
+    );
+    urls.insert(
+        "original".to_string(),
+        format!("/image/{}/original", image.hash).to_json(),
+    );
+    details.insert("urls".to_string(), Json::Object(urls));
+
+    details
+}
+
+/// Changes the settings of a gallery, whichever are given: `sort` and
+/// `order` set the order it and its sub galleries are listed in by default,
+/// `cover` the image shown on its card. Empty values remove them again.
+fn edit_gallery(
+    request: Request,
+    context: &ServerContext,
+    gallery: &ImageGallery,
+    params: &BTreeMap<String, String>,
+) -> Result<()> {
+    if current_user(&request, context).is_none() {
+        return forbidden_response(request, context);
+    }
+
+    let sort = match params.get("sort").map(|x| x.as_str()) {
+        Some("") => Some(None),
+        Some(key) => match SortKey::parse(key) {
+            Some(key) => Some(Some((
+                key.name().to_string(),
+                params.get("order").map_or(false, |x| x == "desc"),
+            ))),
+            None => return error_response(request, "Unknown sort order"),
+        },
+        None => None,
+    };
+
+    let cover = match params.get("cover") {
+        Some(hash) if hash.is_empty() => Some(None),
+        Some(hash) => {
+            if !gallery.all_images().iter().any(|x| x.hash == *hash) {
+                return error_response(request, "Cover must be part of the gallery");
+            }
+            Some(Some(hash.clone()))
+        }
+        None => None,
+    };
+
+    if sort.is_none() && cover.is_none() {
+        return error_response(request, "Nothing to change specified");
+    }
+
+    if let Some(sort) = sort {
+        if context
+            .datastore
+            .set_gallery_sort(gallery.get_path(), sort)
+            .is_err()
+        {
+            return error_response(request, "Failed to save sort order");
+        }
+    }
+
+    if let Some(cover) = cover {
+        if context
+            .datastore
+            .set_gallery_cover(gallery.get_path(), cover)
+            .is_err()
+        {
+            return error_response(request, "Failed to save cover");
+        }
+    }
+
+    let covers = context.datastore.find_gallery_covers().unwrap_or_default();
+    let mut result_dict = match gallery_to_json(context, gallery, &covers) {
+        Json::Object(x) => x,
+        _ => BTreeMap::new(),
+    };
+    result_dict.insert(
+        "sort".to_string(),
+        gallery_sort(context, &gallery.path).to_json(),
+    );
+    json_response(request, &Json::Object(result_dict))
+}
+
+pub struct ApiGalleryAction {}
+
+impl ApiGalleryAction {
+    pub fn new() -> ApiGalleryAction {
+        ApiGalleryAction {}
+    }
+}
+
+impl Action for ApiGalleryAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/api/v1/gallery/?$|^/api/v1/gallery/(.*)$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let root_gallery = match context.get_root_gallery() {
+            Ok(ref x) => x.clone(),
+            Err(_) => return error_response(request, "No root gallery found"),
+        };
+
+        let path: PathBuf = caps
+            .get(1)
+            .map(|x| url_decode(x.as_str().trim_end_matches('/')))
+            .unwrap_or_default()
+            .into();
+
+        let gallery = match find_gallery(root_gallery, &path) {
+            Some(x) => x,
+            None => return not_found_response(request, "Gallery not found"),
+        };
+
+        let params = query_params(request.url());
+        if *request.method() == Method::Post {
+            return edit_gallery(request, &context, &gallery, &params);
+        }
+
+        let covers = context.datastore.find_gallery_covers().unwrap_or_default();
+        let mut result_dict = match gallery_to_json(&context, &gallery, &covers) {
+            Json::Object(x) => x,
+            _ => BTreeMap::new(),
+        };
+        result_dict.insert("parent".to_string(), gallery.get_parent().to_json());
+        result_dict.insert("description".to_string(), gallery.description.to_json());
+        result_dict.insert(
+            "sub_galleries".to_string(),
+            Json::Array(
+                gallery
+                    .sub_galleries
+                    .iter()
+                    .map(|x| gallery_to_json(&context, x, &covers))
+                    .collect(),
+            ),
+        );
+
+        let (offset, limit) = page_params(&params, GALLERY_PAGE_SIZE);
+
+        let filter = ImageFilter::from_query(&params);
+        let sort = SortOrder::from_query(&params, gallery_sort(&context, &gallery.path));
+        let favourites = user_favourites(&request, &context);
+
+        // Stacks are listed like in the gallery page, so that the page can
+        // load more of them while scrolling. Only the requested page is
+        // described.
+        let stacked = params.get("stack").map_or(false, |x| x == "true");
+        let (count, images): (usize, Vec<Json>) = if stacked {
+            let mut stacks = build_stacks(
+                gallery.images.iter().filter(|x| filter.matches(x)),
+                context.stack_window,
+            );
+            sort.sort_stacks(&mut stacks);
+            let page = stacks
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(|x| stack_to_json(&context, x, &favourites))
+                .collect();
+            (stacks.len(), page)
+        } else {
+            let mut images: Vec<Arc<ImageInfo>> = gallery
+                .images
+                .iter()
+                .filter(|x| filter.matches(x))
+                .cloned()
+                .collect();
+            sort.sort_images(&mut images);
+            let page = images
+                .iter()
+                .skip(offset)
+                .take(limit)
+                .map(|x| mark_favourite(image_to_json(&context, x), x, &favourites))
+                .collect();
+            (images.len(), page)
+        };
+
+        result_dict.insert("sort".to_string(), sort.to_json());
+        result_dict.insert("count".to_string(), count.to_json());
+        result_dict.insert("offset".to_string(), offset.to_json());
+        result_dict.insert("limit".to_string(), limit.to_json());
+        result_dict.insert(
+            "next".to_string(),
+            if offset.saturating_add(limit) < count {
+                gallery_page_url(&gallery.get_path(), &params, stacked, offset + limit, limit)
+                    .to_json()
+            } else {
+                Json::Null
+            },
+        );
+        result_dict.insert("images".to_string(), Json::Array(images));
+        result_dict.insert(
+            "subgallerycount".to_string(),
+            gallery.sub_galleries.len().to_json(),
+        );
+        result_dict.insert(
+            "localimagecount".to_string(),
+            gallery.images.len().to_json(),
+        );
+
+        json_response(request, &Json::Object(result_dict))
+    }
+}
+
+pub struct ApiImageAction {}
+
+impl ApiImageAction {
+    pub fn new() -> ApiImageAction {
+        ApiImageAction {}
+    }
+}
+
+impl Action for ApiImageAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/api/v1/image/([0-9A-F]+)$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let hash = match caps.get(1).map(|x| x.as_str()) {
+            Some(x) => x.to_string(),
+            None => return error_response(request, "No hash specified"),
+        };
+
+        let image = match find_image_by_hash(&context, &hash) {
+            Some(x) => x,
+            None => return not_found_response(request, "Image not found"),
+        };
+
+        json_response(request, &Json::Object(image_details(&context, &image)))
+    }
+}
+
+pub struct ApiSearchAction {}
+
+impl ApiSearchAction {
+    pub fn new() -> ApiSearchAction {
+        ApiSearchAction {}
+    }
+}
+
+impl Action for ApiSearchAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/api/v1/search$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        _: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let params = query_params(request.url());
+        let (offset, limit) = page_params(&params, DEFAULT_SEARCH_LIMIT);
+
+        let hits = match search_images(&context, &params) {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Search failed"),
+        };
+
+        let facets = facets(&hits)
+            .into_iter()
+            .map(|facet| {
+                let values = facet
+                    .values
+                    .iter()
+                    .map(|&(ref value, count)| {
+                        let mut value_dict = BTreeMap::new();
+                        value_dict.insert("value".to_string(), value.to_json());
+                        value_dict.insert("count".to_string(), count.to_json());
+                        Json::Object(value_dict)
+                    })
+                    .collect();
+                (facet.name.to_string(), Json::Array(values))
+            })
+            .collect();
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert(
+            "query".to_string(),
+            params.get("q").cloned().unwrap_or_default().to_json(),
+        );
+        result_dict.insert("count".to_string(), hits.len().to_json());
+        result_dict.insert("offset".to_string(), offset.to_json());
+        result_dict.insert("limit".to_string(), limit.to_json());
+        result_dict.insert("facets".to_string(), Json::Object(facets));
+        result_dict.insert(
+            "images".to_string(),
+            Json::Array(
+                hits.iter()
+                    .skip(offset)
+                    .take(limit)
+                    .map(|hit| {
+                        let mut image_dict = match image_to_json(&context, &hit.image) {
+                            Json::Object(x) => x,
+                            _ => BTreeMap::new(),
+                        };
+                        image_dict.insert("meta".to_string(), meta_to_json(&hit.meta));
+                        Json::Object(image_dict)
+                    })
+                    .collect(),
+            ),
+        );
+
+        json_response(request, &Json::Object(result_dict))
+    }
+}
+
+pub struct ApiTimelineAction {}
+
+impl ApiTimelineAction {
+    pub fn new() -> ApiTimelineAction {
+        ApiTimelineAction {}
+    }
+}
+
+impl Action for ApiTimelineAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(
+            r"^/api/v1/timeline/?$|^/api/v1/timeline/([0-9]{4})(?:/([0-9]{2}))?(?:/([0-9]{2}))?/?$",
+        )
+        .unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let period = match Period::from_captures(caps) {
+            Some(x) => x,
+            None => return not_found_response(request, "Invalid date"),
+        };
+
+        let params = query_params(request.url());
+        let (offset, limit) = page_params(&params, DEFAULT_SEARCH_LIMIT);
+
+        let timeline = match context.timeline.read() {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to access timeline"),
+        };
+        let images = timeline.images(period);
+        let favourites = user_favourites(&request, &context);
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("path".to_string(), period.path().to_json());
+        result_dict.insert("label".to_string(), period.label().to_json());
+        result_dict.insert(
+            "parent".to_string(),
+            period.parent().map(|x| x.path()).to_json(),
+        );
+        result_dict.insert("count".to_string(), images.len().to_json());
+        result_dict.insert("offset".to_string(), offset.to_json());
+        result_dict.insert("limit".to_string(), limit.to_json());
+        result_dict.insert("buckets".to_string(), buckets_to_json(&timeline, period));
+        result_dict.insert(
+            "next".to_string(),
+            if offset.saturating_add(limit) < images.len() {
+                timeline_page_url(period, offset + limit, limit).to_json()
+            } else {
+                Json::Null
+            },
+        );
+        result_dict.insert(
+            "images".to_string(),
+            Json::Array(
+                images
+                    .iter()
+                    .skip(offset)
+                    .take(limit)
+                    .map(|x| mark_favourite(image_to_json(&context, x), x, &favourites))
+                    .collect(),
+            ),
+        );
+
+        drop(timeline);
+
+        json_response(request, &Json::Object(result_dict))
+    }
+}
+
+pub struct ApiMapAction {}
+
+impl ApiMapAction {
+    pub fn new() -> ApiMapAction {
+        ApiMapAction {}
+    }
+}
+
+impl Action for ApiMapAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/api/v1/map$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        _: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let params = located_params(&query_params(request.url()));
+        let zoom = params
+            .get("zoom")
+            .and_then(|x| x.parse::<u32>().ok())
+            .unwrap_or(0)
+            .min(MAX_ZOOM);
+
+        let hits = match search_images(&context, &params) {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Search failed"),
+        };
+
+        let clusters = cluster_hits(&hits, zoom)
+            .into_iter()
+            .map(|cluster| {
+                let mut cluster_dict = BTreeMap::new();
+                cluster_dict.insert("count".to_string(), cluster.count.to_json());
+                cluster_dict.insert("latitude".to_string(), cluster.latitude.to_json());
+                cluster_dict.insert("longitude".to_string(), cluster.longitude.to_json());
+                cluster_dict.insert("bounds".to_string(), bounds_to_json(cluster.bounds));
+                cluster_dict.insert(
+                    "image".to_string(),
+                    image_to_json(&context, &cluster.hit.image),
+                );
+                Json::Object(cluster_dict)
+            })
+            .collect();
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("count".to_string(), hits.len().to_json());
+        result_dict.insert("zoom".to_string(), zoom.to_json());
+        result_dict.insert(
+            "bounds".to_string(),
+            hit_bounds(&hits).map(bounds_to_json).unwrap_or(Json::Null),
+        );
+        result_dict.insert("clusters".to_string(), Json::Array(clusters));
+
+        json_response(request, &Json::Object(result_dict))
+    }
+}
+
+pub struct ApiPlacesAction {}
+
+impl ApiPlacesAction {
+    pub fn new() -> ApiPlacesAction {
+        ApiPlacesAction {}
+    }
+}
+
+impl Action for ApiPlacesAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/api/v1/places/?$|^/api/v1/places/([^/]+)(?:/([^/]+))?(?:/([^/]+))?/?$")
+            .unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let parts = parts_from_captures(caps);
+
+        let params = query_params(request.url());
+        let (offset, limit) = page_params(&params, DEFAULT_SEARCH_LIMIT);
+
+        let hits = match place_hits(&context, &parts) {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to find places"),
+        };
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("path".to_string(), place_path(&parts).to_json());
+        result_dict.insert("label".to_string(), place_label(&parts).to_json());
+        result_dict.insert(
+            "parent".to_string(),
+            if parts.is_empty() {
+                Json::Null
+            } else {
+                place_path(&parts[..parts.len() - 1]).to_json()
+            },
+        );
+        result_dict.insert("count".to_string(), hits.len().to_json());
+        result_dict.insert("offset".to_string(), offset.to_json());
+        result_dict.insert("limit".to_string(), limit.to_json());
+        result_dict.insert(
+            "buckets".to_string(),
+            places::buckets_to_json(&hits, &parts),
+        );
+        result_dict.insert(
+            "images".to_string(),
+            Json::Array(
+                hits.iter()
+                    .skip(offset)
+                    .take(limit)
+                    .map(|x| image_to_json(&context, &x.image))
+                    .collect(),
+            ),
+        );
+
+        json_response(request, &Json::Object(result_dict))
+    }
+}
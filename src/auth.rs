@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result};
+
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::hex::{FromHex, ToHex};
+use sha2::Digest;
+use tiny_http::{Header, HeaderField, Method, Request, Response, StatusCode};
+
+use crate::context::ServerContext;
+use crate::web::get_header;
+
+const HASH_SCHEME: &str = "pbkdf2-sha256";
+
+/// Iterations used for new password hashes. Logins are remembered once
+/// checked, so this is only paid for the first request of a user.
+const HASH_ITERATIONS: u32 = 100_000;
+
+/// A password hashed with PBKDF2-HMAC-SHA256, in the form
+/// `pbkdf2-sha256$<iterations>$<salt>$<hash>` with hex encoded salt and hash.
+#[derive(Clone, Debug)]
+pub struct PasswordHash {
+    iterations: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl PasswordHash {
+    pub fn parse(spec: &str) -> Option<PasswordHash> {
+        let mut parts = spec.split('$');
+        if parts.next()? != HASH_SCHEME {
+            return None;
+        }
+        let iterations = parts.next()?.parse::<u32>().ok().filter(|x| *x > 0)?;
+        let salt = parts.next()?.from_hex().ok()?;
+        let hash = parts.next()?.from_hex().ok()?;
+        if parts.next().is_some() || salt.is_empty() || hash.len() != 32 {
+            return None;
+        }
+
+        Some(PasswordHash {
+            iterations,
+            salt,
+            hash,
+        })
+    }
+
+    /// Hashes a password with a random salt.
+    pub fn new(password: &str) -> Result<PasswordHash> {
+        let mut salt = vec![0; 16];
+        File::open("/dev/urandom")?.read_exact(&mut salt)?;
+
+        Ok(PasswordHash {
+            iterations: HASH_ITERATIONS,
+            hash: pbkdf2_sha256(password.as_bytes(), &salt, HASH_ITERATIONS),
+            salt,
+        })
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let hash = pbkdf2_sha256(password.as_bytes(), &self.salt, self.iterations);
+        constant_time_eq(&hash, &self.hash)
+    }
+}
+
+impl fmt::Display for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}${}${}${}",
+            HASH_SCHEME,
+            self.iterations,
+            self.salt.to_hex(),
+            self.hash.to_hex()
+        )
+    }
+}
+
+/// Parses a user list of the form `name:hash,name:hash`, where the second
+/// part is a `PasswordHash` as printed by `hostimg --hash-password`.
+/// Entries that can't be parsed are left out with a warning.
+pub fn parse_users(spec: &str) -> BTreeMap<String, PasswordHash> {
+    spec.split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.trim().splitn(2, ':');
+            let name = parts.next()?.trim();
+            let hash = parts.next()?.trim();
+            if name.is_empty() || hash.is_empty() {
+                return None;
+            }
+
+            match PasswordHash::parse(hash) {
+                Some(x) => Some((name.to_string(), x)),
+                None => {
+                    println!(
+                        "Ignoring user {}, the password hash isn't in the {} format",
+                        name, HASH_SCHEME
+                    );
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn sha256(parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = sha2::Sha256::new();
+    for part in parts {
+        hasher.input(part);
+    }
+
+    hasher.result().to_vec()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = if key.len() > BLOCK_SIZE {
+        sha256(&[key])
+    } else {
+        key.to_vec()
+    };
+    block.resize(BLOCK_SIZE, 0);
+
+    let inner_key: Vec<u8> = block.iter().map(|x| x ^ 0x36).collect();
+    let outer_key: Vec<u8> = block.iter().map(|x| x ^ 0x5c).collect();
+    let inner = sha256(&[&inner_key[..], message]);
+
+    sha256(&[&outer_key[..], &inner[..]])
+}
+
+/// Derives a 32 byte key, which is a single PBKDF2 block.
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut message = salt.to_vec();
+    message.extend_from_slice(&1u32.to_be_bytes());
+
+    let mut block = hmac_sha256(password, &message);
+    let mut result = block.clone();
+    for _ in 1..iterations {
+        block = hmac_sha256(password, &block);
+        for (x, y) in result.iter_mut().zip(&block) {
+            *x ^= y;
+        }
+    }
+
+    result
+}
+
+/// Compares two digests without returning early, so that the time taken
+/// doesn't tell how much of a guess was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Returns the name of the user a request is authenticated as, using HTTP
+/// Basic authentication.
+pub fn current_user(request: &Request, context: &ServerContext) -> Option<String> {
+    let header = request
+        .headers()
+        .iter()
+        .find(|x| x.field.equiv("Authorization"))?;
+
+    let mut parts = header.value.as_str().splitn(2, ' ');
+    if !parts.next()?.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let credentials = parts.next()?.trim().from_base64().ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let mut credentials = credentials.splitn(2, ':');
+    let name = credentials.next()?;
+    let password = credentials.next()?;
+
+    let hash = context.users.get(name)?;
+
+    // Every request is authenticated, so logins that have been checked once
+    // are remembered by a digest rather than running the key derivation again
+    let login = sha256(&[name.as_bytes(), &b":"[..], password.as_bytes()]);
+    if let Ok(logins) = context.verified_logins.lock() {
+        if logins.contains(&login) {
+            return Some(name.to_string());
+        }
+    }
+
+    if !hash.verify(password) {
+        return None;
+    }
+    if let Ok(mut logins) = context.verified_logins.lock() {
+        logins.insert(login);
+    }
+
+    Some(name.to_string())
+}
+
+/// Checks whether a request may be served at all. When no users are
+/// configured the gallery is public, otherwise every request has to be
+/// authenticated.
+pub fn is_allowed(request: &Request, context: &ServerContext) -> bool {
+    context.users.is_empty() || current_user(request, context).is_some()
+}
+
+/// Checks whether `source`, an `Origin` or `Referer` header, points at the
+/// server a request was sent to.
+fn is_same_host(source: &str, host: &str) -> bool {
+    let rest = match source.find("://") {
+        Some(x) => &source[x + 3..],
+        None => return false,
+    };
+    let authority = rest.split(|c| c == '/' || c == '?' || c == '#').next();
+    authority.map_or(false, |x| x.eq_ignore_ascii_case(host.trim()))
+}
+
+/// Checks that a request which may change something was made from one of
+/// our own pages. Browsers send the login along with requests from any site,
+/// e.g. a form posting to the api, but say where those come from. Requests
+/// that don't say, e.g. from scripts, are let through.
+pub fn is_same_origin(request: &Request) -> bool {
+    match *request.method() {
+        Method::Get | Method::Head | Method::Options => return true,
+        _ => {}
+    }
+
+    let source = match get_header(request, "Origin").or_else(|| get_header(request, "Referer")) {
+        Some(x) => x,
+        None => return true,
+    };
+
+    get_header(request, "Host").map_or(false, |host| is_same_host(&source, &host))
+}
+
+pub fn unauthorized_response(request: Request) -> Result<()> {
+    let mut response = Response::empty(StatusCode(401));
+    response.add_header(Header {
+        field: "WWW-Authenticate".parse::<HeaderField>().unwrap(),
+        value: "Basic realm=\"HostIMG\"".parse().unwrap(),
+    });
+    let _ = request.respond(response);
+    Err(Error::new(
+        ErrorKind::PermissionDenied,
+        "Authentication required",
+    ))
+}
+
+/// Refuses a request that changes the library but wasn't made by a logged
+/// in user. Without any users configured such requests are never allowed.
+pub fn forbidden_response(request: Request, context: &ServerContext) -> Result<()> {
+    if !context.users.is_empty() {
+        return unauthorized_response(request);
+    }
+
+    let _ = request.respond(Response::empty(StatusCode(403)));
+    Err(Error::new(
+        ErrorKind::PermissionDenied,
+        "No users configured",
+    ))
+}
+
+pub fn cross_origin_response(request: Request) -> Result<()> {
+    let _ = request.respond(Response::empty(StatusCode(403)));
+    Err(Error::new(
+        ErrorKind::PermissionDenied,
+        "Request from another site",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pbkdf2_sha256() {
+        assert_eq!(
+            pbkdf2_sha256(b"passwd", b"salt", 1).to_hex(),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+    }
+
+    #[test]
+    fn test_password_hash() {
+        let spec = "pbkdf2-sha256$1000$00112233445566778899aabbccddeeff$588372775fb38b5711b6f36b465e293dc7b793e008550ee8a10bb26a8e7f85b8";
+        let hash = PasswordHash::parse(spec).unwrap();
+        assert!(hash.verify("secret"));
+        assert!(!hash.verify("secreT"));
+        assert_eq!(hash.to_string(), spec);
+
+        assert!(PasswordHash::parse(
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        )
+        .is_none());
+        assert!(PasswordHash::parse(
+            "pbkdf2-sha256$0$00$588372775fb38b5711b6f36b465e293dc7b793e008550ee8a10bb26a8e7f85b8"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_is_same_host() {
+        assert!(is_same_host(
+            "https://photos.example.com",
+            "photos.example.com"
+        ));
+        assert!(is_same_host("http://localhost:1234", "localhost:1234"));
+        assert!(is_same_host(
+            "http://Localhost:1234/gallery/2019?q=a",
+            "localhost:1234"
+        ));
+
+        assert!(!is_same_host(
+            "https://evil.example.com",
+            "photos.example.com"
+        ));
+        assert!(!is_same_host("http://localhost:1234", "localhost:4321"));
+        assert!(!is_same_host(
+            "https://photos.example.com.evil.example",
+            "photos.example.com"
+        ));
+        assert!(!is_same_host("null", "photos.example.com"));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}
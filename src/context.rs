@@ -1,14 +1,27 @@
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
 use std::result::Result;
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
+use crate::auth::PasswordHash;
 use crate::db::DataStore;
-use crate::file::ImageGallery;
+use crate::file::{read_description, GalleryModification, ImageGallery};
+use crate::format::OutputFormat;
+use crate::geocode::Geocoder;
+use crate::timeline::Timeline;
+
+/// How long the watcher keeps ignoring a path after a change made by the
+/// server itself, in case the expected event never arrives.
+const SUPPRESSION_TIMEOUT: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub enum ContextError {
     GalleryAccessError,
     GalleryNotSetError,
+    GalleryModificationError(io::Error),
 }
 
 #[derive(Clone)]
@@ -19,10 +32,53 @@ pub struct ServerContext {
     pub gallery_dir: PathBuf,
     pub thumb_dir: PathBuf,
     pub preview_dir: PathBuf,
+    pub upload_dir: PathBuf,
+    pub trash_dir: PathBuf,
+    pub resize_dir: PathBuf,
+
+    /// Widths and heights the resize endpoint accepts.
+    pub resize_sizes: Arc<Vec<u32>>,
+    /// Formats derivatives can be encoded in on this system.
+    pub image_formats: Arc<Vec<OutputFormat>>,
+    /// Whether ffmpeg was found, which is needed to index videos.
+    pub video_support: bool,
+
+    /// Days a deleted image is kept in the trash before being purged.
+    pub trash_retention_days: i64,
+
+    /// Seconds within which consecutive shots are stacked as a burst, or
+    /// zero to only stack files sharing a name.
+    pub stack_window: i64,
+
+    /// Url template of the map tiles, with `{z}`, `{x}` and `{y}` filled in
+    /// by the map page, and the credits shown below it.
+    pub tile_url: String,
+    pub tile_attribution: String,
 
     pub root_gallery: Arc<RwLock<Option<Arc<ImageGallery>>>>,
 
+    /// The images of the gallery tree by capture date, kept in sync by
+    /// `set_root_gallery` and `modify_root_gallery`.
+    pub timeline: Arc<RwLock<Timeline>>,
+
     pub datastore: DataStore,
+
+    /// Resolves the locations of images to place names while indexing.
+    pub geocoder: Arc<Geocoder>,
+
+    /// Files sent here are picked up by the indexing thread.
+    pub indexing_queue: Sender<PathBuf>,
+
+    /// User names mapped to the hash of their password. Empty if the
+    /// gallery is public.
+    pub users: Arc<BTreeMap<String, PasswordHash>>,
+
+    /// Digests of the credentials that have been checked against `users`.
+    pub verified_logins: Arc<Mutex<BTreeSet<Vec<u8>>>>,
+
+    /// Paths changed by the server itself, which the file system watcher
+    /// should leave alone.
+    pub suppressed_paths: Arc<Mutex<BTreeMap<PathBuf, Instant>>>,
 }
 
 impl ServerContext {
@@ -32,11 +88,93 @@ impl ServerContext {
             .write()
             .or(Err(ContextError::GalleryAccessError))?;
 
+        let mut timeline = self
+            .timeline
+            .write()
+            .or(Err(ContextError::GalleryAccessError))?;
+        *timeline = Timeline::from_images(gallery.all_images());
+
         *root_gallery = Some(gallery);
 
         Ok(())
     }
 
+    /// Applies a modification to the gallery tree while holding the lock, so
+    /// that concurrent changes from the web and indexing threads aren't lost.
+    pub fn modify_root_gallery(
+        &self,
+        dir_path: &PathBuf,
+        op: GalleryModification,
+    ) -> Result<(), ContextError> {
+        let mut root_gallery = self
+            .root_gallery
+            .write()
+            .or(Err(ContextError::GalleryAccessError))?;
+
+        // Adding an image can create its gallery along with any missing
+        // parents, e.g. a, a/b and a/b/c at once
+        let (mut new_root, created) = match *root_gallery {
+            Some(ref x) => (
+                x.modify(dir_path, op.clone())
+                    .map_err(ContextError::GalleryModificationError)?,
+                dir_path
+                    .ancestors()
+                    .filter(|path| !path.as_os_str().is_empty())
+                    .map(|path| path.to_path_buf())
+                    .filter(|path| x.find_gallery_from_name(path).is_none())
+                    .collect::<Vec<PathBuf>>(),
+            ),
+            None => return Err(ContextError::GalleryNotSetError),
+        };
+
+        // Galleries that are created for a new image pick up the description
+        // in their directory
+        for path in created {
+            if let Some(description) = read_description(&self.gallery_dir.join(&path)) {
+                new_root = new_root
+                    .modify(&path, GalleryModification::Describe(Some(description)))
+                    .map_err(ContextError::GalleryModificationError)?;
+            }
+        }
+
+        let mut timeline = self
+            .timeline
+            .write()
+            .or(Err(ContextError::GalleryAccessError))?;
+        match op {
+            GalleryModification::Add(info) | GalleryModification::Update(info) => {
+                if new_root.find_image(dir_path, &info.name).is_some() {
+                    timeline.add(info);
+                }
+            }
+            GalleryModification::Remove(info) => timeline.remove(&info),
+            GalleryModification::Describe(_) => {}
+        }
+
+        *root_gallery = Some(new_root);
+
+        Ok(())
+    }
+
+    /// Tells the file system watcher to ignore the next event for `path`.
+    pub fn suppress_watcher(&self, path: &Path) {
+        if let Ok(mut suppressed) = self.suppressed_paths.lock() {
+            suppressed.insert(path.to_path_buf(), Instant::now());
+        }
+    }
+
+    /// Checks whether an event for `path` was caused by the server, and if so
+    /// stops suppressing it.
+    pub fn take_suppressed(&self, path: &Path) -> bool {
+        match self.suppressed_paths.lock() {
+            Ok(mut suppressed) => {
+                suppressed.retain(|_, since| since.elapsed() < SUPPRESSION_TIMEOUT);
+                suppressed.remove(path).is_some()
+            }
+            Err(_) => false,
+        }
+    }
+
     pub fn get_root_gallery(&self) -> Result<Arc<ImageGallery>, ContextError> {
         match self.root_gallery.read() {
             Ok(ref r) => match **r {
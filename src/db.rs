@@ -1,4 +1,5 @@
 use std::cmp::{Ordering, PartialOrd};
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
 use std::path::PathBuf;
 use std::rc::Rc;
@@ -6,6 +7,7 @@ use std::result::Result;
 use std::sync::mpsc;
 use std::thread;
 
+use rusqlite::types::ToSql;
 use rusqlite::Connection;
 
 #[derive(Clone)]
@@ -16,6 +18,25 @@ pub struct ImageInfo {
     pub width: u32,
     pub height: u32,
     pub img_type: String,
+    /// Capture time as a unix timestamp, see `ImageMeta::taken`. Unknown
+    /// until the metadata of the image has been read.
+    pub taken: Option<i64>,
+    /// See `ImageMeta::rating` and `ImageMeta::label`.
+    pub rating: Option<u32>,
+    pub label: Option<String>,
+    /// Size of the file in bytes and its modification time as a unix
+    /// timestamp, as of the last scan.
+    pub size: i64,
+    pub modified: i64,
+}
+
+impl ImageInfo {
+    /// Takes over the metadata the gallery views need from `meta`.
+    pub fn set_meta(&mut self, meta: &ImageMeta) {
+        self.taken = meta.taken;
+        self.rating = meta.rating;
+        self.label = meta.label.clone();
+    }
 }
 
 impl Ord for ImageInfo {
@@ -38,6 +59,216 @@ impl PartialEq for ImageInfo {
 
 impl Eq for ImageInfo {}
 
+/// Metadata read from the image file. `taken` is the capture time as a
+/// unix timestamp, with the camera's local time treated as UTC. Files that
+/// don't record it use their modification time instead.
+#[derive(Clone, Default)]
+pub struct ImageMeta {
+    pub caption: Option<String>,
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub taken: Option<i64>,
+    pub focal_length: Option<f64>,
+    pub aperture: Option<f64>,
+    pub exposure: Option<String>,
+    pub iso: Option<u32>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Resolved from the location when the image is indexed.
+    pub place: Option<Place>,
+    /// Length of a video in seconds.
+    pub duration: Option<f64>,
+    /// Stars from 1 to 5 and colour label, as kept in XMP sidecars.
+    pub rating: Option<u32>,
+    pub label: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// Names of the area an image was taken in, see `Geocoder`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Place {
+    pub country: String,
+    pub region: String,
+    pub city: String,
+}
+
+/// Criteria for `DataStore::search_images`. The text is an FTS query, all
+/// other criteria are exact matches.
+#[derive(Clone, Default)]
+pub struct SearchQuery {
+    pub text: String,
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub tag: Option<String>,
+    pub taken_from: Option<i64>,
+    pub taken_until: Option<i64>,
+    /// West, south, east and north edge of the area to search in. The west
+    /// edge lies east of the east edge if the area crosses the antimeridian.
+    pub bounds: Option<(f64, f64, f64, f64)>,
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+}
+
+/// A deleted image along with where it came from. The image row is kept
+/// as it was, so `image.name` is the original path of the file.
+#[derive(Clone)]
+pub struct TrashEntry {
+    pub id: u32,
+    pub image: ImageInfo,
+    pub trash_file: String,
+    pub deleted_at: i64,
+    pub deleted_by: String,
+}
+
+/// A user curated collection of images from anywhere in the gallery tree.
+/// `hashes` are in the order chosen by the user, and `cover` is the hash of
+/// the image shown for the album, if one has been picked.
+#[derive(Clone)]
+pub struct Album {
+    pub id: u32,
+    pub title: String,
+    pub description: String,
+    pub cover: Option<String>,
+    pub hashes: Vec<String>,
+}
+
+/// An album whose images are those matching a saved search. `rules` holds
+/// the search parameters as a query string, see `smart::rules_from_params`.
+#[derive(Clone)]
+pub struct SmartAlbum {
+    pub id: u32,
+    pub title: String,
+    pub description: String,
+    pub rules: String,
+}
+
+/// Schema changes in the order they were introduced. The number of
+/// migrations applied to a database is kept in its `user_version`.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS image (
+        image_id INTEGER PRIMARY KEY,
+        image_name TEXT NOT NULL,
+        image_hash TEXT NOT NULL,
+        image_width INTEGER NOT NULL,
+        image_height INTEGER NOT NULL,
+        image_type TEXT NOT NULL
+    )",
+    // TODO: create index on name
+    "CREATE TABLE trash (
+        trash_id INTEGER PRIMARY KEY,
+        image_id INTEGER NOT NULL,
+        image_name TEXT NOT NULL,
+        image_hash TEXT NOT NULL,
+        image_width INTEGER NOT NULL,
+        image_height INTEGER NOT NULL,
+        image_type TEXT NOT NULL,
+        trash_file TEXT NOT NULL,
+        trash_deleted_at INTEGER NOT NULL,
+        trash_deleted_by TEXT NOT NULL
+    )",
+    "CREATE TABLE image_meta (
+        image_id INTEGER PRIMARY KEY,
+        meta_caption TEXT,
+        meta_camera TEXT,
+        meta_lens TEXT,
+        meta_taken INTEGER,
+        meta_focal_length REAL,
+        meta_aperture REAL,
+        meta_exposure TEXT,
+        meta_iso INTEGER
+    );
+    CREATE TABLE image_tag (
+        image_id INTEGER NOT NULL,
+        tag_name TEXT NOT NULL,
+        PRIMARY KEY (image_id, tag_name)
+    );
+    CREATE INDEX image_tag_name ON image_tag (tag_name);
+    CREATE VIRTUAL TABLE image_search USING fts4 (path, caption, tags, camera, lens)",
+    // Capture times now fall back to the modification time, so metadata
+    // without one is read again
+    "DELETE FROM image_meta WHERE meta_taken IS NULL",
+    // Dropping the metadata has it read again, including the new columns
+    "ALTER TABLE image_meta ADD COLUMN meta_latitude REAL;
+    ALTER TABLE image_meta ADD COLUMN meta_longitude REAL;
+    CREATE INDEX image_meta_location ON image_meta (meta_latitude, meta_longitude);
+    DELETE FROM image_meta",
+    // FTS tables can't gain columns, so the search index is copied into a
+    // new one. Places are filled in by the metadata backfill.
+    "ALTER TABLE image_meta ADD COLUMN meta_country TEXT;
+    ALTER TABLE image_meta ADD COLUMN meta_region TEXT;
+    ALTER TABLE image_meta ADD COLUMN meta_city TEXT;
+    CREATE INDEX image_meta_place ON image_meta (meta_country, meta_region, meta_city);
+    CREATE VIRTUAL TABLE image_search_place USING fts4 (path, caption, tags, camera, lens, place);
+    INSERT INTO image_search_place (docid, path, caption, tags, camera, lens)
+        SELECT docid, path, caption, tags, camera, lens FROM image_search;
+    DROP TABLE image_search;
+    ALTER TABLE image_search_place RENAME TO image_search",
+    "ALTER TABLE image_meta ADD COLUMN meta_duration REAL",
+    // Dropping the metadata has it read again, including XMP sidecars
+    "ALTER TABLE image_meta ADD COLUMN meta_rating INTEGER;
+    ALTER TABLE image_meta ADD COLUMN meta_label TEXT;
+    DELETE FROM image_meta",
+    "CREATE TABLE favourite (
+        user_name TEXT NOT NULL,
+        image_id INTEGER NOT NULL,
+        PRIMARY KEY (user_name, image_id)
+    )",
+    // Albums refer to images by hash, so that they survive the files being
+    // moved, renamed or restored from the trash
+    "CREATE TABLE album (
+        album_id INTEGER PRIMARY KEY,
+        album_title TEXT NOT NULL,
+        album_description TEXT NOT NULL,
+        album_cover TEXT
+    );
+    CREATE TABLE album_image (
+        album_id INTEGER NOT NULL,
+        image_hash TEXT NOT NULL,
+        album_position INTEGER NOT NULL,
+        PRIMARY KEY (album_id, image_hash)
+    );
+    CREATE INDEX album_image_hash ON album_image (image_hash)",
+    "CREATE TABLE smart_album (
+        smart_album_id INTEGER PRIMARY KEY,
+        smart_album_title TEXT NOT NULL,
+        smart_album_description TEXT NOT NULL,
+        smart_album_rules TEXT NOT NULL
+    )",
+    "CREATE TABLE gallery_sort (
+        gallery_path TEXT PRIMARY KEY,
+        gallery_sort_key TEXT NOT NULL,
+        gallery_sort_descending INTEGER NOT NULL
+    )",
+    "CREATE TABLE gallery_cover (
+        gallery_path TEXT PRIMARY KEY,
+        image_hash TEXT NOT NULL
+    )",
+    // Filled in for existing images by the next scan
+    "ALTER TABLE image ADD COLUMN image_size INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE image ADD COLUMN image_modified INTEGER NOT NULL DEFAULT 0",
+    // Favourites refer to images by hash as well, like albums
+    "CREATE TABLE favourite_hash (
+        user_name TEXT NOT NULL,
+        image_hash TEXT NOT NULL,
+        PRIMARY KEY (user_name, image_hash)
+    );
+    INSERT OR IGNORE INTO favourite_hash (user_name, image_hash)
+        SELECT f.user_name, i.image_hash FROM favourite f JOIN image i ON i.image_id = f.image_id;
+    DROP TABLE favourite;
+    ALTER TABLE favourite_hash RENAME TO favourite;
+    CREATE INDEX favourite_image_hash ON favourite (image_hash)",
+];
+
+/// Columns of `ImageInfo`, as read by `DataStore::query_images`.
+const IMAGE_SELECT: &str = "SELECT i.image_id, i.image_name, i.image_hash, i.image_width, i.image_height, i.image_type, m.meta_taken, m.meta_rating, m.meta_label, i.image_size, i.image_modified FROM image i LEFT JOIN image_meta m ON m.image_id = i.image_id";
+
+/// Columns of `ImageMeta`, as read by `DataStore::meta_from_row`.
+const META_COLUMNS: &str = "m.meta_caption, m.meta_camera, m.meta_lens, m.meta_taken, m.meta_focal_length, m.meta_aperture, m.meta_exposure, m.meta_iso, m.meta_latitude, m.meta_longitude, m.meta_country, m.meta_region, m.meta_city, m.meta_duration, m.meta_rating, m.meta_label, (SELECT group_concat(tag_name, char(10)) FROM image_tag t WHERE t.image_id = m.image_id)";
+
+/// Separates the tags of an image when they're aggregated into one column.
+const TAG_SEPARATOR: char = '\n';
+
 type DbClosure = Box<dyn Fn(Rc<Connection>) + Send + 'static>;
 
 #[derive(Debug)]
@@ -61,26 +292,9 @@ impl DataStore {
         let mut db_file = data_dir.clone();
         db_file.push("hostimg.db");
 
-        let setup_db = !db_file.exists();
-
         let conn = Connection::open(db_file).map_err(|e| DataStoreError::Connection(e))?;
 
-        if setup_db {
-            conn.execute(
-                "CREATE TABLE image (
-                image_id INTEGER PRIMARY KEY,
-                image_name TEXT NOT NULL,
-                image_hash TEXT NOT NULL,
-                image_width INTEGER NOT NULL,
-                image_height INTEGER NOT NULL,
-                image_type TEXT NOT NULL
-            )",
-                &[],
-            )
-            .map_err(|e| DataStoreError::Setup(e))?;
-
-            // TODO: create index on name
-        }
+        DataStore::migrate(&conn)?;
 
         let (channel, receiver) = mpsc::channel::<DbClosure>();
 
@@ -95,33 +309,52 @@ impl DataStore {
         Ok(DataStore { channel })
     }
 
-    pub fn find_image_by_name(&self, name: String) -> Result<Option<ImageInfo>, DataStoreError> {
-        let (sender, receiver) = mpsc::channel::<Result<Vec<ImageInfo>, DataStoreError>>();
+    fn migrate(conn: &Connection) -> Result<(), DataStoreError> {
+        let version: i64 = conn
+            .query_row("PRAGMA user_version", &[], |row| row.get(0))
+            .map_err(|e| DataStoreError::Setup(e))?;
+
+        for (i, sql) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let batch = format!("BEGIN; {}; PRAGMA user_version = {}; COMMIT;", sql, i + 1);
+            conn.execute_batch(&batch)
+                .map_err(|e| DataStoreError::Setup(e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Runs the statements in `body` in a single transaction.
+    fn transaction<T, F>(conn: &Connection, body: F) -> Result<T, DataStoreError>
+    where
+        F: Fn(&Connection) -> Result<T, DataStoreError>,
+    {
+        conn.execute_batch("BEGIN")
+            .map_err(|e| DataStoreError::Execute("BEGIN".to_string(), e))?;
+
+        match body(conn) {
+            Ok(x) => {
+                conn.execute_batch("COMMIT")
+                    .map_err(|e| DataStoreError::Execute("COMMIT".to_string(), e))?;
+                Ok(x)
+            }
+            Err(e) => {
+                let _ = conn.execute_batch("ROLLBACK");
+                Err(e)
+            }
+        }
+    }
+
+    /// Runs `query` on the database thread and waits for its result.
+    fn run<T, F>(&self, query: F) -> Result<T, DataStoreError>
+    where
+        T: Send + 'static,
+        F: Fn(&Connection) -> Result<T, DataStoreError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel::<Result<T, DataStoreError>>();
 
         self.channel
             .send(Box::new(move |conn: Rc<Connection>| {
-                let sql = "SELECT * FROM image WHERE image_name = ?1";
-                let res = conn
-                    .prepare(sql)
-                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))
-                    .and_then(|mut stmt| {
-                        let mapped_rows = stmt
-                            .query_map(&[&name], |row| ImageInfo {
-                                id: row.get(0),
-                                name: row.get(1),
-                                hash: row.get(2),
-                                width: row.get(3),
-                                height: row.get(4),
-                                img_type: row.get(5),
-                            })
-                            .map_err(|e| DataStoreError::QueryMap(e))?;
-
-                        mapped_rows
-                            .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
-                            .collect::<Result<Vec<ImageInfo>, DataStoreError>>()
-                    });
-
-                if let Err(e) = sender.send(res) {
+                if let Err(e) = sender.send(query(&conn)) {
                     eprintln!("Failed to send datastore result: {:?}", e);
                 }
             }))
@@ -130,37 +363,884 @@ impl DataStore {
         receiver
             .recv()
             .map_err(|e| DataStoreError::ChannelReceive(Box::new(e)))?
-            .map(|res| res.into_iter().next())
     }
 
-    pub fn save_image(&self, info: ImageInfo) -> Result<i32, DataStoreError> {
-        let (sender, receiver) = mpsc::channel();
+    fn query_images(
+        conn: &Connection,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<ImageInfo>, DataStoreError> {
+        let mut stmt = conn
+            .prepare(sql)
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
 
-        self.channel
-            .send(Box::new(move |conn: Rc<Connection>| {
-                let sql = "INSERT INTO image (image_name, image_hash, image_width, image_height, image_type) VALUES (?1, ?2, ?3, ?4, ?5)";
-
-                let res = conn
-                    .execute(
-                        sql,
-                        &[
-                            &info.name,
-                            &info.hash,
-                            &info.width,
-                            &info.height,
-                            &info.img_type,
-                        ],
+        let mapped_rows = stmt
+            .query_map(params, |row| ImageInfo {
+                id: row.get(0),
+                name: row.get(1),
+                hash: row.get(2),
+                width: row.get(3),
+                height: row.get(4),
+                img_type: row.get(5),
+                taken: row.get(6),
+                rating: row.get(7),
+                label: row.get(8),
+                size: row.get(9),
+                modified: row.get(10),
+            })
+            .map_err(|e| DataStoreError::QueryMap(e))?;
+
+        mapped_rows
+            .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+            .collect::<Result<Vec<ImageInfo>, DataStoreError>>()
+    }
+
+    pub fn find_image_by_name(&self, name: String) -> Result<Option<ImageInfo>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = format!("{} WHERE i.image_name = ?1", IMAGE_SELECT);
+            DataStore::query_images(conn, &sql, &[&name])
+        })
+        .map(|res| res.into_iter().next())
+    }
+
+    pub fn find_images_by_hash(&self, hash: String) -> Result<Vec<ImageInfo>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = format!(
+                "{} WHERE i.image_hash = ?1 ORDER BY i.image_name",
+                IMAGE_SELECT
+            );
+            DataStore::query_images(conn, &sql, &[&hash])
+        })
+    }
+
+    /// Inserts a new image and returns the id assigned to it.
+    pub fn save_image(&self, info: ImageInfo) -> Result<u32, DataStoreError> {
+        self.run(move |conn| {
+            let sql = "INSERT INTO image (image_name, image_hash, image_width, image_height, image_type, image_size, image_modified) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+
+            conn.execute(
+                sql,
+                &[
+                    &info.name,
+                    &info.hash,
+                    &info.width,
+                    &info.height,
+                    &info.img_type,
+                    &info.size,
+                    &info.modified,
+                ],
+            )
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(conn.last_insert_rowid() as u32)
+        })
+    }
+
+    /// Records the size and modification time of an image's file.
+    pub fn set_image_file_stats(
+        &self,
+        id: u32,
+        size: i64,
+        modified: i64,
+    ) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "UPDATE image SET image_size = ?1, image_modified = ?2 WHERE image_id = ?3";
+            conn.execute(sql, &[&size, &modified, &id])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    pub fn rename_image(&self, id: u32, name: String) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "UPDATE image SET image_name = ?1 WHERE image_id = ?2";
+            conn.execute(sql, &[&name, &id])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    fn query_trash(
+        conn: &Connection,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<TrashEntry>, DataStoreError> {
+        let mut stmt = conn
+            .prepare(sql)
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+        let mapped_rows = stmt
+            .query_map(params, |row| TrashEntry {
+                id: row.get(0),
+                image: ImageInfo {
+                    id: row.get(1),
+                    name: row.get(2),
+                    hash: row.get(3),
+                    width: row.get(4),
+                    height: row.get(5),
+                    img_type: row.get(6),
+                    taken: None,
+                    rating: None,
+                    label: None,
+                    size: 0,
+                    modified: 0,
+                },
+                trash_file: row.get(7),
+                deleted_at: row.get(8),
+                deleted_by: row.get(9),
+            })
+            .map_err(|e| DataStoreError::QueryMap(e))?;
+
+        mapped_rows
+            .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+            .collect::<Result<Vec<TrashEntry>, DataStoreError>>()
+    }
+
+    /// Moves the row of a deleted image into the trash table and returns the
+    /// id of the new trash entry.
+    pub fn trash_image(&self, entry: TrashEntry) -> Result<u32, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::transaction(conn, |conn| {
+                let sql = "INSERT INTO trash (image_id, image_name, image_hash, image_width, image_height, image_type, trash_file, trash_deleted_at, trash_deleted_by) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)";
+                conn.execute(
+                    sql,
+                    &[
+                        &entry.image.id,
+                        &entry.image.name,
+                        &entry.image.hash,
+                        &entry.image.width,
+                        &entry.image.height,
+                        &entry.image.img_type,
+                        &entry.trash_file,
+                        &entry.deleted_at,
+                        &entry.deleted_by,
+                    ],
+                )
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                let trash_id = conn.last_insert_rowid() as u32;
+
+                // Metadata is read from the file again if the image is restored
+                for sql in &[
+                    "DELETE FROM image WHERE image_id = ?1",
+                    "DELETE FROM image_meta WHERE image_id = ?1",
+                    "DELETE FROM image_tag WHERE image_id = ?1",
+                    "DELETE FROM image_search WHERE docid = ?1",
+                ] {
+                    conn.execute(sql, &[&entry.image.id])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+
+                Ok(trash_id)
+            })
+        })
+    }
+
+    pub fn find_trash(&self) -> Result<Vec<TrashEntry>, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::query_trash(
+                conn,
+                "SELECT * FROM trash ORDER BY trash_deleted_at DESC, trash_id DESC",
+                &[],
+            )
+        })
+    }
+
+    pub fn find_trash_entry(&self, id: u32) -> Result<Option<TrashEntry>, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::query_trash(conn, "SELECT * FROM trash WHERE trash_id = ?1", &[&id])
+        })
+        .map(|res| res.into_iter().next())
+    }
+
+    /// Returns the entries deleted before the given unix timestamp.
+    pub fn find_expired_trash(&self, before: i64) -> Result<Vec<TrashEntry>, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::query_trash(
+                conn,
+                "SELECT * FROM trash WHERE trash_deleted_at < ?1",
+                &[&before],
+            )
+        })
+    }
+
+    /// Puts a trashed image back into the image table under `name` and
+    /// returns the id assigned to it. `size` and `modified` are those of the
+    /// restored file.
+    pub fn restore_image(
+        &self,
+        trash_id: u32,
+        name: String,
+        size: i64,
+        modified: i64,
+    ) -> Result<u32, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::transaction(conn, |conn| {
+                let sql = "INSERT INTO image (image_name, image_hash, image_width, image_height, image_type, image_size, image_modified) SELECT ?1, image_hash, image_width, image_height, image_type, ?3, ?4 FROM trash WHERE trash_id = ?2";
+                let restored = conn
+                    .execute(sql, &[&name, &trash_id, &size, &modified])
+                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                if restored == 0 {
+                    return Err(DataStoreError::RowMap(
+                        rusqlite::Error::QueryReturnedNoRows,
+                    ));
+                }
+                let image_id = conn.last_insert_rowid() as u32;
+
+                let sql = "DELETE FROM trash WHERE trash_id = ?1";
+                conn.execute(sql, &[&trash_id])
+                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                Ok(image_id)
+            })
+        })
+    }
+
+    pub fn delete_trash_entry(&self, id: u32) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "DELETE FROM trash WHERE trash_id = ?1";
+            conn.execute(sql, &[&id])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    /// Checks whether any image, in the library or in the trash, still has
+    /// the given content hash.
+    pub fn is_hash_in_use(&self, hash: String) -> Result<bool, DataStoreError> {
+        self.run(move |conn| {
+            let sql = "SELECT (SELECT COUNT(*) FROM image WHERE image_hash = ?1) + (SELECT COUNT(*) FROM trash WHERE image_hash = ?1)";
+            conn.query_row(sql, &[&hash], |row| row.get::<_, i64>(0) > 0)
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))
+        })
+    }
+
+    /// Stores the metadata of an image and makes it searchable. `path` is
+    /// the path of the image within the gallery directory.
+    pub fn save_image_meta(
+        &self,
+        id: u32,
+        path: String,
+        meta: ImageMeta,
+    ) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            DataStore::transaction(conn, |conn| {
+                let sql = "INSERT OR REPLACE INTO image_meta (image_id, meta_caption, meta_camera, meta_lens, meta_taken, meta_focal_length, meta_aperture, meta_exposure, meta_iso, meta_latitude, meta_longitude, meta_country, meta_region, meta_city, meta_duration, meta_rating, meta_label) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)";
+                let (country, region, city) = match meta.place.clone() {
+                    Some(x) => (Some(x.country), Some(x.region), Some(x.city)),
+                    None => (None, None, None),
+                };
+                conn.execute(
+                    sql,
+                    &[
+                        &id,
+                        &meta.caption,
+                        &meta.camera,
+                        &meta.lens,
+                        &meta.taken,
+                        &meta.focal_length,
+                        &meta.aperture,
+                        &meta.exposure,
+                        &meta.iso,
+                        &meta.latitude,
+                        &meta.longitude,
+                        &country,
+                        &region,
+                        &city,
+                        &meta.duration,
+                        &meta.rating,
+                        &meta.label,
+                    ],
+                )
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                let sql = "DELETE FROM image_tag WHERE image_id = ?1";
+                conn.execute(sql, &[&id])
+                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                let sql = "INSERT OR IGNORE INTO image_tag (image_id, tag_name) VALUES (?1, ?2)";
+                for tag in &meta.tags {
+                    conn.execute(sql, &[&id, tag])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+
+                let sql = "DELETE FROM image_search WHERE docid = ?1";
+                conn.execute(sql, &[&id])
+                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                let sql = "INSERT INTO image_search (docid, path, caption, tags, camera, lens, place) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+                conn.execute(
+                    sql,
+                    &[
+                        &id,
+                        &path,
+                        &meta.caption,
+                        &meta.tags.join(" "),
+                        &meta.camera,
+                        &meta.lens,
+                        &meta
+                            .place
+                            .as_ref()
+                            .map(|x| format!("{} {} {}", x.city, x.region, x.country)),
+                    ],
+                )
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                Ok(())
+            })
+        })
+    }
+
+    /// Marks an image as a favourite of a user, or unmarks it.
+    pub fn set_favourite(
+        &self,
+        user: String,
+        hash: String,
+        favourite: bool,
+    ) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = if favourite {
+                "INSERT OR IGNORE INTO favourite (user_name, image_hash) VALUES (?1, ?2)"
+            } else {
+                "DELETE FROM favourite WHERE user_name = ?1 AND image_hash = ?2"
+            };
+            conn.execute(sql, &[&user, &hash])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns the hashes of the images a user has marked as favourites.
+    pub fn find_favourites(&self, user: String) -> Result<BTreeSet<String>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = "SELECT image_hash FROM favourite WHERE user_name = ?1";
+            let mut stmt = conn
+                .prepare(sql)
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            let mapped_rows = stmt
+                .query_map(&[&user], |row| row.get::<_, String>(0))
+                .map_err(|e| DataStoreError::QueryMap(e))?;
+
+            mapped_rows
+                .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+                .collect::<Result<BTreeSet<String>, DataStoreError>>()
+        })
+    }
+
+    /// Updates the path an image can be found by after it has been moved.
+    pub fn set_search_path(&self, id: u32, path: String) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "UPDATE image_search SET path = ?1 WHERE docid = ?2";
+            conn.execute(sql, &[&path, &id])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns the images whose metadata hasn't been read yet.
+    pub fn find_images_without_meta(&self) -> Result<Vec<ImageInfo>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = format!("{} WHERE m.image_id IS NULL", IMAGE_SELECT);
+            DataStore::query_images(conn, &sql, &[])
+        })
+    }
+
+    /// Returns the located images which haven't been given a place, e.g.
+    /// because they were indexed before a geocoding dataset was installed.
+    pub fn find_images_without_place(&self) -> Result<Vec<ImageInfo>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = format!(
+                "{} WHERE m.meta_latitude IS NOT NULL AND m.meta_country IS NULL",
+                IMAGE_SELECT
+            );
+            DataStore::query_images(conn, &sql, &[])
+        })
+    }
+
+    pub fn find_image_meta(&self, id: u32) -> Result<Option<ImageMeta>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = format!(
+                "SELECT {} FROM image_meta m WHERE image_id = ?1",
+                META_COLUMNS
+            );
+            let mut stmt = conn
+                .prepare(&sql)
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            let mapped_rows = stmt
+                .query_map(&[&id], |row| DataStore::meta_from_row(row, 0))
+                .map_err(|e| DataStoreError::QueryMap(e))?;
+
+            let res = mapped_rows
+                .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+                .collect::<Result<Vec<ImageMeta>, DataStoreError>>()?;
+
+            Ok(res.into_iter().next())
+        })
+    }
+
+    fn meta_from_row(row: &rusqlite::Row, start: i32) -> ImageMeta {
+        let country: Option<String> = row.get(start + 10);
+        let region: Option<String> = row.get(start + 11);
+        let city: Option<String> = row.get(start + 12);
+        let tags: Option<String> = row.get(start + 16);
+        ImageMeta {
+            caption: row.get(start),
+            camera: row.get(start + 1),
+            lens: row.get(start + 2),
+            taken: row.get(start + 3),
+            focal_length: row.get(start + 4),
+            aperture: row.get(start + 5),
+            exposure: row.get(start + 6),
+            iso: row.get(start + 7),
+            latitude: row.get(start + 8),
+            longitude: row.get(start + 9),
+            place: match (country, region, city) {
+                (Some(country), Some(region), Some(city)) => Some(Place {
+                    country,
+                    region,
+                    city,
+                }),
+                _ => None,
+            },
+            duration: row.get(start + 13),
+            rating: row.get(start + 14),
+            label: row.get(start + 15),
+            tags: tags
+                .map(|x| x.split(TAG_SEPARATOR).map(|x| x.to_string()).collect())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Finds the images matching a query, along with their metadata, in
+    /// order of capture time.
+    pub fn search_images(
+        &self,
+        query: SearchQuery,
+    ) -> Result<Vec<(ImageInfo, ImageMeta)>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = format!("SELECT i.image_id, i.image_name, i.image_hash, i.image_width, i.image_height, i.image_type, {}, i.image_size, i.image_modified
+                FROM image i LEFT JOIN image_meta m ON m.image_id = i.image_id
+                WHERE (?1 = '' OR i.image_id IN (SELECT docid FROM image_search WHERE image_search MATCH ?1))
+                AND (?2 IS NULL OR m.meta_camera = ?2)
+                AND (?3 IS NULL OR m.meta_lens = ?3)
+                AND (?4 IS NULL OR i.image_id IN (SELECT image_id FROM image_tag WHERE tag_name = ?4))
+                AND (?5 IS NULL OR m.meta_taken >= ?5)
+                AND (?6 IS NULL OR m.meta_taken < ?6)
+                AND (?7 IS NULL OR (m.meta_latitude BETWEEN ?8 AND ?10 AND CASE WHEN ?7 <= ?9
+                    THEN m.meta_longitude BETWEEN ?7 AND ?9
+                    ELSE m.meta_longitude >= ?7 OR m.meta_longitude <= ?9 END))
+                AND (?11 IS NULL OR m.meta_country = ?11)
+                AND (?12 IS NULL OR m.meta_region = ?12)
+                AND (?13 IS NULL OR m.meta_city = ?13)
+                ORDER BY m.meta_taken, i.image_name", META_COLUMNS);
+            let (west, south, east, north) = match query.bounds {
+                Some((west, south, east, north)) => (Some(west), Some(south), Some(east), Some(north)),
+                None => (None, None, None, None),
+            };
+
+            let mut stmt = conn
+                .prepare(&sql)
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            let mapped_rows = stmt
+                .query_map(
+                    &[
+                        &query.text,
+                        &query.camera,
+                        &query.lens,
+                        &query.tag,
+                        &query.taken_from,
+                        &query.taken_until,
+                        &west,
+                        &south,
+                        &east,
+                        &north,
+                        &query.country,
+                        &query.region,
+                        &query.city,
+                    ],
+                    |row| {
+                        let info = ImageInfo {
+                            id: row.get(0),
+                            name: row.get(1),
+                            hash: row.get(2),
+                            width: row.get(3),
+                            height: row.get(4),
+                            img_type: row.get(5),
+                            taken: row.get(9),
+                            rating: row.get(20),
+                            label: row.get(21),
+                            size: row.get(23),
+                            modified: row.get(24),
+                        };
+                        (info, DataStore::meta_from_row(row, 6))
+                    },
+                )
+                .map_err(|e| DataStoreError::QueryMap(e))?;
+
+            mapped_rows
+                .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+                .collect::<Result<Vec<(ImageInfo, ImageMeta)>, DataStoreError>>()
+        })
+    }
+
+    fn query_albums(
+        conn: &Connection,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<Album>, DataStoreError> {
+        let mut stmt = conn
+            .prepare(sql)
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+        let mapped_rows = stmt
+            .query_map(params, |row| Album {
+                id: row.get(0),
+                title: row.get(1),
+                description: row.get(2),
+                cover: row.get(3),
+                hashes: Vec::new(),
+            })
+            .map_err(|e| DataStoreError::QueryMap(e))?;
+
+        let mut albums = mapped_rows
+            .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+            .collect::<Result<Vec<Album>, DataStoreError>>()?;
+
+        let sql = "SELECT image_hash FROM album_image WHERE album_id = ?1 ORDER BY album_position";
+        let mut stmt = conn
+            .prepare(sql)
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+        for album in &mut albums {
+            let mapped_rows = stmt
+                .query_map(&[&album.id], |row| row.get::<_, String>(0))
+                .map_err(|e| DataStoreError::QueryMap(e))?;
+
+            album.hashes = mapped_rows
+                .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+                .collect::<Result<Vec<String>, DataStoreError>>()?;
+        }
+
+        Ok(albums)
+    }
+
+    pub fn find_albums(&self) -> Result<Vec<Album>, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::query_albums(
+                conn,
+                "SELECT album_id, album_title, album_description, album_cover FROM album ORDER BY album_title, album_id",
+                &[],
+            )
+        })
+    }
+
+    pub fn find_album(&self, id: u32) -> Result<Option<Album>, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::query_albums(
+                conn,
+                "SELECT album_id, album_title, album_description, album_cover FROM album WHERE album_id = ?1",
+                &[&id],
+            )
+        })
+        .map(|res| res.into_iter().next())
+    }
+
+    /// Creates an empty album and returns the id assigned to it.
+    pub fn create_album(&self, title: String, description: String) -> Result<u32, DataStoreError> {
+        self.run(move |conn| {
+            let sql = "INSERT INTO album (album_title, album_description) VALUES (?1, ?2)";
+            conn.execute(sql, &[&title, &description])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(conn.last_insert_rowid() as u32)
+        })
+    }
+
+    /// Updates the title, description and cover of an album.
+    pub fn update_album(&self, album: Album) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "UPDATE album SET album_title = ?1, album_description = ?2, album_cover = ?3 WHERE album_id = ?4";
+            conn.execute(
+                sql,
+                &[&album.title, &album.description, &album.cover, &album.id],
+            )
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    pub fn delete_album(&self, id: u32) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            DataStore::transaction(conn, |conn| {
+                for sql in &[
+                    "DELETE FROM album_image WHERE album_id = ?1",
+                    "DELETE FROM album WHERE album_id = ?1",
+                ] {
+                    conn.execute(sql, &[&id])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+
+                Ok(())
+            })
+        })
+    }
+
+    /// Appends an image to the end of an album, unless it's in there already.
+    pub fn add_album_image(&self, id: u32, hash: String) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "INSERT OR IGNORE INTO album_image (album_id, image_hash, album_position) SELECT ?1, ?2, COALESCE(MAX(album_position) + 1, 0) FROM album_image WHERE album_id = ?1";
+            conn.execute(sql, &[&id, &hash])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    /// Takes an image out of an album, and stops using it as the cover.
+    pub fn remove_album_image(&self, id: u32, hash: String) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            DataStore::transaction(conn, |conn| {
+                let sql = "DELETE FROM album_image WHERE album_id = ?1 AND image_hash = ?2";
+                conn.execute(sql, &[&id, &hash])
+                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                let sql =
+                    "UPDATE album SET album_cover = NULL WHERE album_id = ?1 AND album_cover = ?2";
+                conn.execute(sql, &[&id, &hash])
+                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                Ok(())
+            })
+        })
+    }
+
+    /// Moves the given images to the start of an album in the given order.
+    /// Images that aren't listed keep their relative order after them.
+    pub fn order_album_images(&self, id: u32, hashes: Vec<String>) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            DataStore::transaction(conn, |conn| {
+                let count = hashes.len() as i64;
+                let sql = "UPDATE album_image SET album_position = album_position + ?1 WHERE album_id = ?2";
+                conn.execute(sql, &[&count, &id])
+                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+                let sql = "UPDATE album_image SET album_position = ?1 WHERE album_id = ?2 AND image_hash = ?3";
+                for (position, hash) in hashes.iter().enumerate() {
+                    conn.execute(sql, &[&(position as i64), &id, hash])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+
+                Ok(())
+            })
+        })
+    }
+
+    /// Takes an image out of every album, once no file has its content.
+    pub fn remove_hash_from_albums(&self, hash: String) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            DataStore::transaction(conn, |conn| {
+                for sql in &[
+                    "DELETE FROM album_image WHERE image_hash = ?1",
+                    "UPDATE album SET album_cover = NULL WHERE album_cover = ?1",
+                ] {
+                    conn.execute(sql, &[&hash])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+
+                Ok(())
+            })
+        })
+    }
+
+    /// Drops an image from everyone's favourites, once no copy of it is left.
+    pub fn remove_hash_from_favourites(&self, hash: String) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "DELETE FROM favourite WHERE image_hash = ?1";
+            conn.execute(sql, &[&hash])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    fn query_smart_albums(
+        conn: &Connection,
+        sql: &str,
+        params: &[&dyn ToSql],
+    ) -> Result<Vec<SmartAlbum>, DataStoreError> {
+        let mut stmt = conn
+            .prepare(sql)
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+        let mapped_rows = stmt
+            .query_map(params, |row| SmartAlbum {
+                id: row.get(0),
+                title: row.get(1),
+                description: row.get(2),
+                rules: row.get(3),
+            })
+            .map_err(|e| DataStoreError::QueryMap(e))?;
+
+        mapped_rows
+            .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+            .collect::<Result<Vec<SmartAlbum>, DataStoreError>>()
+    }
+
+    pub fn find_smart_albums(&self) -> Result<Vec<SmartAlbum>, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::query_smart_albums(
+                conn,
+                "SELECT smart_album_id, smart_album_title, smart_album_description, smart_album_rules FROM smart_album ORDER BY smart_album_title, smart_album_id",
+                &[],
+            )
+        })
+    }
+
+    pub fn find_smart_album(&self, id: u32) -> Result<Option<SmartAlbum>, DataStoreError> {
+        self.run(move |conn| {
+            DataStore::query_smart_albums(
+                conn,
+                "SELECT smart_album_id, smart_album_title, smart_album_description, smart_album_rules FROM smart_album WHERE smart_album_id = ?1",
+                &[&id],
+            )
+        })
+        .map(|res| res.into_iter().next())
+    }
+
+    /// Saves a new smart album and returns the id assigned to it.
+    pub fn create_smart_album(&self, album: SmartAlbum) -> Result<u32, DataStoreError> {
+        self.run(move |conn| {
+            let sql = "INSERT INTO smart_album (smart_album_title, smart_album_description, smart_album_rules) VALUES (?1, ?2, ?3)";
+            conn.execute(sql, &[&album.title, &album.description, &album.rules])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(conn.last_insert_rowid() as u32)
+        })
+    }
+
+    pub fn update_smart_album(&self, album: SmartAlbum) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "UPDATE smart_album SET smart_album_title = ?1, smart_album_description = ?2, smart_album_rules = ?3 WHERE smart_album_id = ?4";
+            conn.execute(
+                sql,
+                &[&album.title, &album.description, &album.rules, &album.id],
+            )
+            .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    pub fn delete_smart_album(&self, id: u32) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            let sql = "DELETE FROM smart_album WHERE smart_album_id = ?1";
+            conn.execute(sql, &[&id])
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            Ok(())
+        })
+    }
+
+    /// Returns the default sort orders of galleries by their path, as the
+    /// name of the sort key and whether it's descending.
+    pub fn find_gallery_sorts(&self) -> Result<BTreeMap<String, (String, bool)>, DataStoreError> {
+        self.run(move |conn| {
+            let sql =
+                "SELECT gallery_path, gallery_sort_key, gallery_sort_descending FROM gallery_sort";
+            let mut stmt = conn
+                .prepare(sql)
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            let mapped_rows = stmt
+                .query_map(&[], |row| {
+                    (
+                        row.get::<_, String>(0),
+                        (row.get::<_, String>(1), row.get::<_, bool>(2)),
                     )
-                    .map_err(|e| DataStoreError::Execute(sql.to_string(), e));
+                })
+                .map_err(|e| DataStoreError::QueryMap(e))?;
 
-                if let Err(e) = sender.send(res) {
-                    eprintln!("Failed to send datastore result: {:?}", e);
+            mapped_rows
+                .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+                .collect::<Result<BTreeMap<String, (String, bool)>, DataStoreError>>()
+        })
+    }
+
+    /// Sets the default sort order of a gallery, or removes it so that the
+    /// gallery goes back to the one of its parents.
+    pub fn set_gallery_sort(
+        &self,
+        path: String,
+        sort: Option<(String, bool)>,
+    ) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            match sort {
+                Some((ref key, descending)) => {
+                    let sql = "INSERT OR REPLACE INTO gallery_sort (gallery_path, gallery_sort_key, gallery_sort_descending) VALUES (?1, ?2, ?3)";
+                    conn.execute(sql, &[&path, key, &descending])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
                 }
-            }))
-            .map_err(|_e| DataStoreError::ChannelSend)?;
+                None => {
+                    let sql = "DELETE FROM gallery_sort WHERE gallery_path = ?1";
+                    conn.execute(sql, &[&path])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+            }
 
-        receiver
-            .recv()
-            .map_err(|e| DataStoreError::ChannelReceive(Box::new(e)))?
+            Ok(())
+        })
+    }
+
+    /// Returns the hashes of the covers picked for galleries, by the path of
+    /// the gallery.
+    pub fn find_gallery_covers(&self) -> Result<BTreeMap<String, String>, DataStoreError> {
+        self.run(move |conn| {
+            let sql = "SELECT gallery_path, image_hash FROM gallery_cover";
+            let mut stmt = conn
+                .prepare(sql)
+                .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+
+            let mapped_rows = stmt
+                .query_map(&[], |row| {
+                    (row.get::<_, String>(0), row.get::<_, String>(1))
+                })
+                .map_err(|e| DataStoreError::QueryMap(e))?;
+
+            mapped_rows
+                .map(|item| item.map_err(|e| DataStoreError::RowMap(e)))
+                .collect::<Result<BTreeMap<String, String>, DataStoreError>>()
+        })
+    }
+
+    /// Picks the cover of a gallery, or removes it so that one is chosen
+    /// automatically.
+    pub fn set_gallery_cover(
+        &self,
+        path: String,
+        hash: Option<String>,
+    ) -> Result<(), DataStoreError> {
+        self.run(move |conn| {
+            match hash {
+                Some(ref hash) => {
+                    let sql = "INSERT OR REPLACE INTO gallery_cover (gallery_path, image_hash) VALUES (?1, ?2)";
+                    conn.execute(sql, &[&path, hash])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+                None => {
+                    let sql = "DELETE FROM gallery_cover WHERE gallery_path = ?1";
+                    conn.execute(sql, &[&path])
+                        .map_err(|e| DataStoreError::Execute(sql.to_string(), e))?;
+                }
+            }
+
+            Ok(())
+        })
     }
 }
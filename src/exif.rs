@@ -0,0 +1,619 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+pub const GPS_LATITUDE_REF: u16 = 0x0001;
+pub const GPS_LATITUDE: u16 = 0x0002;
+pub const GPS_LONGITUDE_REF: u16 = 0x0003;
+pub const GPS_LONGITUDE: u16 = 0x0004;
+pub const COMPRESSION: u16 = 0x0103;
+pub const IMAGE_DESCRIPTION: u16 = 0x010E;
+pub const MAKE: u16 = 0x010F;
+pub const MODEL: u16 = 0x0110;
+pub const STRIP_OFFSETS: u16 = 0x0111;
+pub const STRIP_BYTE_COUNTS: u16 = 0x0117;
+pub const DATE_TIME: u16 = 0x0132;
+pub const SUB_IFDS: u16 = 0x014A;
+pub const JPEG_OFFSET: u16 = 0x0201;
+pub const JPEG_LENGTH: u16 = 0x0202;
+pub const EXPOSURE_TIME: u16 = 0x829A;
+pub const F_NUMBER: u16 = 0x829D;
+pub const EXIF_IFD: u16 = 0x8769;
+pub const GPS_IFD: u16 = 0x8825;
+pub const ISO_SPEED: u16 = 0x8827;
+pub const DATE_TIME_ORIGINAL: u16 = 0x9003;
+pub const FOCAL_LENGTH: u16 = 0x920A;
+pub const XP_COMMENT: u16 = 0x9C9C;
+pub const XP_KEYWORDS: u16 = 0x9C9E;
+pub const LENS_MODEL: u16 = 0xA434;
+
+/// Upper bound for the number of entries read from a single IFD, so that
+/// corrupt files can't make us allocate without limit.
+const MAX_IFD_ENTRIES: usize = 1024;
+
+/// Upper bound for the number of IFDs searched for embedded images.
+const MAX_IFDS: usize = 64;
+
+/// TIFF compression schemes whose strips hold a JPEG stream.
+const JPEG_COMPRESSIONS: &[u32] = &[6, 7];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Ifd {
+    Primary,
+    Exif,
+    Gps,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExifValue {
+    Text(String),
+    Bytes(Vec<u8>),
+    Unsigned(Vec<u32>),
+    Signed(Vec<i32>),
+    Rational(Vec<(u32, u32)>),
+    SignedRational(Vec<(i32, i32)>),
+}
+
+/// The tags of a TIFF structure, as found in the APP1 segment of a JPEG file.
+pub struct Exif {
+    fields: BTreeMap<(Ifd, u16), ExifValue>,
+}
+
+struct TiffReader<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> TiffReader<'a> {
+    fn u16_at(&self, pos: usize) -> Option<u16> {
+        let b = self.data.get(pos..pos + 2)?;
+        Some(if self.big_endian {
+            (b[0] as u16) << 8 | b[1] as u16
+        } else {
+            (b[1] as u16) << 8 | b[0] as u16
+        })
+    }
+
+    fn u32_at(&self, pos: usize) -> Option<u32> {
+        let b = self.data.get(pos..pos + 4)?;
+        Some(if self.big_endian {
+            (b[0] as u32) << 24 | (b[1] as u32) << 16 | (b[2] as u32) << 8 | b[3] as u32
+        } else {
+            (b[3] as u32) << 24 | (b[2] as u32) << 16 | (b[1] as u32) << 8 | b[0] as u32
+        })
+    }
+
+    fn read_value(&self, entry: usize) -> Option<ExifValue> {
+        let field_type = self.u16_at(entry + 2)?;
+        let count = self.u32_at(entry + 4)? as usize;
+
+        let size = match field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 => 4,
+            5 | 10 => 8,
+            _ => return None,
+        };
+
+        let length = size * count;
+        let pos = if length <= 4 {
+            entry + 8
+        } else {
+            self.u32_at(entry + 8)? as usize
+        };
+        let bytes = self.data.get(pos..pos.checked_add(length)?)?;
+
+        let value = match field_type {
+            2 => {
+                let text = bytes.split(|&b| b == 0).next().unwrap_or(&[]);
+                ExifValue::Text(String::from_utf8_lossy(text).trim().to_string())
+            }
+            1 | 6 | 7 => ExifValue::Bytes(bytes.to_vec()),
+            3 => ExifValue::Unsigned(
+                (0..count)
+                    .filter_map(|i| self.u16_at(pos + 2 * i))
+                    .map(|x| x as u32)
+                    .collect(),
+            ),
+            4 => ExifValue::Unsigned(
+                (0..count)
+                    .filter_map(|i| self.u32_at(pos + 4 * i))
+                    .collect(),
+            ),
+            8 => ExifValue::Signed(
+                (0..count)
+                    .filter_map(|i| self.u16_at(pos + 2 * i))
+                    .map(|x| x as i16 as i32)
+                    .collect(),
+            ),
+            9 => ExifValue::Signed(
+                (0..count)
+                    .filter_map(|i| self.u32_at(pos + 4 * i))
+                    .map(|x| x as i32)
+                    .collect(),
+            ),
+            5 => ExifValue::Rational(
+                (0..count)
+                    .filter_map(|i| {
+                        Some((self.u32_at(pos + 8 * i)?, self.u32_at(pos + 8 * i + 4)?))
+                    })
+                    .collect(),
+            ),
+            _ => ExifValue::SignedRational(
+                (0..count)
+                    .filter_map(|i| {
+                        Some((
+                            self.u32_at(pos + 8 * i)? as i32,
+                            self.u32_at(pos + 8 * i + 4)? as i32,
+                        ))
+                    })
+                    .collect(),
+            ),
+        };
+
+        Some(value)
+    }
+
+    fn read_ifd(&self, ifd: Ifd, offset: usize, fields: &mut BTreeMap<(Ifd, u16), ExifValue>) {
+        let count = match self.u16_at(offset) {
+            Some(x) => (x as usize).min(MAX_IFD_ENTRIES),
+            None => return,
+        };
+
+        for i in 0..count {
+            let entry = offset + 2 + 12 * i;
+            let tag = match self.u16_at(entry) {
+                Some(x) => x,
+                None => return,
+            };
+
+            // Only the primary IFD links to the others, which keeps corrupt
+            // files from sending us around in circles.
+            if ifd == Ifd::Primary && (tag == EXIF_IFD || tag == GPS_IFD) {
+                let sub_ifd = if tag == EXIF_IFD { Ifd::Exif } else { Ifd::Gps };
+                if let Some(sub_offset) = self.u32_at(entry + 8) {
+                    self.read_ifd(sub_ifd, sub_offset as usize, fields);
+                }
+                continue;
+            }
+
+            if let Some(value) = self.read_value(entry) {
+                fields.insert((ifd, tag), value);
+            }
+        }
+    }
+
+    /// Reads the unsigned integers of a tag in the IFD at `offset`.
+    fn ifd_numbers(&self, offset: usize, tag: u16) -> Vec<u32> {
+        let count = self
+            .u16_at(offset)
+            .map_or(0, |x| x as usize)
+            .min(MAX_IFD_ENTRIES);
+        for i in 0..count {
+            let entry = offset + 2 + 12 * i;
+            if self.u16_at(entry) != Some(tag) {
+                continue;
+            }
+
+            return match self.read_value(entry) {
+                Some(ExifValue::Unsigned(x)) => x,
+                _ => Vec::new(),
+            };
+        }
+
+        Vec::new()
+    }
+
+    /// Collects the JPEG streams referenced by the IFD at `offset`, then
+    /// follows its sub IFDs and the next IFD in the chain.
+    fn find_jpegs(&self, offset: usize, visited: &mut BTreeSet<usize>, jpegs: &mut Vec<&'a [u8]>) {
+        if offset == 0 || visited.len() >= MAX_IFDS || !visited.insert(offset) {
+            return;
+        }
+
+        let mut streams = Vec::new();
+        if let (Some(&start), Some(&length)) = (
+            self.ifd_numbers(offset, JPEG_OFFSET).first(),
+            self.ifd_numbers(offset, JPEG_LENGTH).first(),
+        ) {
+            streams.push((start, length));
+        }
+
+        let compression = self.ifd_numbers(offset, COMPRESSION).first().cloned();
+        if compression.map_or(false, |x| JPEG_COMPRESSIONS.contains(&x)) {
+            let starts = self.ifd_numbers(offset, STRIP_OFFSETS);
+            let lengths = self.ifd_numbers(offset, STRIP_BYTE_COUNTS);
+            // Only single strip images are whole JPEG streams
+            if starts.len() == 1 && lengths.len() == 1 {
+                streams.push((starts[0], lengths[0]));
+            }
+        }
+
+        for (start, length) in streams {
+            let (start, length) = (start as usize, length as usize);
+            if let Some(data) = self.data.get(start..start.saturating_add(length)) {
+                if data.starts_with(&[0xFF, 0xD8]) {
+                    jpegs.push(data);
+                }
+            }
+        }
+
+        for sub_offset in self.ifd_numbers(offset, SUB_IFDS) {
+            self.find_jpegs(sub_offset as usize, visited, jpegs);
+        }
+
+        let count = self
+            .u16_at(offset)
+            .map_or(0, |x| x as usize)
+            .min(MAX_IFD_ENTRIES);
+        if let Some(next) = self.u32_at(offset + 2 + 12 * count) {
+            self.find_jpegs(next as usize, visited, jpegs);
+        }
+    }
+}
+
+/// Finds the JPEG images embedded in a TIFF structure, like the previews
+/// of camera RAW files.
+pub fn embedded_jpegs(data: &[u8]) -> Vec<&[u8]> {
+    let big_endian = match data.get(0..2) {
+        Some(b"II") => false,
+        Some(b"MM") => true,
+        _ => return Vec::new(),
+    };
+
+    let reader = TiffReader { data, big_endian };
+    let mut jpegs = Vec::new();
+    if let Some(offset) = reader.u32_at(4) {
+        reader.find_jpegs(offset as usize, &mut BTreeSet::new(), &mut jpegs);
+    }
+
+    jpegs
+}
+
+impl Exif {
+    /// Parses a TIFF structure, starting with its byte order mark.
+    pub fn parse_tiff(data: &[u8]) -> Option<Exif> {
+        let big_endian = match data.get(0..2)? {
+            b"II" => false,
+            b"MM" => true,
+            _ => return None,
+        };
+
+        let reader = TiffReader { data, big_endian };
+        if reader.u16_at(2)? != 42 {
+            return None;
+        }
+
+        let mut fields = BTreeMap::new();
+        reader.read_ifd(Ifd::Primary, reader.u32_at(4)? as usize, &mut fields);
+
+        Some(Exif { fields })
+    }
+
+    /// Reads the EXIF data of a TIFF based file, e.g. a camera RAW file.
+    pub fn from_tiff(path: &Path) -> io::Result<Option<Exif>> {
+        Ok(Exif::parse_tiff(&fs::read(path)?))
+    }
+
+    /// Reads the EXIF data of a JPEG file. Files without any yield `None`.
+    pub fn from_jpeg(path: &Path) -> io::Result<Option<Exif>> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut marker = [0; 2];
+        reader.read_exact(&mut marker)?;
+        if marker != [0xFF, 0xD8] {
+            return Ok(None);
+        }
+
+        loop {
+            let mut header = [0; 4];
+            reader.read_exact(&mut header)?;
+            if header[0] != 0xFF {
+                return Ok(None);
+            }
+
+            let length = ((header[2] as usize) << 8 | header[3] as usize).saturating_sub(2);
+
+            // Metadata always precedes the start of the image data
+            if header[1] == 0xDA || header[1] == 0xD9 {
+                return Ok(None);
+            }
+
+            let mut segment = vec![0; length];
+            reader.read_exact(&mut segment)?;
+
+            if header[1] == 0xE1 && segment.starts_with(b"Exif\0\0") {
+                return Ok(Exif::parse_tiff(&segment[6..]));
+            }
+        }
+    }
+
+    pub fn get(&self, ifd: Ifd, tag: u16) -> Option<&ExifValue> {
+        self.fields.get(&(ifd, tag))
+    }
+
+    /// Returns a text field, dropping ones that are empty.
+    pub fn text(&self, ifd: Ifd, tag: u16) -> Option<String> {
+        match self.get(ifd, tag)? {
+            ExifValue::Text(x) if !x.is_empty() => Some(x.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns the Windows specific XP fields, which hold UTF-16 text.
+    pub fn utf16_text(&self, ifd: Ifd, tag: u16) -> Option<String> {
+        let bytes = match self.get(ifd, tag)? {
+            ExifValue::Bytes(x) => x,
+            _ => return None,
+        };
+
+        let chars: Vec<u16> = bytes
+            .chunks(2)
+            .filter(|x| x.len() == 2)
+            .map(|x| (x[1] as u16) << 8 | x[0] as u16)
+            .take_while(|&x| x != 0)
+            .collect();
+
+        let text = String::from_utf16_lossy(&chars).trim().to_string();
+        if text.is_empty() {
+            None
+        } else {
+            Some(text)
+        }
+    }
+
+    /// Returns the n:th component of a numeric field as a float.
+    pub fn number_at(&self, ifd: Ifd, tag: u16, index: usize) -> Option<f64> {
+        match self.get(ifd, tag)? {
+            ExifValue::Unsigned(x) => x.get(index).map(|&x| x as f64),
+            ExifValue::Signed(x) => x.get(index).map(|&x| x as f64),
+            ExifValue::Rational(x) => x
+                .get(index)
+                .filter(|x| x.1 != 0)
+                .map(|&(n, d)| n as f64 / d as f64),
+            ExifValue::SignedRational(x) => x
+                .get(index)
+                .filter(|x| x.1 != 0)
+                .map(|&(n, d)| n as f64 / d as f64),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, ifd: Ifd, tag: u16) -> Option<f64> {
+        self.number_at(ifd, tag, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes TIFF structures in either byte order. Data is appended as it
+    /// comes, so anything an IFD points to is written before the IFD.
+    struct TiffWriter {
+        data: Vec<u8>,
+        big_endian: bool,
+    }
+
+    impl TiffWriter {
+        fn new(big_endian: bool) -> TiffWriter {
+            let mut writer = TiffWriter {
+                data: Vec::new(),
+                big_endian,
+            };
+            writer
+                .data
+                .extend_from_slice(if big_endian { b"MM" } else { b"II" });
+            writer.u16(42);
+            writer.u32(0);
+            writer
+        }
+
+        fn offset(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        fn u16(&mut self, x: u16) {
+            let bytes = if self.big_endian {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+        }
+
+        fn u32(&mut self, x: u32) {
+            let bytes = if self.big_endian {
+                x.to_be_bytes()
+            } else {
+                x.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+        }
+
+        fn bytes(&mut self, data: &[u8]) -> u32 {
+            let offset = self.offset();
+            self.data.extend_from_slice(data);
+            offset
+        }
+
+        /// Appends an IFD of `(tag, type, count, value)` entries, where the
+        /// value is a single number or an offset, and returns its offset.
+        fn ifd(&mut self, entries: &[(u16, u16, u32, u32)], next: u32) -> u32 {
+            let offset = self.offset();
+            self.u16(entries.len() as u16);
+            for &(tag, field_type, count, value) in entries {
+                self.u16(tag);
+                self.u16(field_type);
+                self.u32(count);
+                if field_type == 3 && count == 1 {
+                    self.u16(value as u16);
+                    self.u16(0);
+                } else {
+                    self.u32(value);
+                }
+            }
+            self.u32(next);
+            offset
+        }
+
+        fn set_first_ifd(&mut self, offset: u32) {
+            let bytes = if self.big_endian {
+                offset.to_be_bytes()
+            } else {
+                offset.to_le_bytes()
+            };
+            self.data[4..8].copy_from_slice(&bytes);
+        }
+    }
+
+    #[test]
+    fn test_parse_tiff() {
+        for &big_endian in &[false, true] {
+            let mut writer = TiffWriter::new(big_endian);
+            let make = writer.bytes(b"Canon\0");
+            let date = writer.bytes(b"2019:07:01 12:00:00\0");
+            let f_number = writer.offset();
+            writer.u32(28);
+            writer.u32(10);
+            let exif = writer.ifd(
+                &[
+                    (DATE_TIME_ORIGINAL, 2, 20, date),
+                    (F_NUMBER, 5, 1, f_number),
+                ],
+                0,
+            );
+            let primary = writer.ifd(
+                &[
+                    (MAKE, 2, 6, make),
+                    (ISO_SPEED, 3, 1, 400),
+                    (EXIF_IFD, 4, 1, exif),
+                ],
+                0,
+            );
+            writer.set_first_ifd(primary);
+
+            let exif = Exif::parse_tiff(&writer.data).unwrap();
+            assert_eq!(exif.text(Ifd::Primary, MAKE), Some("Canon".to_string()));
+            assert_eq!(exif.number(Ifd::Primary, ISO_SPEED), Some(400.0));
+            assert_eq!(
+                exif.text(Ifd::Exif, DATE_TIME_ORIGINAL),
+                Some("2019:07:01 12:00:00".to_string())
+            );
+            assert_eq!(exif.number(Ifd::Exif, F_NUMBER), Some(2.8));
+            assert!(exif.get(Ifd::Primary, EXIF_IFD).is_none());
+            assert!(exif.get(Ifd::Primary, DATE_TIME_ORIGINAL).is_none());
+        }
+    }
+
+    #[test]
+    fn test_parse_tiff_invalid_header() {
+        assert!(Exif::parse_tiff(b"").is_none());
+        assert!(Exif::parse_tiff(b"XX\x2a\0\x08\0\0\0").is_none());
+        assert!(Exif::parse_tiff(b"II\x2b\0\x08\0\0\0").is_none());
+        assert!(Exif::parse_tiff(b"II\x2a\0").is_none());
+    }
+
+    #[test]
+    fn test_parse_tiff_malformed_offsets() {
+        // The first IFD lies beyond the end of the data
+        let mut writer = TiffWriter::new(false);
+        writer.set_first_ifd(0xFFFF_FFF0);
+        let exif = Exif::parse_tiff(&writer.data).unwrap();
+        assert!(exif.get(Ifd::Primary, MAKE).is_none());
+
+        // Values that point outside the data, are too long for it or have an
+        // unknown type are skipped, while the others are still read
+        let mut writer = TiffWriter::new(false);
+        let make = writer.bytes(b"Canon\0");
+        let primary = writer.ifd(
+            &[
+                (MAKE, 2, 6, make),
+                (MODEL, 2, 6, 0xFFFF_FFF0),
+                (IMAGE_DESCRIPTION, 2, 0x4000_0000, make),
+                (DATE_TIME, 99, 1, 0),
+                (GPS_IFD, 4, 1, 0xFFFF_FFF0),
+            ],
+            0,
+        );
+        writer.set_first_ifd(primary);
+        let exif = Exif::parse_tiff(&writer.data).unwrap();
+        assert_eq!(exif.text(Ifd::Primary, MAKE), Some("Canon".to_string()));
+        assert!(exif.get(Ifd::Primary, MODEL).is_none());
+        assert!(exif.get(Ifd::Primary, IMAGE_DESCRIPTION).is_none());
+        assert!(exif.get(Ifd::Primary, DATE_TIME).is_none());
+
+        // An entry count past the end of the data
+        let mut writer = TiffWriter::new(true);
+        let make = writer.bytes(b"Canon\0");
+        let primary = writer.ifd(&[(MAKE, 2, 6, make)], 0);
+        writer.data[primary as usize..primary as usize + 2].copy_from_slice(&[0xFF, 0xFF]);
+        writer.set_first_ifd(primary);
+        let exif = Exif::parse_tiff(&writer.data).unwrap();
+        assert_eq!(exif.text(Ifd::Primary, MAKE), Some("Canon".to_string()));
+
+        // An EXIF IFD that points back at the primary one is read once
+        let mut writer = TiffWriter::new(false);
+        let make = writer.bytes(b"Canon\0");
+        let primary = writer.offset();
+        writer.ifd(&[(MAKE, 2, 6, make), (EXIF_IFD, 4, 1, primary)], 0);
+        writer.set_first_ifd(primary);
+        let exif = Exif::parse_tiff(&writer.data).unwrap();
+        assert_eq!(exif.text(Ifd::Exif, MAKE), Some("Canon".to_string()));
+    }
+
+    #[test]
+    fn test_embedded_jpegs() {
+        let mut writer = TiffWriter::new(false);
+        let preview = writer.bytes(&[0xFF, 0xD8, 1, 2, 3, 0xFF, 0xD9]);
+        let strip = writer.bytes(&[0xFF, 0xD8, 4, 0xFF, 0xD9]);
+        let raw = writer.bytes(&[0, 1, 2, 3]);
+        let sub = writer.ifd(
+            &[
+                (COMPRESSION, 3, 1, 7),
+                (STRIP_OFFSETS, 4, 1, strip),
+                (STRIP_BYTE_COUNTS, 4, 1, 5),
+            ],
+            0,
+        );
+        let thumbnail = writer.ifd(&[(JPEG_OFFSET, 4, 1, preview), (JPEG_LENGTH, 4, 1, 7)], 0);
+        let not_jpeg = writer.ifd(&[(JPEG_OFFSET, 4, 1, raw), (JPEG_LENGTH, 4, 1, 4)], 0);
+        let too_long = writer.ifd(
+            &[(JPEG_OFFSET, 4, 1, preview), (JPEG_LENGTH, 4, 1, 0xFFFF)],
+            0,
+        );
+        let primary = writer.ifd(&[(SUB_IFDS, 4, 3, 0)], thumbnail);
+        writer.set_first_ifd(primary);
+
+        // The sub IFDs are listed in an array
+        let sub_ifds = writer.offset();
+        writer.u32(sub);
+        writer.u32(not_jpeg);
+        writer.u32(too_long);
+        let entry = primary as usize + 2;
+        writer.data[entry + 8..entry + 12].copy_from_slice(&sub_ifds.to_le_bytes());
+
+        let jpegs = embedded_jpegs(&writer.data);
+        assert_eq!(jpegs.len(), 2);
+        assert_eq!(jpegs[0], &[0xFF, 0xD8, 4, 0xFF, 0xD9][..]);
+        assert_eq!(jpegs[1], &[0xFF, 0xD8, 1, 2, 3, 0xFF, 0xD9][..]);
+    }
+
+    #[test]
+    fn test_embedded_jpegs_loop() {
+        // An IFD that is its own next IFD is only visited once
+        let mut writer = TiffWriter::new(true);
+        let preview = writer.bytes(&[0xFF, 0xD8, 0xFF, 0xD9]);
+        let primary = writer.offset();
+        writer.ifd(
+            &[(JPEG_OFFSET, 4, 1, preview), (JPEG_LENGTH, 4, 1, 4)],
+            primary,
+        );
+        writer.set_first_ifd(primary);
+
+        assert_eq!(embedded_jpegs(&writer.data).len(), 1);
+        assert!(embedded_jpegs(b"junk").is_empty());
+        assert!(embedded_jpegs(b"").is_empty());
+    }
+}
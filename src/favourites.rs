@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::Request;
+
+use crate::auth::{current_user, forbidden_response};
+use crate::context::ServerContext;
+use crate::gallery::{image_to_json, mark_favourite};
+use crate::web::{error_response, html_response, Action, WebServer};
+
+/// Lists the favourites of the logged in user from across all galleries,
+/// as if they were a gallery of their own.
+pub struct FavouritesAction {}
+
+impl FavouritesAction {
+    pub fn new() -> FavouritesAction {
+        FavouritesAction {}
+    }
+}
+
+impl Action for FavouritesAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/favourites/?$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/favourites.html").to_string();
+        server.register_template("favourites", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        _: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let user = match current_user(&request, &context) {
+            Some(x) => x,
+            None => return forbidden_response(request, &context),
+        };
+
+        let favourites = match context.datastore.find_favourites(user) {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to read favourites"),
+        };
+
+        let root_gallery = match context.get_root_gallery() {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "No root gallery found"),
+        };
+
+        let mut images = root_gallery.all_images();
+        images.retain(|x| favourites.contains(&x.hash));
+        images.sort_by(|a, b| (a.taken, &a.name).cmp(&(b.taken, &b.name)));
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("count".to_string(), images.len().to_json());
+        result_dict.insert(
+            "images".to_string(),
+            Json::Array(
+                images
+                    .iter()
+                    .map(|x| mark_favourite(image_to_json(&context, x), x, &favourites))
+                    .collect(),
+            ),
+        );
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("favourites", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+}
@@ -3,13 +3,13 @@ extern crate sha2;
 
 use std::cmp::{Ordering, PartialOrd};
 use std::collections::BTreeSet;
-use std::fs::{read_dir, File};
+use std::fs::{metadata, read_dir, read_to_string, remove_file, File};
 use std::io::{self, BufReader, ErrorKind, Read};
 use std::path::{Component, Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, UNIX_EPOCH};
 
 use image::{DynamicImage, GenericImage, ImageResult};
 use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
@@ -17,19 +17,32 @@ use sha2::Digest;
 
 use crate::context::{ContextError, ServerContext};
 use crate::db::{DataStoreError, ImageInfo};
+use crate::markdown;
+use crate::metadata::index_metadata;
+use crate::raw::{self, is_raw};
+use crate::video::{self, is_video};
+use crate::xmp::{is_sidecar, sidecar_owners};
 
+/// Opens an image, or the preview embedded in a RAW file.
 pub fn open_image(file: &Path) -> ImageResult<DynamicImage> {
+    if is_raw(file) {
+        return raw::open_preview(file);
+    }
+
     let file_obj = File::open(&file)?;
     let reader = BufReader::new(file_obj);
     image::load(reader, image::ImageFormat::JPEG)
 }
 
 pub fn hash_file(file: &Path) -> io::Result<String> {
-    let mut file_obj = File::open(file)?;
+    let file_obj = File::open(file)?;
+    hash_reader(file_obj)
+}
 
+pub fn hash_reader<R: Read>(mut reader: R) -> io::Result<String> {
     let mut buffer = [0; 4096];
     let mut hasher = sha2::Sha256::new();
-    while let Ok(bytes_read) = file_obj.read(&mut buffer) {
+    while let Ok(bytes_read) = reader.read(&mut buffer) {
         if bytes_read == 0 {
             break;
         }
@@ -51,6 +64,53 @@ pub fn is_image(file: &Path) -> bool {
         .and_then(|x| x.to_str())
         .map(|s| s.to_ascii_lowercase() == "jpg")
         .unwrap_or(false)
+        || is_raw(file)
+}
+
+/// Checks whether a file is an image or a video.
+pub fn is_media(file: &Path) -> bool {
+    is_image(file) || is_video(file)
+}
+
+/// Returns the size of a file in bytes and its modification time as a unix
+/// timestamp, or zeros if it can't be read.
+pub fn file_stats(file: &Path) -> (i64, i64) {
+    match metadata(file) {
+        Ok(meta) => (
+            meta.len() as i64,
+            meta.modified()
+                .ok()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |x| x.as_secs() as i64),
+        ),
+        Err(_) => (0, 0),
+    }
+}
+
+/// Files describing the album in their directory, in order of preference.
+const DESCRIPTION_FILES: &[&str] = &["README.md", "index.txt"];
+
+/// Checks whether a file is the description of the album in its directory.
+pub fn is_description(file: &Path) -> bool {
+    file.file_name()
+        .and_then(|x| x.to_str())
+        .map_or(false, |name| DESCRIPTION_FILES.contains(&name))
+}
+
+/// Reads the description of the album in a directory, rendered to html.
+/// Both kinds of files are taken as Markdown, which plain text mostly is.
+pub fn read_description(dir: &Path) -> Option<String> {
+    DESCRIPTION_FILES
+        .iter()
+        .filter_map(|name| read_to_string(dir.join(name)).ok())
+        .map(|text| markdown::render(&text))
+        .find(|html| !html.is_empty())
+}
+
+/// Checks whether a file should be indexed. Videos are only read if ffmpeg
+/// was found, and are picked up once it's installed otherwise.
+pub fn is_indexed(context: &ServerContext, file: &Path) -> bool {
+    is_image(file) || (context.video_support && is_video(file))
 }
 
 #[derive(Debug)]
@@ -88,16 +148,35 @@ pub struct GalleryScanner {
 
 fn process_image(context: &ServerContext, file: &Path) -> Result<Arc<ImageInfo>, ScannerError> {
     let file_name = file.to_str().ok_or(ScannerError::Charset)?;
-    println!("Adding {}", file_name);
 
-    let image_file = ImageFile::build_from_path(file.to_path_buf())?;
+    // Files can be queued more than once, e.g. by an upload and then again
+    // by the watcher, so only index files that aren't known already.
+    let info = match context
+        .datastore
+        .find_image_by_name(file_name.to_string())?
+    {
+        Some(info) => info,
+        None => {
+            println!("Adding {}", file_name);
 
-    image_file.scale_and_save(2048, 2048, &context.preview_dir)?;
-    image_file.scale_and_save(256, 256, &context.thumb_dir)?;
+            let image_file = if is_video(file) {
+                ImageFile::build_from_video(file.to_path_buf(), &context.preview_dir)?
+            } else {
+                ImageFile::build_from_path(file.to_path_buf())?
+            };
+
+            image_file.scale_and_save(2048, 2048, &context.preview_dir)?;
+            image_file.scale_and_save(256, 256, &context.thumb_dir)?;
 
-    let info = image_file.build_info()?;
+            let mut info = image_file.build_info()?;
 
-    context.datastore.save_image(info.clone())?;
+            info.id = context.datastore.save_image(info.clone())?;
+            let meta = index_metadata(context, &info)?;
+            info.set_meta(&meta);
+
+            info
+        }
+    };
 
     let parent = file
         .parent()
@@ -109,17 +188,14 @@ fn process_image(context: &ServerContext, file: &Path) -> Result<Arc<ImageInfo>,
     let info = Arc::new(info);
     let op = GalleryModification::Add(info.clone());
 
-    let root_gallery = context.get_root_gallery()?;
-    let new_root = root_gallery.modify(&parent, op)?;
-
-    context.set_root_gallery(new_root)?;
+    context.modify_root_gallery(&parent, op)?;
 
     Ok(info)
 }
 
 impl GalleryScanner {
-    pub fn new(context: ServerContext) -> GalleryScanner {
-        let (indexing_queue, indexing_receiver) = channel();
+    pub fn new(context: ServerContext, indexing_receiver: Receiver<PathBuf>) -> GalleryScanner {
+        let indexing_queue = context.indexing_queue.clone();
 
         GalleryScanner {
             context,
@@ -149,7 +225,9 @@ impl GalleryScanner {
     pub fn scan(&mut self) -> Result<(), io::Error> {
         let gallery_dir = &self.context.gallery_dir.clone();
 
-        let gallery = Arc::new(self.scan_recursive(gallery_dir, &is_image)?);
+        let context = self.context.clone();
+        let accept = |file: &Path| is_indexed(&context, file);
+        let gallery = Arc::new(self.scan_recursive(gallery_dir, &accept)?);
 
         self.context
             .set_root_gallery(gallery)
@@ -168,6 +246,7 @@ impl GalleryScanner {
             .to_path_buf();
 
         let mut new_gallery = ImageGallery::new(local_path);
+        new_gallery.description = read_description(dir);
 
         for entry in read_dir(dir)?.filter_map(|x| x.ok()) {
             let filetype = entry.file_type()?;
@@ -184,7 +263,23 @@ impl GalleryScanner {
                 }
             } else if filetype.is_file() && accept(&p) {
                 match self.find_file(&p) {
-                    Ok(Some(info)) => {
+                    Ok(Some(mut info)) => {
+                        // Keeps the size and modification time up to date
+                        // for sorting, as files can change while the server
+                        // isn't running
+                        let (size, modified) = file_stats(&p);
+                        if (size, modified) != (info.size, info.modified) {
+                            info.size = size;
+                            info.modified = modified;
+                            if let Err(e) = self
+                                .context
+                                .datastore
+                                .set_image_file_stats(info.id, size, modified)
+                            {
+                                println!("Failed to update {:?}: {:?}", p, e);
+                            }
+                        }
+
                         new_gallery.imagecount += 1;
                         new_gallery.images.insert(Arc::new(info));
                     }
@@ -199,6 +294,8 @@ impl GalleryScanner {
             }
         }
 
+        new_gallery.summarize();
+
         println!(
             "Finished scanning directory {:?} with {} images",
             dir, new_gallery.imagecount
@@ -218,15 +315,109 @@ impl GalleryScanner {
         Ok(query_result)
     }
 
+    /// Checks whether an event was caused by the server itself, e.g. when
+    /// moving or deleting images through the web interface, in which case
+    /// the gallery has been updated already.
+    fn is_suppressed(&self, event: &DebouncedEvent) -> bool {
+        match *event {
+            DebouncedEvent::Create(ref path)
+            | DebouncedEvent::Write(ref path)
+            | DebouncedEvent::Remove(ref path) => self.context.take_suppressed(path),
+            DebouncedEvent::Rename(ref from_path, ref to_path) => {
+                let from_suppressed = self.context.take_suppressed(from_path);
+                let to_suppressed = self.context.take_suppressed(to_path);
+                from_suppressed || to_suppressed
+            }
+            _ => false,
+        }
+    }
+
+    /// Reads the metadata of the files a changed sidecar belongs to again.
+    fn reread_sidecar(&mut self, sidecar: &Path) -> Result<(), io::Error> {
+        for file in sidecar_owners(sidecar) {
+            let mut info = match self
+                .find_file(&file)
+                .or(build_io_result("Failed to find file"))?
+            {
+                Some(x) => x,
+                None => continue,
+            };
+
+            println!("Detected changed sidecar of {:?}", file);
+            let meta = index_metadata(&self.context, &info)
+                .or(build_io_result("Failed to read metadata"))?;
+            info.set_meta(&meta);
+
+            let parent = match file
+                .parent()
+                .and_then(|x| x.strip_prefix(&self.context.gallery_dir).ok())
+            {
+                Some(x) => x.to_path_buf(),
+                None => return build_io_result("Path has no parent"),
+            };
+            self.context
+                .modify_root_gallery(&parent, GalleryModification::Update(Arc::new(info)))
+                .or(build_io_result("Failed to modify root gallery"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the description of the album a changed description file is
+    /// in again.
+    fn reread_description(&mut self, file: &Path) -> Result<(), io::Error> {
+        let dir = match file.parent() {
+            Some(x) => x,
+            None => return build_io_result("Path has no parent"),
+        };
+        let local_path = match dir.strip_prefix(&self.context.gallery_dir) {
+            Ok(x) => x.to_path_buf(),
+            Err(_) => return build_io_result("Failed to strip directory prefix"),
+        };
+
+        println!("Detected changed description: {:?}", file);
+        let op = GalleryModification::Describe(read_description(dir));
+        self.context
+            .modify_root_gallery(&local_path, op)
+            .or(build_io_result("Failed to modify root gallery"))?;
+
+        Ok(())
+    }
+
     fn handle_update(&mut self, event: DebouncedEvent) -> Result<(), io::Error> {
-        let root_gallery = self
-            .context
-            .get_root_gallery()
-            .or(build_io_result("Failed to get root gallery"))?;
+        if self.is_suppressed(&event) {
+            return Ok(());
+        }
+
+        let paths: Vec<PathBuf> = match event {
+            DebouncedEvent::Create(ref path)
+            | DebouncedEvent::Write(ref path)
+            | DebouncedEvent::Remove(ref path) => vec![path.clone()],
+            DebouncedEvent::Rename(ref from_path, ref to_path) => {
+                vec![from_path.clone(), to_path.clone()]
+            }
+            _ => Vec::new(),
+        };
+
+        let sidecars: Vec<&PathBuf> = paths.iter().filter(|x| is_sidecar(x)).collect();
+        if !sidecars.is_empty() {
+            for sidecar in sidecars {
+                self.reread_sidecar(sidecar)?;
+            }
+            return Ok(());
+        }
+
+        let descriptions: Vec<&PathBuf> = paths.iter().filter(|x| is_description(x)).collect();
+        if !descriptions.is_empty() {
+            for description in descriptions {
+                self.reread_description(description)?;
+            }
+            return Ok(());
+        }
 
         match event {
             DebouncedEvent::Create(ref path) => {
-                if !is_image(path) {
+                if !is_indexed(&self.context, path) {
                     return Ok(());
                 }
 
@@ -245,10 +436,9 @@ impl GalleryScanner {
                     println!("Found new image: {:?}", path);
                     let op = GalleryModification::Add(Arc::new(info));
 
-                    let new_root = root_gallery.modify(&parent, op)?;
                     self.context
-                        .set_root_gallery(new_root)
-                        .or(build_io_result("Failed to set root gallery"))?;
+                        .modify_root_gallery(&parent, op)
+                        .or(build_io_result("Failed to modify root gallery"))?;
                 } else {
                     self.indexing_queue
                         .send(path.clone())
@@ -256,7 +446,7 @@ impl GalleryScanner {
                 }
             }
             DebouncedEvent::Remove(ref path) => {
-                if !is_image(path) {
+                if !is_indexed(&self.context, path) {
                     return Ok(());
                 }
 
@@ -277,13 +467,12 @@ impl GalleryScanner {
                 println!("Detected removed image: {:?}", path);
                 let op = GalleryModification::Remove(info);
 
-                let new_root = root_gallery.modify(&parent, op)?;
                 self.context
-                    .set_root_gallery(new_root)
-                    .or(build_io_result("Failed to set root gallery"))?;
+                    .modify_root_gallery(&parent, op)
+                    .or(build_io_result("Failed to modify root gallery"))?;
             }
             DebouncedEvent::Rename(ref from_path, ref to_path) => {
-                if !is_image(from_path) {
+                if !is_indexed(&self.context, from_path) {
                     return Ok(());
                 }
 
@@ -306,11 +495,9 @@ impl GalleryScanner {
                     .send(to_path.clone())
                     .or(build_io_result("Failed to send file to indexing thread"))?;
 
-                let new_root =
-                    root_gallery.modify(&from_parent, GalleryModification::Remove(from_info))?;
                 self.context
-                    .set_root_gallery(new_root)
-                    .or(build_io_result("Failed to set root gallery"))?;
+                    .modify_root_gallery(&from_parent, GalleryModification::Remove(from_info))
+                    .or(build_io_result("Failed to modify root gallery"))?;
             }
             DebouncedEvent::Write(_) => {}
             DebouncedEvent::Rescan => {}
@@ -355,6 +542,20 @@ pub struct ImageGallery {
     pub sub_galleries: BTreeSet<Arc<ImageGallery>>,
     pub images: BTreeSet<Arc<ImageInfo>>,
     pub imagecount: u32,
+    /// The description of the album, rendered to html.
+    pub description: Option<String>,
+    /// Total size of the files in bytes, capture times of the first and the
+    /// last image, and the best rated image, all including sub galleries.
+    /// They're kept up to date like `imagecount`, see `summarize`.
+    pub size: i64,
+    pub taken: Option<(i64, i64)>,
+    pub best: Option<Arc<ImageInfo>>,
+}
+
+/// Checks whether an image makes a better cover than another, by rating
+/// and then by name.
+fn is_better_cover(image: &ImageInfo, other: &ImageInfo) -> bool {
+    image.rating > other.rating || (image.rating == other.rating && image.name < other.name)
 }
 
 impl Ord for ImageGallery {
@@ -381,6 +582,10 @@ impl Eq for ImageGallery {}
 pub enum GalleryModification {
     Add(Arc<ImageInfo>),
     Remove(Arc<ImageInfo>),
+    /// Replaces the information about an image, if it's part of the gallery.
+    Update(Arc<ImageInfo>),
+    /// Replaces the description of the gallery, if it exists.
+    Describe(Option<String>),
 }
 
 impl ImageGallery {
@@ -390,9 +595,50 @@ impl ImageGallery {
             sub_galleries: BTreeSet::new(),
             images: BTreeSet::new(),
             imagecount: 0,
+            description: None,
+            size: 0,
+            taken: None,
+            best: None,
         }
     }
 
+    /// Works out `size`, `taken` and `best` from the images of the gallery
+    /// and the figures of its sub galleries, which are summarized already.
+    fn summarize(&mut self) {
+        let mut size = 0;
+        let mut taken: Option<(i64, i64)> = None;
+        let mut best: Option<&Arc<ImageInfo>> = None;
+
+        let parts = self
+            .images
+            .iter()
+            .map(|x| (x.size, x.taken.map(|t| (t, t)), Some(x)))
+            .chain(
+                self.sub_galleries
+                    .iter()
+                    .map(|x| (x.size, x.taken, x.best.as_ref())),
+            );
+        for (part_size, part_taken, part_best) in parts {
+            size += part_size;
+            taken = match (taken, part_taken) {
+                (Some((first, last)), Some((part_first, part_last))) => {
+                    Some((first.min(part_first), last.max(part_last)))
+                }
+                (x, y) => x.or(y),
+            };
+            if let Some(image) = part_best {
+                if best.map_or(true, |x| is_better_cover(image, x)) {
+                    best = Some(image);
+                }
+            }
+        }
+
+        let best = best.cloned();
+        self.size = size;
+        self.taken = taken;
+        self.best = best;
+    }
+
     pub fn get_name(&self) -> String {
         self.path
             .file_name()
@@ -442,6 +688,7 @@ impl ImageGallery {
         op: GalleryModification,
     ) -> Result<Arc<ImageGallery>, io::Error> {
         let mut new_self = ImageGallery::new(self.path.clone());
+        new_self.description = self.description.clone();
         let mut found_gallery = false;
         for subgallery in &self.sub_galleries {
             let new_subgallery = if dir_path.starts_with(&subgallery.path) {
@@ -465,16 +712,32 @@ impl ImageGallery {
         if dir_path.as_path() == self.path.as_path() {
             match op {
                 GalleryModification::Add(info) => {
-                    new_self.images.insert(info.clone());
-                    new_self.imagecount += 1;
+                    if new_self.images.replace(info.clone()).is_none() {
+                        new_self.imagecount += 1;
+                    }
                 }
                 GalleryModification::Remove(info) => {
                     if new_self.images.remove(&info) {
                         new_self.imagecount -= 1;
                     }
                 }
+                GalleryModification::Update(info) => {
+                    if new_self.images.contains(&info) {
+                        new_self.images.replace(info);
+                    }
+                }
+                GalleryModification::Describe(description) => {
+                    new_self.description = description;
+                }
             }
         } else if !found_gallery {
+            // Galleries only exist as long as there are images in them, and
+            // pick up their description when they're created
+            if let GalleryModification::Describe(_) = op {
+                new_self.summarize();
+                return Ok(Arc::new(new_self));
+            }
+
             match self.build_next_path_step(dir_path) {
                 Some(new_subpath) => {
                     let new_subgallery =
@@ -490,9 +753,31 @@ impl ImageGallery {
             }
         }
 
+        new_self.summarize();
         Ok(Arc::new(new_self))
     }
 
+    /// Collects every image in this gallery and all of its sub galleries.
+    pub fn all_images(&self) -> Vec<Arc<ImageInfo>> {
+        let mut images: Vec<Arc<ImageInfo>> = self.images.iter().cloned().collect();
+        for gallery in &self.sub_galleries {
+            images.extend(gallery.all_images());
+        }
+
+        images
+    }
+
+    /// Looks up an image by its file name in the gallery at `dir_path`, which
+    /// may be this gallery itself.
+    pub fn find_image(&self, dir_path: &PathBuf, name: &str) -> Option<Arc<ImageInfo>> {
+        if dir_path.as_path() == self.path.as_path() {
+            return self.images.iter().find(|x| x.name == name).cloned();
+        }
+
+        self.find_gallery_from_name(dir_path)
+            .and_then(|gallery| gallery.images.iter().find(|x| x.name == name).cloned())
+    }
+
     pub fn find_gallery_from_name(&self, search_path: &PathBuf) -> Option<Arc<ImageGallery>> {
         for gallery in &self.sub_galleries {
             if gallery.path.as_path() == search_path.as_path() {
@@ -509,21 +794,59 @@ impl ImageGallery {
     }
 }
 
+/// The type of a file as recorded for formats told apart by their
+/// extension, e.g. "NEF" or "MP4".
+fn extension_type(path: &Path) -> String {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .unwrap_or("")
+        .to_ascii_uppercase()
+}
+
 pub struct ImageFile {
     pub path: PathBuf,
+    /// The image itself, or the poster frame of a video.
     pub image: DynamicImage,
     pub hash: String,
+    pub img_type: String,
 }
 
 impl ImageFile {
     pub fn build_from_path(path: PathBuf) -> Result<ImageFile, io::Error> {
         let img = open_image(&path).or(build_io_result("Failed to open image"))?;
         let hash = hash_file(&path)?;
+        let img_type = if is_raw(&path) {
+            extension_type(&path)
+        } else {
+            "JPEG".to_string()
+        };
 
         Ok(ImageFile {
             path,
             image: img,
             hash: hash,
+            img_type,
+        })
+    }
+
+    /// Reads a video through a frame extracted with ffmpeg, which is
+    /// written to `temp_dir` on the way.
+    pub fn build_from_video(path: PathBuf, temp_dir: &Path) -> Result<ImageFile, io::Error> {
+        let probe = video::probe(&path)?;
+
+        let frame_path = temp_dir.join(format!(".{:?}.poster.jpg", thread::current().id()));
+        let frame = video::poster_frame(&path, probe.duration, &frame_path)
+            .and_then(|_| open_image(&frame_path).or(build_io_result("Failed to open frame")));
+        let _ = remove_file(&frame_path);
+
+        let hash = hash_file(&path)?;
+        let img_type = extension_type(&path);
+
+        Ok(ImageFile {
+            path,
+            image: frame?,
+            hash,
+            img_type,
         })
     }
 
@@ -557,13 +880,19 @@ impl ImageFile {
         let file_name = self.path.to_str().ok_or(ScannerError::Charset)?;
 
         let (width, height) = self.image.dimensions();
+        let (size, modified) = file_stats(&self.path);
         Ok(ImageInfo {
             id: 0,
             name: file_name.to_string(),
             hash: self.hash.clone(),
             width: width,
             height: height,
-            img_type: "JPEG".to_string(),
+            img_type: self.img_type.clone(),
+            taken: None,
+            rating: None,
+            label: None,
+            size,
+            modified,
         })
     }
 }
@@ -0,0 +1,147 @@
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Encodings derivatives can be served in. JPEG and PNG are written by the
+/// image crate, the others by the command line encoders of their reference
+/// libraries, if installed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+/// Formats preferred over JPEG by content negotiation, best first.
+const NEGOTIATED_FORMATS: &[OutputFormat] = &[OutputFormat::Avif, OutputFormat::Webp];
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
+            "png" => Some(OutputFormat::Png),
+            "webp" => Some(OutputFormat::Webp),
+            "avif" => Some(OutputFormat::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match *self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    /// The external encoder and the arguments to check whether it's there.
+    fn encoder(&self) -> Option<(&'static str, &'static str)> {
+        match *self {
+            OutputFormat::Webp => Some(("cwebp", "-version")),
+            OutputFormat::Avif => Some(("avifenc", "--version")),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the formats that can be written, probing for the external
+/// encoders.
+pub fn available_formats() -> Vec<OutputFormat> {
+    let mut formats = vec![OutputFormat::Jpeg, OutputFormat::Png];
+    for format in &[OutputFormat::Webp, OutputFormat::Avif] {
+        let (program, version_arg) = match format.encoder() {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let found = Command::new(program)
+            .arg(version_arg)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|x| x.success())
+            .unwrap_or(false);
+        if found {
+            formats.push(*format);
+        }
+    }
+
+    formats
+}
+
+/// Checks whether an Accept header lists a media type, ignoring wildcards
+/// since every browser sends `*/*` whatever it supports.
+fn accepts(accept: &str, content_type: &str) -> bool {
+    accept.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(|x| x.trim());
+        if parts.next() != Some(content_type) {
+            return false;
+        }
+
+        // A quality of zero explicitly rules the type out
+        !parts.any(|x| {
+            x.starts_with("q=") && x[2..].parse::<f64>().map(|q| q == 0.0).unwrap_or(false)
+        })
+    })
+}
+
+/// Picks the best available format the client accepts, falling back to
+/// JPEG which every client handles.
+pub fn negotiate(accept: Option<&str>, available: &[OutputFormat]) -> OutputFormat {
+    let accept = accept.unwrap_or("");
+    NEGOTIATED_FORMATS
+        .iter()
+        .find(|x| available.contains(x) && accepts(accept, x.content_type()))
+        .cloned()
+        .unwrap_or(OutputFormat::Jpeg)
+}
+
+/// Converts a JPEG or PNG file with an external encoder.
+pub fn encode_file(
+    format: OutputFormat,
+    quality: u8,
+    input: &Path,
+    output: &Path,
+) -> io::Result<()> {
+    let (program, _) = format.encoder().ok_or(io::Error::new(
+        ErrorKind::InvalidInput,
+        "Format has no external encoder",
+    ))?;
+
+    let quality = quality.to_string();
+    let mut command = Command::new(program);
+    match format {
+        OutputFormat::Webp => command
+            .args(&["-quiet", "-q", &quality])
+            .arg(input)
+            .arg("-o")
+            .arg(output),
+        _ => command.args(&["-q", &quality]).arg(input).arg(output),
+    };
+
+    let status = command
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        let _ = fs::remove_file(output);
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("{} failed with {}", program, status),
+        ));
+    }
+
+    Ok(())
+}
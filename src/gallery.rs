@@ -1,6 +1,7 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
 use std::io::Result;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::UNIX_EPOCH;
 
@@ -9,10 +10,349 @@ use chrono::Duration;
 use handlebars::Handlebars;
 use regex::{Captures, Regex};
 use rustc_serialize::json::{Json, ToJson};
-use tiny_http::{Header, HeaderField, Request, Response};
+use tiny_http::{Header, HeaderField, Request};
 
+use crate::auth::current_user;
 use crate::context::ServerContext;
-use crate::web::{error_response, url_decode, Action, WebServer};
+use crate::db::ImageInfo;
+use crate::file::ImageGallery;
+use crate::format::negotiate;
+use crate::range::file_response;
+use crate::raw;
+use crate::resize::{derivative_in_format, PIXEL_RATIOS};
+use crate::sort::{gallery_sort, SortOrder};
+use crate::stack::{build_stacks, Stack};
+use crate::video;
+use crate::web::{
+    error_response, get_header, html_response, http_date, is_not_modified, not_found_response,
+    not_modified_response, query_params, query_string, url_decode, url_encode, Action, WebServer,
+};
+use crate::xmp::LABELS;
+
+/// Returns the path of an image relative to the gallery directory.
+pub fn image_relative_path(context: &ServerContext, image: &ImageInfo) -> PathBuf {
+    let path = Path::new(&image.name);
+    path.strip_prefix(&context.gallery_dir)
+        .unwrap_or(path)
+        .to_path_buf()
+}
+
+/// Height of the rows of tiles in the grid, at most.
+const TILE_HEIGHT: u32 = 200;
+
+/// Lists the thumbnails of an image for displays of each pixel ratio, as
+/// the `srcset` of its tile. High density displays get sharper ones from
+/// the resize endpoint, as long as the tile height is one of its sizes.
+fn thumb_srcset(context: &ServerContext, image: &ImageInfo) -> Option<String> {
+    if !context.resize_sizes.contains(&TILE_HEIGHT) {
+        return None;
+    }
+
+    let mut candidates = vec![format!("/image/{}/thumb 1x", image.hash)];
+    for ratio in PIXEL_RATIOS.iter().filter(|&&x| x > 1) {
+        candidates.push(format!(
+            "/image/{}/resize?h={}&dpr={} {}x",
+            image.hash, TILE_HEIGHT, ratio, ratio
+        ));
+    }
+
+    Some(candidates.join(", "))
+}
+
+/// Builds the summary of an image shared by the html views and the api.
+pub fn image_to_json(context: &ServerContext, image: &ImageInfo) -> Json {
+    let path = image_relative_path(context, image);
+
+    let mut image_dict = BTreeMap::new();
+    image_dict.insert(
+        "name".to_string(),
+        path.file_name()
+            .and_then(|x| x.to_str())
+            .unwrap_or("")
+            .to_json(),
+    );
+    image_dict.insert("path".to_string(), path.to_str().unwrap_or("").to_json());
+    image_dict.insert(
+        "gallery".to_string(),
+        path.parent()
+            .and_then(|x| x.to_str())
+            .unwrap_or("")
+            .to_json(),
+    );
+    image_dict.insert("hash".to_string(), image.hash.to_json());
+    image_dict.insert("srcset".to_string(), thumb_srcset(context, image).to_json());
+    image_dict.insert("width".to_string(), image.width.to_json());
+    image_dict.insert("height".to_string(), image.height.to_json());
+    image_dict.insert("type".to_string(), image.img_type.to_json());
+    image_dict.insert(
+        "video".to_string(),
+        video::is_video(Path::new(&image.name)).to_json(),
+    );
+    image_dict.insert("rating".to_string(), image.rating.to_json());
+    image_dict.insert("label".to_string(), image.label.to_json());
+    image_dict.insert(
+        "taken".to_string(),
+        image
+            .taken
+            .map(|x| {
+                NaiveDateTime::from_timestamp(x, 0)
+                    .format("%Y-%m-%dT%H:%M:%S")
+                    .to_string()
+            })
+            .to_json(),
+    );
+    Json::Object(image_dict)
+}
+
+/// Returns the hashes of the favourites of the user making a request, which
+/// are none if it isn't logged in.
+pub fn user_favourites(request: &Request, context: &ServerContext) -> BTreeSet<String> {
+    current_user(request, context)
+        .and_then(|user| context.datastore.find_favourites(user).ok())
+        .unwrap_or_default()
+}
+
+/// Adds whether an image is a favourite of the current user to its summary.
+pub fn mark_favourite(json: Json, image: &ImageInfo, favourites: &BTreeSet<String>) -> Json {
+    match json {
+        Json::Object(mut image_dict) => {
+            image_dict.insert(
+                "favourite".to_string(),
+                favourites.contains(&image.hash).to_json(),
+            );
+            Json::Object(image_dict)
+        }
+        x => x,
+    }
+}
+
+/// Narrows a listing down to images with at least the given number of
+/// stars and the given colour label, as passed in the query.
+pub struct ImageFilter {
+    pub min_rating: Option<u32>,
+    pub label: Option<String>,
+}
+
+impl ImageFilter {
+    pub fn from_query(params: &BTreeMap<String, String>) -> ImageFilter {
+        ImageFilter {
+            min_rating: params
+                .get("rating")
+                .and_then(|x| x.parse::<u32>().ok())
+                .filter(|&x| x > 0),
+            label: params.get("label").cloned().filter(|x| !x.is_empty()),
+        }
+    }
+
+    pub fn matches(&self, image: &ImageInfo) -> bool {
+        let rating = match self.min_rating {
+            Some(min) => image.rating.unwrap_or(0) >= min,
+            None => true,
+        };
+        let label = match (&self.label, &image.label) {
+            (Some(wanted), Some(label)) => wanted.eq_ignore_ascii_case(label),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+
+        rating && label
+    }
+
+    /// Lists the choices of the filter form, with the current ones selected.
+    pub fn options_to_json(&self) -> Json {
+        let option = |value: String, name: String, selected: bool| {
+            let mut option_dict = BTreeMap::new();
+            option_dict.insert("value".to_string(), value.to_json());
+            option_dict.insert("name".to_string(), name.to_json());
+            option_dict.insert("selected".to_string(), selected.to_json());
+            Json::Object(option_dict)
+        };
+
+        let ratings = (0..6)
+            .map(|x| {
+                let name = match x {
+                    0 => "Any rating".to_string(),
+                    5 => "5 stars".to_string(),
+                    x => format!("{} stars or more", x),
+                };
+                option(x.to_string(), name, self.min_rating.unwrap_or(0) == x)
+            })
+            .collect::<Vec<Json>>();
+
+        let mut labels = vec![option(
+            String::new(),
+            "Any label".to_string(),
+            self.label.is_none(),
+        )];
+        labels.extend(LABELS.iter().map(|x| {
+            let selected = self
+                .label
+                .as_ref()
+                .map_or(false, |label| label.eq_ignore_ascii_case(x));
+            option(x.to_string(), x.to_string(), selected)
+        }));
+
+        let mut options_dict = BTreeMap::new();
+        options_dict.insert("ratings".to_string(), Json::Array(ratings));
+        options_dict.insert("labels".to_string(), Json::Array(labels));
+        Json::Object(options_dict)
+    }
+}
+
+/// Builds the summary of the cover of a stack, listing the other images
+/// under "stack" if there are any.
+pub fn stack_to_json(
+    context: &ServerContext,
+    stack: &Stack,
+    favourites: &BTreeSet<String>,
+) -> Json {
+    let summary =
+        |image: &ImageInfo| mark_favourite(image_to_json(context, image), image, favourites);
+    let mut json = summary(stack.cover());
+    if stack.images.len() > 1 {
+        if let Json::Object(ref mut image_dict) = json {
+            image_dict.insert("stack_count".to_string(), stack.images.len().to_json());
+            image_dict.insert(
+                "stack".to_string(),
+                Json::Array(stack.images[1..].iter().map(|x| summary(x)).collect()),
+            );
+        }
+    }
+
+    json
+}
+
+/// Picks the image shown on the card of a gallery: the one chosen by a
+/// user if it's still part of the gallery, and the best rated one
+/// otherwise.
+fn gallery_cover(
+    context: &ServerContext,
+    gallery: &ImageGallery,
+    covers: &BTreeMap<String, String>,
+) -> Option<Arc<ImageInfo>> {
+    let picked = covers
+        .get(&gallery.get_path())
+        .and_then(|hash| context.datastore.find_images_by_hash(hash.clone()).ok())
+        .and_then(|candidates| {
+            candidates.into_iter().find_map(|info| {
+                let parent = image_relative_path(context, &info)
+                    .parent()
+                    .map(|x| x.to_path_buf())
+                    .unwrap_or_default();
+                gallery.find_image(&parent, &info.name)
+            })
+        });
+
+    picked.or_else(|| gallery.best.clone())
+}
+
+/// Formats a number of bytes for people to read, e.g. "2.5 GB".
+pub fn format_size(bytes: i64) -> String {
+    let units = ["bytes", "kB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1000.0 && unit < units.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn format_day(timestamp: i64) -> String {
+    NaiveDateTime::from_timestamp(timestamp, 0)
+        .format("%Y-%m-%d")
+        .to_string()
+}
+
+/// Builds the summary of a gallery shared by the html views and the api.
+/// `covers` are the covers picked for galleries, see
+/// `DataStore::find_gallery_covers`.
+pub fn gallery_to_json(
+    context: &ServerContext,
+    gallery: &ImageGallery,
+    covers: &BTreeMap<String, String>,
+) -> Json {
+    let mut gallery_dict = BTreeMap::new();
+    gallery_dict.insert("name".to_string(), gallery.get_name().to_json());
+    gallery_dict.insert("imagecount".to_string(), gallery.imagecount.to_json());
+    gallery_dict.insert(
+        "cover".to_string(),
+        gallery_cover(context, gallery, covers)
+            .map(|x| x.hash.clone())
+            .to_json(),
+    );
+    gallery_dict.insert("size".to_string(), gallery.size.to_json());
+    gallery_dict.insert(
+        "size_label".to_string(),
+        format_size(gallery.size).to_json(),
+    );
+    if let Some((first, last)) = gallery.taken {
+        let (first, last) = (format_day(first), format_day(last));
+        let label = if first == last {
+            first.clone()
+        } else {
+            format!("{} \u{2013} {}", first, last)
+        };
+        gallery_dict.insert("taken_from".to_string(), first.to_json());
+        gallery_dict.insert("taken_until".to_string(), last.to_json());
+        gallery_dict.insert("taken_label".to_string(), label.to_json());
+    }
+    gallery_dict.insert("path".to_string(), gallery.get_path().to_json());
+    Json::Object(gallery_dict)
+}
+
+/// Number of tiles the gallery page starts out with, and of images or
+/// stacks in a page of the gallery api. The rest are loaded from the api
+/// while scrolling.
+pub const GALLERY_PAGE_SIZE: usize = 200;
+
+/// Reads the `offset` and `limit` of a page of images from request
+/// parameters. The limit is kept between 1 and `GALLERY_PAGE_SIZE`, so that
+/// a single request can't have every image in the library described at once.
+pub fn page_params(params: &BTreeMap<String, String>, default_limit: usize) -> (usize, usize) {
+    let offset = params
+        .get("offset")
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(0);
+    let limit = params
+        .get("limit")
+        .and_then(|x| x.parse::<usize>().ok())
+        .unwrap_or(default_limit);
+
+    (offset, limit.max(1).min(GALLERY_PAGE_SIZE))
+}
+
+/// Links to a page of images, or stacks of them, of a gallery in the api,
+/// keeping the filter and sort order of the request.
+pub fn gallery_page_url(
+    path: &str,
+    params: &BTreeMap<String, String>,
+    stacked: bool,
+    offset: usize,
+    limit: usize,
+) -> String {
+    let mut page_params: BTreeMap<String, String> = ["rating", "label", "sort", "order"]
+        .iter()
+        .filter_map(|&name| params.get(name).map(|x| (name.to_string(), x.clone())))
+        .collect();
+    if stacked {
+        page_params.insert("stack".to_string(), "true".to_string());
+    }
+    page_params.insert("offset".to_string(), offset.to_string());
+    page_params.insert("limit".to_string(), limit.to_string());
+
+    let path = path
+        .split('/')
+        .map(|x| url_encode(x))
+        .collect::<Vec<_>>()
+        .join("/");
+    format!("/api/v1/gallery/{}?{}", path, query_string(&page_params))
+}
 
 pub struct GalleryAction {}
 
@@ -53,36 +393,59 @@ impl Action for GalleryAction {
             .and_then(|x| root_gallery.find_gallery_from_name(&x))
             .unwrap_or(root_gallery);
 
-        let mut sub_galleries = Vec::new();
-        for sub_gallery in &gallery.sub_galleries {
-            let mut gallery_dict = BTreeMap::new();
-            gallery_dict.insert("path".to_string(), sub_gallery.get_path().to_json());
-            gallery_dict.insert("name".to_string(), sub_gallery.get_name().to_json());
-            sub_galleries.push(Json::Object(gallery_dict));
-        }
-
-        let sub_galleries = Json::Array(sub_galleries);
-
-        let mut images = Vec::new();
-        for image in &gallery.images {
-            let mut image_dict = BTreeMap::new();
-            image_dict.insert("name".to_string(), image.name.to_json());
-            image_dict.insert("hash".to_string(), image.hash.to_json());
-            image_dict.insert("width".to_string(), image.width.to_json());
-            image_dict.insert("height".to_string(), image.height.to_json());
-            images.push(Json::Object(image_dict));
-        }
+        let covers = context.datastore.find_gallery_covers().unwrap_or_default();
+        let sub_galleries = Json::Array(
+            gallery
+                .sub_galleries
+                .iter()
+                .map(|x| gallery_to_json(&context, x, &covers))
+                .collect(),
+        );
 
-        let images = Json::Array(images);
+        let params = query_params(request.url());
+        let filter = ImageFilter::from_query(&params);
+        let sort = SortOrder::from_query(&params, gallery_sort(&context, &gallery.path));
+        let favourites = user_favourites(&request, &context);
+        let mut stacks = build_stacks(
+            gallery.images.iter().filter(|x| filter.matches(x)),
+            context.stack_window,
+        );
+        sort.sort_stacks(&mut stacks);
+        let images = Json::Array(
+            stacks
+                .iter()
+                .take(GALLERY_PAGE_SIZE)
+                .map(|x| stack_to_json(&context, x, &favourites))
+                .collect(),
+        );
 
         let mut result_dict = BTreeMap::new();
         result_dict.insert("name".to_string(), gallery.get_name().to_json());
+        result_dict.insert("path".to_string(), gallery.get_path().to_json());
         if let Some(parent) = gallery.get_parent() {
             result_dict.insert("has_parent".to_string(), true.to_json());
             result_dict.insert("parent".to_string(), parent.to_json());
         }
+        if let Some(ref description) = gallery.description {
+            result_dict.insert("description".to_string(), description.to_json());
+        }
         result_dict.insert("sub_galleries".to_string(), sub_galleries);
         result_dict.insert("images".to_string(), images);
+        result_dict.insert("filter".to_string(), filter.options_to_json());
+        result_dict.insert("sort".to_string(), sort.options_to_json());
+        if stacks.len() > GALLERY_PAGE_SIZE {
+            result_dict.insert(
+                "next_url".to_string(),
+                gallery_page_url(
+                    &gallery.get_path(),
+                    &params,
+                    true,
+                    GALLERY_PAGE_SIZE,
+                    GALLERY_PAGE_SIZE,
+                )
+                .to_json(),
+            );
+        }
         let result_obj = Json::Object(result_dict);
 
         let html_data = match handlebars.render("gallery", &result_obj).ok() {
@@ -90,13 +453,135 @@ impl Action for GalleryAction {
             None => return error_response(request, "Failed to encode response"),
         };
 
-        let mut response = Response::from_string(html_data);
-        response.add_header(Header {
-            field: "Content-Type".parse::<HeaderField>().unwrap(),
-            value: "text/html".parse().unwrap(),
+        html_response(request, html_data)
+    }
+}
+
+/// Sends an image file with headers that let browsers cache it for good,
+/// as derivatives never change for a given hash. Files whose format was
+/// chosen from the Accept header are marked as such for caching proxies.
+pub fn image_file_response(
+    request: Request,
+    path: &Path,
+    content_type: &str,
+    negotiated: bool,
+) -> Result<()> {
+    // The file name holds the hash along with the size and format, so it
+    // identifies the content
+    let etag = format!(
+        "\"{}\"",
+        path.file_name().and_then(|x| x.to_str()).unwrap_or("")
+    );
+
+    let mut headers = Vec::new();
+    if negotiated {
+        headers.push(Header {
+            field: "Vary".parse::<HeaderField>().unwrap(),
+            value: "Accept".parse().unwrap(),
+        });
+    }
+
+    cached_file_response(request, path, content_type, &etag, headers)
+}
+
+/// Sends a file that never changes under its url, answering conditional
+/// and range requests. `headers` are sent along with the caching headers.
+fn cached_file_response(
+    request: Request,
+    path: &Path,
+    content_type: &str,
+    etag: &str,
+    mut headers: Vec<Header>,
+) -> Result<()> {
+    let file = File::open(path)?;
+
+    let modified = path
+        .metadata()
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map(|x| x.as_secs() as i64);
+
+    let expires = UTC::now() + Duration::days(365);
+    headers.push(Header {
+        field: "ETag".parse::<HeaderField>().unwrap(),
+        value: etag.parse().unwrap(),
+    });
+    headers.push(Header {
+        field: "Cache-Control".parse::<HeaderField>().unwrap(),
+        value: "private, max-age=31536000".parse().unwrap(),
+    });
+    headers.push(Header {
+        field: "Expires".parse::<HeaderField>().unwrap(),
+        value: http_date(expires.timestamp()).parse().unwrap(),
+    });
+
+    if is_not_modified(&request, etag, modified) {
+        return not_modified_response(request, headers);
+    }
+
+    headers.push(Header {
+        field: "Content-Type".parse::<HeaderField>().unwrap(),
+        value: content_type.parse().unwrap(),
+    });
+
+    if let Some(modified) = modified {
+        headers.push(Header {
+            field: "Last-Modified".parse::<HeaderField>().unwrap(),
+            value: http_date(modified).parse().unwrap(),
         });
-        return request.respond(response);
     }
+
+    file_response(request, file, headers, etag, modified)
+}
+
+/// Names the file an original is saved as. Header values must be ASCII, so
+/// other names are given percent-encoded as well.
+fn content_disposition(name: &str) -> String {
+    let ascii_name: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!(
+        "inline; filename=\"{}\"; filename*=UTF-8''{}",
+        ascii_name,
+        url_encode(name)
+    )
+}
+
+/// Sends the original file of an image or video. Its hash is that of the
+/// content, so it serves as a strong ETag.
+fn original_response(request: Request, context: &ServerContext, hash: &str) -> Result<()> {
+    let images = match context.datastore.find_images_by_hash(hash.to_string()) {
+        Ok(x) => x,
+        Err(_) => return error_response(request, "Failed to find image"),
+    };
+
+    let path = match images
+        .iter()
+        .map(|x| Path::new(&x.name))
+        .find(|x| x.exists())
+    {
+        Some(x) => x.to_path_buf(),
+        None => return not_found_response(request, "Image not found"),
+    };
+
+    let etag = format!("\"{}\"", hash);
+    let content_type = video::content_type(&path)
+        .or_else(|| raw::content_type(&path))
+        .unwrap_or("image/jpeg");
+
+    let name = path.file_name().and_then(|x| x.to_str()).unwrap_or("");
+    let headers = vec![Header {
+        field: "Content-Disposition".parse::<HeaderField>().unwrap(),
+        value: content_disposition(name).parse().unwrap(),
+    }];
+
+    cached_file_response(request, &path, content_type, &etag, headers)
 }
 
 pub struct ImageAction {}
@@ -109,7 +594,7 @@ impl ImageAction {
 
 impl Action for ImageAction {
     fn get_regex(&self) -> Regex {
-        Regex::new(r"^/image/(.+)/(.+)$").unwrap()
+        Regex::new(r"^/image/([0-9A-Fa-f]+)/(thumb|preview|original)$").unwrap()
     }
 
     fn initialize(&self, _: &mut WebServer) -> Result<()> {
@@ -129,46 +614,32 @@ impl Action for ImageAction {
         };
 
         let img_size = caps.get(2).map(|x| x.as_str()).unwrap_or("thumb");
+        if img_size == "original" {
+            return original_response(request, &context, &hash);
+        }
 
-        let mut path = match img_size {
-            "thumb" => context.thumb_dir.clone(),
-            "preview" => context.preview_dir.clone(),
+        let dir = match img_size {
+            "thumb" => &context.thumb_dir,
+            "preview" => &context.preview_dir,
             _ => return error_response(request, "Unknown image size requested"),
         };
-        path.push(hash + ".jpg");
-
-        let file = File::open(&path)?;
-
-        let mut response = Response::from_file(file);
-
-        response.add_header(Header {
-            field: "Cache-Control".parse::<HeaderField>().unwrap(),
-            value: "private, max-age=31536000".parse().unwrap(),
-        });
 
-        response.add_header(Header {
-            field: "Content-Type".parse::<HeaderField>().unwrap(),
-            value: "image/jpeg".parse().unwrap(),
-        });
-
-        if let Ok(fixed) = path.metadata().and_then(|x| x.modified()) {
-            if let Ok(time) = fixed.duration_since(UNIX_EPOCH) {
-                let modified = NaiveDateTime::from_timestamp(time.as_secs() as i64, 0);
-                let modified_formatted = modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
-                response.add_header(Header {
-                    field: "Last-Modified".parse::<HeaderField>().unwrap(),
-                    value: modified_formatted.parse().unwrap(),
-                });
+        let accept = get_header(&request, "Accept");
+        let format = negotiate(accept.as_ref().map(|x| x.as_str()), &context.image_formats);
+        let path = match derivative_in_format(dir, &hash, format) {
+            Ok(x) => x,
+            Err(e) => {
+                // Fall back to the JPEG if conversion fails
+                eprintln!("Failed to convert {} to {:?}: {:?}", hash, format, e);
+                return image_file_response(
+                    request,
+                    &dir.join(format!("{}.jpg", hash)),
+                    "image/jpeg",
+                    true,
+                );
             }
-        }
-
-        let expires = UTC::now() + Duration::days(365);
-        let expires_formatted = expires.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
-        response.add_header(Header {
-            field: "Expires".parse::<HeaderField>().unwrap(),
-            value: expires_formatted.parse().unwrap(),
-        });
+        };
 
-        return request.respond(response);
+        image_file_response(request, &path, format.content_type(), true)
     }
 }
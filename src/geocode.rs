@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::db::Place;
+
+/// GeoNames city extracts, from the most to the least detailed. The first
+/// one found in the data directory is used.
+const CITY_FILES: &[&str] = &[
+    "cities500.txt",
+    "cities1000.txt",
+    "cities5000.txt",
+    "cities15000.txt",
+];
+const REGION_FILE: &str = "admin1CodesASCII.txt";
+const COUNTRY_FILE: &str = "countryInfo.txt";
+
+/// Capitals and large cities used when there's no GeoNames data, with their
+/// region given by name rather than by code.
+const BUNDLED_CITIES: &str = include_str!("geodata/cities.txt");
+const BUNDLED_COUNTRIES: &str = include_str!("geodata/countries.txt");
+
+/// Images further away than this from any known city don't get a place.
+const MAX_DISTANCE_KM: f64 = 100.0;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+struct City {
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    country_code: String,
+    region_code: String,
+}
+
+/// Resolves coordinates to the nearest city of an offline GeoNames dataset,
+/// or of the bundled list of capitals and large cities without one.
+pub struct Geocoder {
+    cities: Vec<City>,
+    /// Indices into `cities`, by the whole degrees of their position.
+    grid: HashMap<(i32, i32), Vec<usize>>,
+    /// Region names by country and region code, e.g. "NO.12".
+    regions: HashMap<String, String>,
+    countries: HashMap<String, String>,
+    bundled: bool,
+}
+
+/// Splits the lines of a GeoNames file into columns, skipping comments.
+fn read_columns(path: &Path) -> io::Result<Vec<Vec<String>>> {
+    parse_columns(BufReader::new(File::open(path)?))
+}
+
+fn parse_columns<R: BufRead>(reader: R) -> io::Result<Vec<Vec<String>>> {
+    let mut rows = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+
+        rows.push(line.split('\t').map(|x| x.to_string()).collect());
+    }
+
+    Ok(rows)
+}
+
+/// Reads a two column lookup table, e.g. country codes and names.
+fn read_names(path: &Path, key: usize, name: usize) -> io::Result<HashMap<String, String>> {
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+
+    Ok(read_columns(path)?
+        .into_iter()
+        .filter(|x| x.len() > key.max(name))
+        .map(|x| (x[key].clone(), x[name].clone()))
+        .collect())
+}
+
+fn grid_cell(latitude: f64, longitude: f64) -> (i32, i32) {
+    (latitude.floor() as i32, longitude.floor() as i32)
+}
+
+/// Great-circle distance between two positions.
+fn distance_km(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (b.1 - a.1).to_radians();
+
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+impl Geocoder {
+    pub fn empty() -> Geocoder {
+        Geocoder {
+            cities: Vec::new(),
+            grid: HashMap::new(),
+            regions: HashMap::new(),
+            countries: HashMap::new(),
+            bundled: false,
+        }
+    }
+
+    fn add_city(&mut self, city: City) {
+        self.grid
+            .entry(grid_cell(city.latitude, city.longitude))
+            .or_insert_with(Vec::new)
+            .push(self.cities.len());
+        self.cities.push(city);
+    }
+
+    /// Loads the bundled list of capitals and large cities.
+    pub fn bundled() -> Geocoder {
+        let mut geocoder = Geocoder::empty();
+        geocoder.bundled = true;
+
+        let rows = parse_columns(BUNDLED_CITIES.as_bytes()).unwrap_or_default();
+        for row in rows.into_iter().filter(|x| x.len() == 5) {
+            let (latitude, longitude) = match (row[1].parse::<f64>(), row[2].parse::<f64>()) {
+                (Ok(lat), Ok(lon)) => (lat, lon),
+                _ => continue,
+            };
+
+            // Regions are named directly, so the name doubles as the code
+            if !row[4].is_empty() {
+                geocoder
+                    .regions
+                    .insert(format!("{}.{}", row[3], row[4]), row[4].clone());
+            }
+            geocoder.add_city(City {
+                name: row[0].clone(),
+                latitude,
+                longitude,
+                country_code: row[3].clone(),
+                region_code: row[4].clone(),
+            });
+        }
+
+        geocoder.countries = parse_columns(BUNDLED_COUNTRIES.as_bytes())
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|x| x.len() == 2)
+            .map(|x| (x[0].clone(), x[1].clone()))
+            .collect();
+
+        geocoder
+    }
+
+    /// Loads the GeoNames files found in `dir`. Without any city extract
+    /// there, the bundled list is used instead.
+    pub fn load(dir: &Path) -> io::Result<Geocoder> {
+        let city_file = match CITY_FILES.iter().map(|x| dir.join(x)).find(|x| x.exists()) {
+            Some(x) => x,
+            None => return Ok(Geocoder::bundled()),
+        };
+
+        let mut geocoder = Geocoder::empty();
+
+        for row in read_columns(&city_file)? {
+            if row.len() < 11 {
+                continue;
+            }
+
+            let (latitude, longitude) = match (row[4].parse::<f64>(), row[5].parse::<f64>()) {
+                (Ok(lat), Ok(lon)) => (lat, lon),
+                _ => continue,
+            };
+
+            geocoder.add_city(City {
+                name: row[1].clone(),
+                latitude,
+                longitude,
+                country_code: row[8].clone(),
+                region_code: row[10].clone(),
+            });
+        }
+
+        geocoder.regions = read_names(&dir.join(REGION_FILE), 0, 1)?;
+        geocoder.countries = read_names(&dir.join(COUNTRY_FILE), 0, 4)?;
+
+        Ok(geocoder)
+    }
+
+    pub fn len(&self) -> usize {
+        self.cities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cities.is_empty()
+    }
+
+    /// Whether only the bundled capitals and large cities are known.
+    pub fn is_bundled(&self) -> bool {
+        self.bundled
+    }
+
+    /// Finds the place closest to a position. Only the grid cells around
+    /// the position are searched, so cities more than a degree away may be
+    /// missed.
+    pub fn place_of(&self, latitude: f64, longitude: f64) -> Option<Place> {
+        let (cell_lat, cell_lon) = grid_cell(latitude, longitude);
+
+        let mut nearest: Option<(f64, &City)> = None;
+        for dlat in -1..=1 {
+            for dlon in -1..=1 {
+                // Cells wrap around at the antimeridian
+                let lon = (cell_lon + dlon + 180).rem_euclid(360) - 180;
+                let cities = match self.grid.get(&(cell_lat + dlat, lon)) {
+                    Some(x) => x,
+                    None => continue,
+                };
+
+                for &i in cities {
+                    let city = &self.cities[i];
+                    let distance =
+                        distance_km((latitude, longitude), (city.latitude, city.longitude));
+                    if distance <= nearest.map_or(MAX_DISTANCE_KM, |x| x.0) {
+                        nearest = Some((distance, city));
+                    }
+                }
+            }
+        }
+
+        let (_, city) = nearest?;
+        let country = self
+            .countries
+            .get(&city.country_code)
+            .cloned()
+            .unwrap_or_else(|| city.country_code.clone());
+
+        // Places without regions, like city states, use the city instead
+        let region = self
+            .regions
+            .get(&format!("{}.{}", city.country_code, city.region_code))
+            .cloned()
+            .unwrap_or_else(|| city.name.clone());
+
+        Some(Place {
+            country,
+            region,
+            city: city.name.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn city(name: &str, latitude: f64, longitude: f64) -> City {
+        City {
+            name: name.to_string(),
+            latitude,
+            longitude,
+            country_code: "XX".to_string(),
+            region_code: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_distance_km() {
+        assert_eq!(distance_km((59.91, 10.75), (59.91, 10.75)), 0.0);
+
+        // A degree of latitude is about 111 km anywhere
+        let distance = distance_km((0.0, 0.0), (1.0, 0.0));
+        assert!((distance - 111.2).abs() < 0.1);
+        let distance = distance_km((0.0, 179.5), (0.0, -179.5));
+        assert!((distance - 111.2).abs() < 0.1);
+
+        // Oslo to Bergen
+        let distance = distance_km((59.91, 10.75), (60.39, 5.32));
+        assert!(distance > 300.0 && distance < 310.0);
+    }
+
+    #[test]
+    fn test_place_of() {
+        let mut geocoder = Geocoder::empty();
+        geocoder.add_city(city("Near", 10.2, 20.2));
+        geocoder.add_city(city("Far", 10.9, 20.9));
+        geocoder
+            .countries
+            .insert("XX".to_string(), "Country".to_string());
+
+        let place = geocoder.place_of(10.25, 20.25).unwrap();
+        assert_eq!(place.city, "Near");
+        assert_eq!(place.country, "Country");
+        assert_eq!(place.region, "Near");
+
+        assert_eq!(geocoder.place_of(10.8, 20.8).unwrap().city, "Far");
+    }
+
+    #[test]
+    fn test_place_of_cutoff() {
+        let mut geocoder = Geocoder::empty();
+        geocoder.add_city(city("City", 0.5, 0.5));
+
+        assert!(geocoder.place_of(1.3, 0.5).is_some());
+        assert!(geocoder.place_of(1.45, 0.5).is_none());
+        assert!(geocoder.place_of(-30.0, 0.5).is_none());
+    }
+
+    #[test]
+    fn test_place_of_antimeridian() {
+        let mut geocoder = Geocoder::empty();
+        geocoder.add_city(city("West", -17.0, 179.9));
+        geocoder.add_city(city("East", 65.0, -179.9));
+
+        assert_eq!(geocoder.place_of(-17.0, -179.9).unwrap().city, "West");
+        assert_eq!(geocoder.place_of(65.0, 179.9).unwrap().city, "East");
+    }
+
+    #[test]
+    fn test_bundled() {
+        let geocoder = Geocoder::bundled();
+        assert!(geocoder.is_bundled());
+        assert!(!geocoder.is_empty());
+
+        let place = geocoder.place_of(48.8584, 2.2945).unwrap();
+        assert_eq!(place.city, "Paris");
+        assert_eq!(place.region, "Île-de-France");
+        assert_eq!(place.country, "France");
+
+        let place = geocoder.place_of(1.28, 103.85).unwrap();
+        assert_eq!(place.city, "Singapore");
+        assert_eq!(place.region, "Singapore");
+
+        assert!(geocoder.place_of(0.0, -30.0).is_none());
+    }
+}
@@ -6,24 +6,67 @@ extern crate notify;
 extern crate regex;
 extern crate rusqlite;
 extern crate rustc_serialize;
+extern crate sha1;
 extern crate sha2;
 extern crate tiny_http;
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fs::create_dir;
+use std::io;
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex, RwLock};
 
 use crate::db::DataStore;
 use crate::file::GalleryScanner;
 
+mod album;
+mod api;
+mod auth;
 mod context;
 mod db;
+mod exif;
+mod favourites;
 mod file;
+mod format;
 mod gallery;
+mod geocode;
+mod manage;
+mod map;
+mod markdown;
+mod metadata;
+mod places;
+mod range;
+mod raw;
+mod resize;
+mod resumable;
+mod search;
+mod smart;
+mod sort;
+mod stack;
+mod timeline;
+mod trash;
+mod upload;
+mod video;
 mod web;
+mod xmp;
 
 fn main() {
+    // Prints the hash of a password read from stdin, for HOSTIMG_USERS
+    if env::args().nth(1).as_deref() == Some("--hash-password") {
+        let mut password = String::new();
+        if let Err(e) = io::stdin().read_line(&mut password) {
+            println!("Failed to read password: {:?}", e);
+            return;
+        }
+        match auth::PasswordHash::new(password.trim_end_matches(|c| c == '\r' || c == '\n')) {
+            Ok(x) => println!("{}", x),
+            Err(e) => println!("Failed to hash password: {:?}", e),
+        }
+        return;
+    }
+
     let file_dir = match env::home_dir() {
         Some(mut dir) => {
             dir.push(".hostimg");
@@ -63,6 +106,80 @@ fn main() {
         create_dir(&preview_dir).unwrap();
     }
 
+    let mut upload_dir = file_dir.clone();
+    upload_dir.push("uploads");
+
+    if !upload_dir.exists() {
+        create_dir(&upload_dir).unwrap();
+    }
+
+    let mut trash_dir = file_dir.clone();
+    trash_dir.push("trash");
+
+    if !trash_dir.exists() {
+        create_dir(&trash_dir).unwrap();
+    }
+
+    let mut resize_dir = file_dir.clone();
+    resize_dir.push("resized");
+
+    if !resize_dir.exists() {
+        create_dir(&resize_dir).unwrap();
+    }
+
+    let resize_sizes = env::var("HOSTIMG_RESIZE_SIZES")
+        .map(|x| resize::parse_sizes(&x))
+        .unwrap_or_else(|_| resize::DEFAULT_RESIZE_SIZES.to_vec());
+
+    let image_formats = format::available_formats();
+    println!("Derivatives can be encoded as {:?}", image_formats);
+
+    let video_support = video::ffmpeg_available();
+    if !video_support {
+        println!("ffmpeg not found, videos won't be indexed");
+    }
+
+    let trash_retention_days = env::var("HOSTIMG_TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(trash::DEFAULT_TRASH_RETENTION_DAYS);
+
+    let stack_window = env::var("HOSTIMG_STACK_WINDOW")
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .unwrap_or(stack::DEFAULT_STACK_WINDOW);
+
+    let tile_url =
+        env::var("HOSTIMG_TILE_URL").unwrap_or_else(|_| map::DEFAULT_TILE_URL.to_string());
+    let tile_attribution = env::var("HOSTIMG_TILE_ATTRIBUTION")
+        .unwrap_or_else(|_| map::DEFAULT_TILE_ATTRIBUTION.to_string());
+
+    let geonames_dir = env::var("HOSTIMG_GEONAMES_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| file_dir.join("geonames"));
+    let geocoder = match geocode::Geocoder::load(&geonames_dir) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Failed to load places from {:?}: {:?}", geonames_dir, e);
+            geocode::Geocoder::bundled()
+        }
+    };
+    if geocoder.is_bundled() {
+        println!(
+            "No GeoNames data found in {:?}, only capitals and large cities are named. See HOSTIMG_GEONAMES_DIR for more detail.",
+            geonames_dir
+        );
+    } else {
+        println!("Loaded {} places", geocoder.len());
+    }
+
+    let users = env::var("HOSTIMG_USERS")
+        .map(|x| auth::parse_users(&x))
+        .unwrap_or_default();
+    if users.is_empty() {
+        println!("No users configured, the gallery is public and read only");
+    }
+
     let gallery_dir = match env::args().nth(1) {
         Some(x) => PathBuf::from(x),
         None => {
@@ -71,6 +188,8 @@ fn main() {
         }
     };
 
+    let (indexing_queue, indexing_receiver) = channel();
+
     let context = context::ServerContext {
         port: 1080,
         server_threads: 4,
@@ -78,13 +197,31 @@ fn main() {
         gallery_dir: gallery_dir,
         thumb_dir: thumb_dir,
         preview_dir: preview_dir,
+        upload_dir: upload_dir,
+        trash_dir: trash_dir,
+        resize_dir,
+        resize_sizes: Arc::new(resize_sizes),
+        image_formats: Arc::new(image_formats),
+        video_support,
+        trash_retention_days,
+        stack_window,
+        tile_url,
+        tile_attribution,
 
         root_gallery: Arc::new(RwLock::new(None)),
+        timeline: Arc::new(RwLock::new(timeline::Timeline::new())),
 
         datastore: store,
+        geocoder: Arc::new(geocoder),
+
+        indexing_queue,
+
+        users: Arc::new(users),
+        verified_logins: Arc::new(Mutex::new(BTreeSet::new())),
+        suppressed_paths: Arc::new(Mutex::new(BTreeMap::new())),
     };
 
-    let mut scanner = GalleryScanner::new(context.clone());
+    let mut scanner = GalleryScanner::new(context.clone(), indexing_receiver);
 
     if let Err(e) = scanner.scan() {
         println!("Scanning of file system failed: {:?}", e);
@@ -92,6 +229,9 @@ fn main() {
     }
 
     scanner.process_images();
+    metadata::start_metadata_backfill(context.clone());
+    resumable::start_upload_reaper(context.clone());
+    trash::start_trash_reaper(context.clone());
     println!("Running");
 
     match web::WebServer::new(context.clone()) {
@@ -102,6 +242,68 @@ fn main() {
             if let Err(e) = server.register_action(Box::new(gallery::ImageAction::new())) {
                 println!("Failed to register ImageAction: {:?}", e);
             }
+            if let Err(e) = server.register_action(Box::new(resize::ResizeAction::new())) {
+                println!("Failed to register ResizeAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(api::ApiGalleryAction::new())) {
+                println!("Failed to register ApiGalleryAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(api::ApiImageAction::new())) {
+                println!("Failed to register ApiImageAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(api::ApiSearchAction::new())) {
+                println!("Failed to register ApiSearchAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(search::SearchAction::new())) {
+                println!("Failed to register SearchAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(timeline::TimelineAction::new())) {
+                println!("Failed to register TimelineAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(api::ApiTimelineAction::new())) {
+                println!("Failed to register ApiTimelineAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(places::PlacesAction::new())) {
+                println!("Failed to register PlacesAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(api::ApiPlacesAction::new())) {
+                println!("Failed to register ApiPlacesAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(map::MapAction::new())) {
+                println!("Failed to register MapAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(api::ApiMapAction::new())) {
+                println!("Failed to register ApiMapAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(upload::UploadAction::new())) {
+                println!("Failed to register UploadAction: {:?}", e);
+            }
+            if let Err(e) =
+                server.register_action(Box::new(resumable::ResumableUploadAction::new()))
+            {
+                println!("Failed to register ResumableUploadAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(manage::ImageManageAction::new())) {
+                println!("Failed to register ImageManageAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(trash::TrashAction::new())) {
+                println!("Failed to register TrashAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(favourites::FavouritesAction::new())) {
+                println!("Failed to register FavouritesAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(album::AlbumAction::new())) {
+                println!("Failed to register AlbumAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(album::ApiAlbumAction::new())) {
+                println!("Failed to register ApiAlbumAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(smart::SmartAlbumAction::new())) {
+                println!("Failed to register SmartAlbumAction: {:?}", e);
+            }
+            if let Err(e) = server.register_action(Box::new(smart::ApiSmartAlbumAction::new())) {
+                println!("Failed to register ApiSmartAlbumAction: {:?}", e);
+            }
 
             server.run_webserver(false);
         }
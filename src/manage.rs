@@ -0,0 +1,494 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Result};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::{Method, Request, Response, StatusCode};
+
+use crate::api::find_image_by_hash;
+use crate::auth::{current_user, forbidden_response};
+use crate::context::{ContextError, ServerContext};
+use crate::db::{DataStoreError, ImageInfo, TrashEntry};
+use crate::file::{is_media, GalleryModification};
+use crate::gallery::{image_relative_path, image_to_json};
+use crate::metadata::{index_metadata, meta_to_json};
+use crate::upload::{gallery_directory, target_file};
+use crate::web::{json_response, not_found_response, query_params, Action, WebServer};
+use crate::xmp::{own_sidecar, write_sidecar, XmpData, LABELS};
+
+#[derive(Debug)]
+pub enum ManageError {
+    BadRequest(&'static str),
+    Conflict(&'static str),
+    Io(io::Error),
+    Context(ContextError),
+    DataStore(DataStoreError),
+}
+
+impl From<io::Error> for ManageError {
+    fn from(other: io::Error) -> Self {
+        ManageError::Io(other)
+    }
+}
+
+impl From<ContextError> for ManageError {
+    fn from(other: ContextError) -> Self {
+        ManageError::Context(other)
+    }
+}
+
+impl From<DataStoreError> for ManageError {
+    fn from(other: DataStoreError) -> Self {
+        ManageError::DataStore(other)
+    }
+}
+
+/// Answers a request that changed the library with the result as JSON, or
+/// with the status matching the error.
+pub fn manage_response(
+    request: Request,
+    result: std::result::Result<Json, ManageError>,
+) -> Result<()> {
+    match result {
+        Ok(json) => json_response(request, &json),
+        Err(e) => {
+            let status = match e {
+                ManageError::BadRequest(_) => 400,
+                ManageError::Conflict(_) => 409,
+                _ => 500,
+            };
+            let _ = request.respond(Response::empty(StatusCode(status)));
+            Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+        }
+    }
+}
+
+/// Returns the gallery of an image, relative to the gallery directory.
+pub fn image_gallery_path(context: &ServerContext, image: &ImageInfo) -> PathBuf {
+    image_relative_path(context, image)
+        .parent()
+        .map(|x| x.to_path_buf())
+        .unwrap_or_default()
+}
+
+/// Finds the image a request refers to. Several files can share the same
+/// content, so an optional `path` picks a specific copy.
+fn find_requested_image(
+    context: &ServerContext,
+    hash: &str,
+    path: Option<&String>,
+) -> Option<Arc<ImageInfo>> {
+    let path = match path {
+        Some(x) => PathBuf::from(x),
+        None => return find_image_by_hash(context, hash),
+    };
+
+    let root_gallery = context.get_root_gallery().ok()?;
+    let parent = path.parent().map(|x| x.to_path_buf()).unwrap_or_default();
+    let name = context.gallery_dir.join(&path);
+
+    root_gallery
+        .find_image(&parent, name.to_str()?)
+        .filter(|x| x.hash == hash)
+}
+
+/// Moves a file, copying it if the target is on a different file system.
+/// Like `rename_no_clobber` this fails with `AlreadyExists` rather than
+/// replacing the target. A copy that can't be completed is removed again,
+/// so that the file never ends up in both places.
+pub fn move_file(source: &Path, target: &Path) -> Result<()> {
+    match rename_no_clobber(source, target) {
+        Err(ref e) if e.kind() != io::ErrorKind::AlreadyExists => {}
+        result => return result,
+    }
+
+    let mut output = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)?;
+    let result = fs::File::open(source)
+        .and_then(|mut input| io::copy(&mut input, &mut output))
+        .and_then(|_| output.sync_all())
+        .and_then(|_| fs::remove_file(source));
+    if result.is_err() {
+        let _ = fs::remove_file(target);
+    }
+    result
+}
+
+/// Renames a file, failing with `AlreadyExists` rather than replacing the
+/// target. The file is linked under the new name before the old one is
+/// removed, which fails atomically if the name is taken. File systems
+/// without hard links get the name reserved with an empty file instead.
+pub fn rename_no_clobber(source: &Path, target: &Path) -> Result<()> {
+    match fs::hard_link(source, target) {
+        Ok(()) => {
+            return fs::remove_file(source).map_err(|e| {
+                let _ = fs::remove_file(target);
+                e
+            })
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Err(e),
+        Err(_) => {}
+    }
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(target)?;
+    fs::rename(source, target).map_err(|e| {
+        let _ = fs::remove_file(target);
+        e
+    })
+}
+
+/// Moves the darktable sidecar of a file along with it, if there is one.
+/// It belongs to that file alone, so it mustn't stay behind to be picked up
+/// by the next file given the same name. Failing to move it is only logged.
+pub fn move_sidecar(context: &ServerContext, source: &Path, target: &Path) {
+    let sidecar = own_sidecar(source);
+    if !sidecar.is_file() {
+        return;
+    }
+
+    let target_sidecar = own_sidecar(target);
+    match move_file(&sidecar, &target_sidecar) {
+        Ok(()) => {
+            context.suppress_watcher(&sidecar);
+            context.suppress_watcher(&target_sidecar);
+        }
+        Err(e) => println!("Failed to move sidecar {:?}: {:?}", sidecar, e),
+    }
+}
+
+/// Moves a file on disk and updates the datastore and the gallery tree to
+/// match, without having the file system watcher process the change again.
+pub fn relocate_image(
+    context: &ServerContext,
+    image: Arc<ImageInfo>,
+    target: &Path,
+) -> std::result::Result<Arc<ImageInfo>, ManageError> {
+    let new_name = target
+        .to_str()
+        .ok_or(ManageError::BadRequest("Invalid file name"))?
+        .to_string();
+
+    let source = PathBuf::from(&image.name);
+
+    // darktable's sidecars belong to a single file and move along with it,
+    // so they mustn't replace one that's already there
+    let sidecar = own_sidecar(&source);
+    let target_sidecar = own_sidecar(target);
+    if sidecar.is_file() && target_sidecar.exists() {
+        return Err(ManageError::Conflict("A sidecar with that name exists"));
+    }
+
+    rename_no_clobber(&source, target).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => ManageError::Conflict("A file with that name exists"),
+        _ => ManageError::Io(e),
+    })?;
+
+    // Watcher events are debounced, so suppressing them after the fact is
+    // soon enough.
+    context.suppress_watcher(&source);
+    context.suppress_watcher(target);
+
+    context.datastore.rename_image(image.id, new_name.clone())?;
+
+    let old_parent = image_gallery_path(context, &image);
+
+    let mut new_info = (*image).clone();
+    new_info.name = new_name;
+    let new_info = Arc::new(new_info);
+
+    // The image has moved already, so a sidecar that turned up in the
+    // meantime is left alone rather than failing the whole move
+    move_sidecar(context, &source, target);
+
+    let search_path = image_relative_path(context, &new_info);
+    context
+        .datastore
+        .set_search_path(image.id, search_path.to_string_lossy().into_owned())?;
+    let new_parent = image_gallery_path(context, &new_info);
+
+    context.modify_root_gallery(&old_parent, GalleryModification::Remove(image))?;
+    context.modify_root_gallery(&new_parent, GalleryModification::Add(new_info.clone()))?;
+
+    Ok(new_info)
+}
+
+/// Moves an image into the trash folder and removes it from the library.
+/// It stays restorable until the trash retention period has passed.
+fn delete_image(
+    context: &ServerContext,
+    image: Arc<ImageInfo>,
+    user: String,
+) -> std::result::Result<Json, ManageError> {
+    let source = PathBuf::from(&image.name);
+    let file_name = source
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or(ManageError::BadRequest("Invalid file name"))?;
+
+    // Images with the same name deleted at the same time get a counter
+    let deleted_at = UTC::now().timestamp();
+    let target = target_file(&context.trash_dir, &format!("{}_{}", deleted_at, file_name))
+        .ok_or(ManageError::BadRequest("Invalid file name"))?;
+    let trash_file = target
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or(ManageError::BadRequest("Invalid file name"))?
+        .to_string();
+
+    // Each step is undone if a later one fails, so that the image is either
+    // in the library or in the trash
+    let parent = image_gallery_path(context, &image);
+    context.modify_root_gallery(&parent, GalleryModification::Remove(image.clone()))?;
+    let restore_gallery = || {
+        let _ = context.modify_root_gallery(&parent, GalleryModification::Add(image.clone()));
+    };
+
+    // The trash can be on a different file system than the gallery
+    if let Err(e) = move_file(&source, &target) {
+        restore_gallery();
+        return Err(match e.kind() {
+            io::ErrorKind::AlreadyExists => ManageError::Conflict("File exists in trash"),
+            _ => e.into(),
+        });
+    }
+    context.suppress_watcher(&source);
+    move_sidecar(context, &source, &target);
+
+    let trash_id = match context.datastore.trash_image(TrashEntry {
+        id: 0,
+        image: (*image).clone(),
+        trash_file,
+        deleted_at,
+        deleted_by: user,
+    }) {
+        Ok(x) => x,
+        Err(e) => {
+            if move_file(&target, &source).is_err() {
+                eprintln!("Failed to move {:?} back from trash", source);
+            }
+            move_sidecar(context, &target, &source);
+            restore_gallery();
+            return Err(e.into());
+        }
+    };
+
+    println!("Moved {:?} to trash", source);
+
+    let mut result_dict = BTreeMap::new();
+    result_dict.insert(
+        "deleted".to_string(),
+        image_relative_path(context, &image)
+            .to_str()
+            .unwrap_or("")
+            .to_json(),
+    );
+    result_dict.insert("trash_id".to_string(), trash_id.to_json());
+    Ok(Json::Object(result_dict))
+}
+
+fn move_image(
+    context: &ServerContext,
+    image: Arc<ImageInfo>,
+    gallery: &str,
+) -> std::result::Result<Json, ManageError> {
+    let dir =
+        gallery_directory(context, gallery).ok_or(ManageError::BadRequest("Unknown gallery"))?;
+    if Path::new(&image.name).parent() == Some(dir.as_path()) {
+        return Err(ManageError::Conflict("Image is in that gallery already"));
+    }
+
+    let file_name = Path::new(&image.name)
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or(ManageError::BadRequest("Invalid file name"))?;
+    let target =
+        target_file(&dir, file_name).ok_or(ManageError::BadRequest("Invalid file name"))?;
+
+    let new_info = relocate_image(context, image, &target)?;
+    Ok(image_to_json(context, &new_info))
+}
+
+fn extension(file: &Path) -> Option<String> {
+    file.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.to_ascii_lowercase())
+}
+
+/// Gives an image a new file name in the same gallery. The extension has to
+/// stay the same, since it decides how the file is read.
+fn rename_image(
+    context: &ServerContext,
+    image: Arc<ImageInfo>,
+    name: &str,
+) -> std::result::Result<Json, ManageError> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(|c| c == '/' || c == '\\')
+        || !is_media(Path::new(name))
+    {
+        return Err(ManageError::BadRequest("Invalid file name"));
+    }
+    if extension(Path::new(name)) != extension(Path::new(&image.name)) {
+        return Err(ManageError::BadRequest(
+            "The file extension can't be changed",
+        ));
+    }
+
+    let target = Path::new(&image.name).with_file_name(name);
+    let new_info = relocate_image(context, image, &target)?;
+    Ok(image_to_json(context, &new_info))
+}
+
+/// Parses a comma separated list of tags, dropping duplicates.
+fn parse_tags(value: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in value.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        if !tags.iter().any(|x| x == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+
+    tags
+}
+
+/// Changes the caption, tags, rating or label of an image, whichever are
+/// given, and writes them to its sidecar for desktop tools to pick up.
+fn edit_metadata(
+    context: &ServerContext,
+    image: Arc<ImageInfo>,
+    params: &BTreeMap<String, String>,
+) -> std::result::Result<Json, ManageError> {
+    let mut meta = match context.datastore.find_image_meta(image.id)? {
+        Some(x) => x,
+        None => index_metadata(context, &image)?,
+    };
+
+    if let Some(caption) = params.get("caption") {
+        meta.caption = Some(caption.trim().to_string()).filter(|x| !x.is_empty());
+    }
+    if let Some(tags) = params.get("tags") {
+        meta.tags = parse_tags(tags);
+    }
+    if let Some(rating) = params.get("rating") {
+        meta.rating = match rating.parse::<u32>() {
+            Ok(0) => None,
+            Ok(x) if x <= 5 => Some(x),
+            _ => return Err(ManageError::BadRequest("Rating must be from 0 to 5")),
+        };
+    }
+    if let Some(label) = params.get("label") {
+        meta.label = match label.trim() {
+            "" => None,
+            x if LABELS.contains(&x) => Some(x.to_string()),
+            _ => return Err(ManageError::BadRequest("Unknown label")),
+        };
+    }
+
+    let sidecar = write_sidecar(Path::new(&image.name), &XmpData::from_meta(&meta))?;
+    context.suppress_watcher(&sidecar);
+
+    let path = image_relative_path(context, &image)
+        .to_string_lossy()
+        .into_owned();
+    context
+        .datastore
+        .save_image_meta(image.id, path, meta.clone())?;
+
+    let mut new_info = (*image).clone();
+    new_info.set_meta(&meta);
+    let parent = image_gallery_path(context, &new_info);
+    context.modify_root_gallery(&parent, GalleryModification::Update(Arc::new(new_info)))?;
+
+    Ok(meta_to_json(&meta))
+}
+
+fn set_favourite(
+    context: &ServerContext,
+    image: Arc<ImageInfo>,
+    user: String,
+    favourite: bool,
+) -> std::result::Result<Json, ManageError> {
+    context
+        .datastore
+        .set_favourite(user, image.hash.clone(), favourite)?;
+
+    let mut result_dict = BTreeMap::new();
+    result_dict.insert("favourite".to_string(), favourite.to_json());
+    Ok(Json::Object(result_dict))
+}
+
+pub struct ImageManageAction {}
+
+impl ImageManageAction {
+    pub fn new() -> ImageManageAction {
+        ImageManageAction {}
+    }
+}
+
+impl Action for ImageManageAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/api/v1/image/([0-9A-F]+)/(delete|move|rename|meta|favourite)$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        if *request.method() != Method::Post {
+            let _ = request.respond(Response::empty(StatusCode(405)));
+            return Ok(());
+        }
+
+        let user = match current_user(&request, &context) {
+            Some(x) => x,
+            None => return forbidden_response(request, &context),
+        };
+
+        let hash = caps.get(1).map(|x| x.as_str()).unwrap_or("");
+        let operation = caps.get(2).map(|x| x.as_str()).unwrap_or("");
+        let params = query_params(request.url());
+
+        let image = match find_requested_image(&context, hash, params.get("path")) {
+            Some(x) => x,
+            None => return not_found_response(request, "Image not found"),
+        };
+
+        let result = match operation {
+            "delete" => delete_image(&context, image, user),
+            "move" => match params.get("gallery") {
+                Some(gallery) => move_image(&context, image, gallery),
+                None => Err(ManageError::BadRequest("No gallery specified")),
+            },
+            "rename" => match params.get("name") {
+                Some(name) => rename_image(&context, image, name),
+                None => Err(ManageError::BadRequest("No name specified")),
+            },
+            "meta" => edit_metadata(&context, image, &params),
+            "favourite" => match params.get("value").map(|x| x.as_str()) {
+                Some("true") => set_favourite(&context, image, user, true),
+                Some("false") => set_favourite(&context, image, user, false),
+                _ => Err(ManageError::BadRequest("Value must be true or false")),
+            },
+            _ => Err(ManageError::BadRequest("Unknown operation")),
+        };
+
+        manage_response(request, result)
+    }
+}
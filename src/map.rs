@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+use std::f64::consts::PI;
+use std::io::Result;
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::Request;
+
+use crate::context::ServerContext;
+use crate::search::SearchHit;
+use crate::web::{error_response, html_response, query_params, query_string, Action, WebServer};
+
+/// Tiles used unless overridden with `HOSTIMG_TILE_URL`.
+pub const DEFAULT_TILE_URL: &str = "https://tile.openstreetmap.org/{z}/{x}/{y}.png";
+pub const DEFAULT_TILE_ATTRIBUTION: &str =
+    "&copy; <a href=\"https://www.openstreetmap.org/copyright\">OpenStreetMap</a> contributors";
+
+pub const MAX_ZOOM: u32 = 19;
+
+/// Markers closer to each other than this many pixels are merged.
+const CLUSTER_SIZE: f64 = 80.0;
+
+/// The web mercator projection ends here, as its square would grow endlessly
+/// towards the poles.
+const MAX_LATITUDE: f64 = 85.0511;
+
+/// A group of images close enough to share a marker at some zoom level.
+pub struct Cluster<'a> {
+    pub count: usize,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// West, south, east and north edge of the area covered by the images.
+    pub bounds: (f64, f64, f64, f64),
+    /// The image shown on the marker.
+    pub hit: &'a SearchHit,
+}
+
+/// Projects a position to web mercator pixel coordinates at `zoom`.
+fn project(latitude: f64, longitude: f64, zoom: u32) -> (f64, f64) {
+    let size = 256.0 * (1u64 << zoom) as f64;
+    let latitude = latitude.max(-MAX_LATITUDE).min(MAX_LATITUDE).to_radians();
+
+    let x = (longitude + 180.0) / 360.0 * size;
+    let y = (1.0 - (latitude.tan() + 1.0 / latitude.cos()).ln() / PI) / 2.0 * size;
+    (x, y)
+}
+
+fn location(hit: &SearchHit) -> Option<(f64, f64)> {
+    Some((hit.meta.latitude?, hit.meta.longitude?))
+}
+
+/// Merges the located images into clusters, using a grid of cells the size
+/// of a marker at `zoom`.
+pub fn cluster_hits(hits: &[SearchHit], zoom: u32) -> Vec<Cluster<'_>> {
+    let mut cells: BTreeMap<(i64, i64), Cluster> = BTreeMap::new();
+
+    for hit in hits {
+        let (latitude, longitude) = match location(hit) {
+            Some(x) => x,
+            None => continue,
+        };
+
+        let (x, y) = project(latitude, longitude, zoom);
+        let cell = ((x / CLUSTER_SIZE) as i64, (y / CLUSTER_SIZE) as i64);
+
+        let cluster = cells.entry(cell).or_insert(Cluster {
+            count: 0,
+            latitude: 0.0,
+            longitude: 0.0,
+            bounds: (longitude, latitude, longitude, latitude),
+            hit,
+        });
+
+        // Running sums, turned into the center below
+        cluster.count += 1;
+        cluster.latitude += latitude;
+        cluster.longitude += longitude;
+
+        let (west, south, east, north) = cluster.bounds;
+        cluster.bounds = (
+            west.min(longitude),
+            south.min(latitude),
+            east.max(longitude),
+            north.max(latitude),
+        );
+    }
+
+    cells
+        .into_iter()
+        .map(|(_, mut cluster)| {
+            cluster.latitude /= cluster.count as f64;
+            cluster.longitude /= cluster.count as f64;
+            cluster
+        })
+        .collect()
+}
+
+/// Returns the area covering every located image, if there are any.
+pub fn hit_bounds(hits: &[SearchHit]) -> Option<(f64, f64, f64, f64)> {
+    hits.iter()
+        .filter_map(location)
+        .fold(None, |bounds, (lat, lon)| {
+            Some(match bounds {
+                Some((west, south, east, north)) => {
+                    (lon.min(west), lat.min(south), lon.max(east), lat.max(north))
+                }
+                None => (lon, lat, lon, lat),
+            })
+        })
+}
+
+pub fn bounds_to_json(bounds: (f64, f64, f64, f64)) -> Json {
+    vec![bounds.0, bounds.1, bounds.2, bounds.3].to_json()
+}
+
+/// Restricts a search to the whole world unless the request names an area,
+/// which leaves out images without a location.
+pub fn located_params(params: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut params = params.clone();
+    if params.get("bbox").map_or(true, |x| x.is_empty()) {
+        params.insert("bbox".to_string(), "-180,-90,180,90".to_string());
+    }
+
+    params
+}
+
+pub struct MapAction {}
+
+impl MapAction {
+    pub fn new() -> MapAction {
+        MapAction {}
+    }
+}
+
+impl Action for MapAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/map/?$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/map.html").to_string();
+        server.register_template("map", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        _: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        // Search filters are passed on to the marker requests, the visible
+        // area is chosen by the map itself
+        let mut params = query_params(request.url());
+        params.remove("bbox");
+        params.remove("zoom");
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("tile_url".to_string(), context.tile_url.to_json());
+        result_dict.insert(
+            "tile_attribution".to_string(),
+            context.tile_attribution.to_json(),
+        );
+        result_dict.insert("max_zoom".to_string(), MAX_ZOOM.to_json());
+        result_dict.insert("query".to_string(), query_string(&params).to_json());
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("map", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+}
@@ -0,0 +1,307 @@
+use regex::{Captures, Regex};
+
+/// How deeply quotes are nested before the rest is shown as plain text.
+const MAX_QUOTE_DEPTH: usize = 8;
+
+/// Renders the Markdown of a gallery description to html. Only a common
+/// subset is supported: headings, paragraphs, lists, quotes, code, rules,
+/// emphasis and links. Everything is escaped before it's marked up, so html
+/// in the text shows up as written instead of ending up in the page, and
+/// links are limited to web and mail addresses.
+pub fn render(text: &str) -> String {
+    render_blocks(text, 0)
+}
+
+/// Renders the blocks of text within `depth` quotes.
+fn render_blocks(text: &str, depth: usize) -> String {
+    let mut html = String::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut list: Option<&str> = None;
+    let mut quote: Vec<&str> = Vec::new();
+    let mut code: Option<Vec<&str>> = None;
+
+    let heading = Regex::new(r"^(#{1,6})\s+(.*?)\s*#*\s*$").unwrap();
+    let bullet = Regex::new(r"^\s*[-*+]\s+(.*)$").unwrap();
+    let number = Regex::new(r"^\s*[0-9]+[.)]\s+(.*)$").unwrap();
+    let rule = Regex::new(r"^\s*(?:(?:-\s*){3,}|(?:\*\s*){3,}|(?:_\s*){3,})$").unwrap();
+
+    for line in text.lines() {
+        if let Some(mut lines) = code.take() {
+            if line.trim_start().starts_with("```") {
+                html.push_str(&format!(
+                    "<pre><code>{}</code></pre>\n",
+                    escape(&lines.join("\n"))
+                ));
+            } else {
+                lines.push(line);
+                code = Some(lines);
+            }
+            continue;
+        }
+
+        let is_fence = line.trim_start().starts_with("```");
+        let is_quote = line.trim_start().starts_with('>');
+        let is_rule = rule.is_match(line);
+        let item = bullet
+            .captures(line)
+            .map(|x| ("ul", x.get(1).map_or("", |x| x.as_str())))
+            .or_else(|| {
+                number
+                    .captures(line)
+                    .map(|x| ("ol", x.get(1).map_or("", |x| x.as_str())))
+            })
+            .filter(|_| !is_rule);
+        let is_text = !line.trim().is_empty()
+            && !is_fence
+            && !is_quote
+            && !is_rule
+            && item.is_none()
+            && !heading.is_match(line);
+
+        // Anything but another line of the same kind ends the current block
+        if !paragraph.is_empty() && !is_text {
+            html.push_str(&format!("<p>{}</p>\n", inline(&paragraph.join("\n"))));
+            paragraph.clear();
+        }
+        if let Some(tag) = list {
+            if item.map(|(x, _)| x) != Some(tag) && !line.trim().is_empty() {
+                html.push_str(&format!("</{}>\n", tag));
+                list = None;
+            }
+        }
+        if !quote.is_empty() && !is_quote {
+            html.push_str(&blockquote(&quote, depth + 1));
+            quote.clear();
+        }
+
+        if is_fence {
+            code = Some(Vec::new());
+        } else if is_quote {
+            let rest = &line.trim_start()[1..];
+            quote.push(rest.strip_prefix(' ').unwrap_or(rest));
+        } else if let Some((tag, content)) = item {
+            if list.is_none() {
+                html.push_str(&format!("<{}>\n", tag));
+                list = Some(tag);
+            }
+            html.push_str(&format!("<li>{}</li>\n", inline(content)));
+        } else if let Some(caps) = heading.captures(line) {
+            let level = caps[1].len();
+            html.push_str(&format!("<h{0}>{1}</h{0}>\n", level, inline(&caps[2])));
+        } else if is_rule {
+            html.push_str("<hr>\n");
+        } else if is_text {
+            paragraph.push(line.trim());
+        }
+    }
+
+    if let Some(lines) = code {
+        html.push_str(&format!(
+            "<pre><code>{}</code></pre>\n",
+            escape(&lines.join("\n"))
+        ));
+    }
+    if !paragraph.is_empty() {
+        html.push_str(&format!("<p>{}</p>\n", inline(&paragraph.join("\n"))));
+    }
+    if let Some(tag) = list {
+        html.push_str(&format!("</{}>\n", tag));
+    }
+    if !quote.is_empty() {
+        html.push_str(&blockquote(&quote, depth + 1));
+    }
+
+    html
+}
+
+/// Renders a quote at the given level of nesting. The content of the
+/// innermost allowed level is taken literally, so that a long run of ">"
+/// can't nest without bounds.
+fn blockquote(lines: &[&str], depth: usize) -> String {
+    let text = lines.join("\n");
+    let content = if depth < MAX_QUOTE_DEPTH {
+        render_blocks(&text, depth)
+    } else {
+        format!("<p>{}</p>\n", escape(&text))
+    };
+
+    format!("<blockquote>{}</blockquote>\n", content)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Checks that a link leads to a web page or a mail address, rather than
+/// e.g. running a script. Relative links are fine.
+fn is_safe_url(url: &str) -> bool {
+    let url = url.to_lowercase();
+    match url.find(':') {
+        Some(colon) => {
+            let scheme = &url[..colon];
+            scheme.contains('/')
+                || scheme.contains('?')
+                || scheme.contains('#')
+                || ["http", "https", "mailto"].contains(&scheme)
+        }
+        None => true,
+    }
+}
+
+/// Marks up code spans, links and emphasis within a block of text.
+fn inline(text: &str) -> String {
+    let code = Regex::new(r"`([^`]+)`").unwrap();
+
+    // Code spans are taken as they are, so only the text around them is
+    // marked up
+    let mut html = String::new();
+    let mut last = 0;
+    for caps in code.captures_iter(text) {
+        let span = caps.get(0).unwrap();
+        html.push_str(&inline_text(&text[last..span.start()]));
+        html.push_str(&format!("<code>{}</code>", escape(&caps[1])));
+        last = span.end();
+    }
+    html.push_str(&inline_text(&text[last..]));
+
+    html.replace('\n', " ")
+}
+
+fn inline_text(text: &str) -> String {
+    let link = Regex::new(r"\[([^\]]+)\]\(([^)\s]+)\)").unwrap();
+    let strong = Regex::new(r"\*\*([^*]+)\*\*|__([^_]+)__").unwrap();
+    let emphasis = Regex::new(r"\*([^*]+)\*|\b_([^_]+)_\b").unwrap();
+
+    let text = escape(text);
+    let text = link.replace_all(&text, |caps: &Captures| {
+        if is_safe_url(&caps[2]) {
+            format!("<a href=\"{}\">{}</a>", &caps[2], &caps[1])
+        } else {
+            caps[1].to_string()
+        }
+    });
+    let text = strong.replace_all(&text, |caps: &Captures| {
+        let content = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .map_or("", |x| x.as_str());
+        format!("<strong>{}</strong>", content)
+    });
+    let text = emphasis.replace_all(&text, |caps: &Captures| {
+        let content = caps
+            .get(1)
+            .or_else(|| caps.get(2))
+            .map_or("", |x| x.as_str());
+        format!("<em>{}</em>", content)
+    });
+
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_blocks() {
+        assert_eq!(render("# Title"), "<h1>Title</h1>\n");
+        assert_eq!(render("## Sub ##"), "<h2>Sub</h2>\n");
+        assert_eq!(
+            render("Hello\nworld\n\nNext"),
+            "<p>Hello world</p>\n<p>Next</p>\n"
+        );
+        assert_eq!(
+            render("- a\n- b\n\n1. one\n2. two"),
+            "<ul>\n<li>a</li>\n<li>b</li>\n</ul>\n<ol>\n<li>one</li>\n<li>two</li>\n</ol>\n"
+        );
+        assert_eq!(
+            render("```\n<b>x</b>\n```"),
+            "<pre><code>&lt;b&gt;x&lt;/b&gt;</code></pre>\n"
+        );
+        assert_eq!(render("---"), "<hr>\n");
+        assert_eq!(render("* * *"), "<hr>\n");
+    }
+
+    #[test]
+    fn test_render_inline() {
+        assert_eq!(
+            render("**bold** and *em* and `a*b*`"),
+            "<p><strong>bold</strong> and <em>em</em> and <code>a*b*</code></p>\n"
+        );
+        assert_eq!(
+            render("[site](https://example.com/a?b=1&c=2)"),
+            "<p><a href=\"https://example.com/a?b=1&amp;c=2\">site</a></p>\n"
+        );
+        assert_eq!(
+            render("[up](../index.html)"),
+            "<p><a href=\"../index.html\">up</a></p>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_quotes() {
+        assert_eq!(
+            render("> quote\n> more\n\ntext"),
+            "<blockquote><p>quote more</p>\n</blockquote>\n<p>text</p>\n"
+        );
+        assert_eq!(
+            render(">> a"),
+            "<blockquote><blockquote><p>a</p>\n</blockquote>\n</blockquote>\n"
+        );
+
+        // Deeper quotes are cut off and the rest is shown as written
+        let html = render(&format!("{} deep", ">".repeat(20)));
+        assert_eq!(html.matches("<blockquote>").count(), MAX_QUOTE_DEPTH);
+        assert!(html.contains(&format!("<p>{} deep</p>", "&gt;".repeat(12))));
+
+        let html = render(&">".repeat(100_000));
+        assert_eq!(html.matches("<blockquote>").count(), MAX_QUOTE_DEPTH);
+    }
+
+    #[test]
+    fn test_render_escapes_html() {
+        assert_eq!(
+            render("<script>alert(1)</script>"),
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt;</p>\n"
+        );
+        assert_eq!(
+            render("# <img src=x onerror=alert(1)>"),
+            "<h1>&lt;img src=x onerror=alert(1)&gt;</h1>\n"
+        );
+
+        let html = render("[x](http://a\"onmouseover=\"alert(1))");
+        assert!(!html.contains("\"onmouseover"));
+    }
+
+    #[test]
+    fn test_render_unsafe_links() {
+        assert_eq!(render("[click](javascript:alert(1))"), "<p>click)</p>\n");
+        assert_eq!(render("[click](JavaScript:alert(1))"), "<p>click)</p>\n");
+        assert_eq!(
+            render("[click](data:text/html;base64,PHNjcmlwdD4=)"),
+            "<p>click</p>\n"
+        );
+    }
+
+    #[test]
+    fn test_is_safe_url() {
+        assert!(is_safe_url("http://example.com"));
+        assert!(is_safe_url("HTTPS://example.com"));
+        assert!(is_safe_url("mailto:someone@example.com"));
+        assert!(is_safe_url("/gallery/a:b"));
+        assert!(is_safe_url("page?time=12:00"));
+        assert!(is_safe_url("#section:2"));
+        assert!(is_safe_url("index.html"));
+
+        assert!(!is_safe_url("javascript:alert(1)"));
+        assert!(!is_safe_url("JaVaScRiPt:alert(1)"));
+        assert!(!is_safe_url("vbscript:msgbox(1)"));
+        assert!(!is_safe_url("data:text/html;base64,PHNjcmlwdD4="));
+        assert!(!is_safe_url("file:///etc/passwd"));
+    }
+}
@@ -0,0 +1,327 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use chrono::prelude::*;
+use regex::Regex;
+use rustc_serialize::json::{Json, ToJson};
+
+use crate::context::ServerContext;
+use crate::db::{DataStoreError, ImageInfo, ImageMeta};
+use crate::exif::{self, Exif, ExifValue, Ifd};
+use crate::file::GalleryModification;
+use crate::gallery::image_relative_path;
+use crate::raw::is_raw;
+use crate::video::{self, VideoProbe};
+use crate::xmp::{self, XmpData};
+
+/// Combines make and model into a camera name, leaving out the make if the
+/// model already starts with it (e.g. "Canon" and "Canon EOS 5D").
+fn camera_name(make: Option<String>, model: Option<String>) -> Option<String> {
+    match (make, model) {
+        (Some(make), Some(model)) => {
+            let brand = make.split_whitespace().next().unwrap_or("").to_lowercase();
+            if model.to_lowercase().starts_with(&brand) {
+                Some(model)
+            } else {
+                Some(format!("{} {}", make, model))
+            }
+        }
+        (make, model) => model.or(make),
+    }
+}
+
+/// Formats an exposure time the way cameras display it, e.g. "1/250".
+fn exposure_time(exif: &Exif) -> Option<String> {
+    let (n, d) = match exif.get(Ifd::Exif, exif::EXPOSURE_TIME)? {
+        ExifValue::Rational(x) => *x.first()?,
+        _ => return None,
+    };
+
+    if n == 0 || d == 0 {
+        None
+    } else if n >= d {
+        Some(format!("{}", n as f64 / d as f64))
+    } else {
+        Some(format!("1/{}", (d as f64 / n as f64).round()))
+    }
+}
+
+/// Converts a GPS coordinate stored as degrees, minutes and seconds, with
+/// the reference telling the hemisphere.
+fn gps_coordinate(exif: &Exif, reference: u16, value: u16, negative: &str) -> Option<f64> {
+    let degrees = exif.number_at(Ifd::Gps, value, 0)?;
+    let minutes = exif.number_at(Ifd::Gps, value, 1).unwrap_or(0.0);
+    let seconds = exif.number_at(Ifd::Gps, value, 2).unwrap_or(0.0);
+    let coordinate = degrees + minutes / 60.0 + seconds / 3600.0;
+
+    if exif.text(Ifd::Gps, reference).as_ref().map(|x| x.as_str()) == Some(negative) {
+        Some(-coordinate)
+    } else {
+        Some(coordinate)
+    }
+}
+
+fn parse_exif_date(value: Option<String>) -> Option<i64> {
+    NaiveDateTime::parse_from_str(&value?, "%Y:%m:%d %H:%M:%S")
+        .ok()
+        .map(|x| x.timestamp())
+}
+
+pub fn meta_to_json(meta: &ImageMeta) -> Json {
+    let mut meta_dict = BTreeMap::new();
+    meta_dict.insert("caption".to_string(), meta.caption.to_json());
+    meta_dict.insert("camera".to_string(), meta.camera.to_json());
+    meta_dict.insert("lens".to_string(), meta.lens.to_json());
+    meta_dict.insert(
+        "taken".to_string(),
+        meta.taken
+            .map(|x| {
+                NaiveDateTime::from_timestamp(x, 0)
+                    .format("%Y-%m-%dT%H:%M:%S")
+                    .to_string()
+            })
+            .to_json(),
+    );
+    meta_dict.insert("focal_length".to_string(), meta.focal_length.to_json());
+    meta_dict.insert("aperture".to_string(), meta.aperture.to_json());
+    meta_dict.insert("exposure".to_string(), meta.exposure.to_json());
+    meta_dict.insert("iso".to_string(), meta.iso.to_json());
+    meta_dict.insert("latitude".to_string(), meta.latitude.to_json());
+    meta_dict.insert("longitude".to_string(), meta.longitude.to_json());
+    meta_dict.insert(
+        "place".to_string(),
+        match meta.place {
+            Some(ref place) => {
+                let mut place_dict = BTreeMap::new();
+                place_dict.insert("country".to_string(), place.country.to_json());
+                place_dict.insert("region".to_string(), place.region.to_json());
+                place_dict.insert("city".to_string(), place.city.to_json());
+                Json::Object(place_dict)
+            }
+            None => Json::Null,
+        },
+    );
+    meta_dict.insert("duration".to_string(), meta.duration.to_json());
+    meta_dict.insert("rating".to_string(), meta.rating.to_json());
+    meta_dict.insert("label".to_string(), meta.label.to_json());
+    meta_dict.insert("tags".to_string(), meta.tags.to_json());
+    Json::Object(meta_dict)
+}
+
+fn modification_time(path: &Path) -> Option<i64> {
+    let modified = path.metadata().and_then(|x| x.modified()).ok()?;
+    let time = modified.duration_since(UNIX_EPOCH).ok()?;
+
+    // Converted to local time, to match the times recorded by cameras
+    let local = Local.timestamp(time.as_secs() as i64, 0);
+    Some(local.naive_local().timestamp())
+}
+
+/// Takes over what desktop tools keep in a sidecar, which overrides what's
+/// embedded in the file.
+fn apply_sidecar(meta: &mut ImageMeta, xmp: XmpData) {
+    if let Some(caption) = xmp.caption {
+        meta.caption = Some(caption).filter(|x| !x.is_empty());
+    }
+    if let Some(tags) = xmp.tags {
+        meta.tags = tags;
+    }
+    meta.rating = xmp.rating;
+    meta.label = xmp.label;
+}
+
+/// Reads the metadata stored in an image or video file and its sidecar.
+/// Files without any result in an empty set, which is still saved so they
+/// aren't read again.
+pub fn read_metadata(path: &Path) -> ImageMeta {
+    let mut meta = if video::is_video(path) {
+        match video::probe(path) {
+            Ok(probe) => video_metadata(&probe),
+            Err(_) => ImageMeta::default(),
+        }
+    } else {
+        let exif = if is_raw(path) {
+            Exif::from_tiff(path)
+        } else {
+            Exif::from_jpeg(path)
+        };
+        match exif {
+            Ok(Some(exif)) => exif_metadata(&exif),
+            _ => ImageMeta::default(),
+        }
+    };
+
+    if let Some(xmp) = xmp::read_sidecar(path) {
+        apply_sidecar(&mut meta, xmp);
+    }
+
+    if meta.taken.is_none() {
+        meta.taken = modification_time(path);
+    }
+
+    meta
+}
+
+fn exif_metadata(exif: &Exif) -> ImageMeta {
+    let tags = exif
+        .utf16_text(Ifd::Primary, exif::XP_KEYWORDS)
+        .map(|x| {
+            x.split(';')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ImageMeta {
+        caption: exif
+            .text(Ifd::Primary, exif::IMAGE_DESCRIPTION)
+            .or_else(|| exif.utf16_text(Ifd::Primary, exif::XP_COMMENT)),
+        camera: camera_name(
+            exif.text(Ifd::Primary, exif::MAKE),
+            exif.text(Ifd::Primary, exif::MODEL),
+        ),
+        lens: exif.text(Ifd::Exif, exif::LENS_MODEL),
+        taken: parse_exif_date(exif.text(Ifd::Exif, exif::DATE_TIME_ORIGINAL))
+            .or_else(|| parse_exif_date(exif.text(Ifd::Primary, exif::DATE_TIME))),
+        focal_length: exif.number(Ifd::Exif, exif::FOCAL_LENGTH),
+        aperture: exif.number(Ifd::Exif, exif::F_NUMBER),
+        exposure: exposure_time(exif),
+        iso: exif.number(Ifd::Exif, exif::ISO_SPEED).map(|x| x as u32),
+        latitude: gps_coordinate(exif, exif::GPS_LATITUDE_REF, exif::GPS_LATITUDE, "S")
+            .filter(|x| x.abs() <= 90.0),
+        longitude: gps_coordinate(exif, exif::GPS_LONGITUDE_REF, exif::GPS_LONGITUDE, "W")
+            .filter(|x| x.abs() <= 180.0),
+        place: None,
+        duration: None,
+        rating: None,
+        label: None,
+        tags,
+    }
+}
+
+/// Parses a creation time as recorded by phones. Apple devices keep the
+/// local time along with its offset, others only the time in UTC, which is
+/// converted to the local time of the server instead.
+fn parse_video_date(probe: &VideoProbe) -> Option<i64> {
+    if let Some(date) = probe.tags.get("com.apple.quicktime.creationdate") {
+        if let Ok(x) = DateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%z") {
+            return Some(x.naive_local().timestamp());
+        }
+    }
+
+    let date = DateTime::parse_from_rfc3339(probe.tags.get("creation_time")?).ok()?;
+    Some(date.with_timezone(&Local).naive_local().timestamp())
+}
+
+/// Parses a location in ISO 6709 notation, e.g. "+59.9139+010.7522/".
+fn parse_video_location(probe: &VideoProbe) -> Option<(f64, f64)> {
+    let location = probe
+        .tags
+        .get("com.apple.quicktime.location.iso6709")
+        .or_else(|| probe.tags.get("location"))?;
+
+    let caps = Regex::new(r"^([+-][0-9.]+)([+-][0-9.]+)")
+        .unwrap()
+        .captures(location)?;
+    let latitude = caps.get(1)?.as_str().parse::<f64>().ok()?;
+    let longitude = caps.get(2)?.as_str().parse::<f64>().ok()?;
+
+    if latitude.abs() <= 90.0 && longitude.abs() <= 180.0 {
+        Some((latitude, longitude))
+    } else {
+        None
+    }
+}
+
+fn video_metadata(probe: &VideoProbe) -> ImageMeta {
+    let location = parse_video_location(probe);
+
+    ImageMeta {
+        camera: camera_name(
+            probe.tags.get("com.apple.quicktime.make").cloned(),
+            probe.tags.get("com.apple.quicktime.model").cloned(),
+        ),
+        taken: parse_video_date(probe),
+        latitude: location.map(|x| x.0),
+        longitude: location.map(|x| x.1),
+        duration: probe.duration,
+        ..ImageMeta::default()
+    }
+}
+
+/// Reads the metadata of an indexed image, resolves where it was taken and
+/// adds it to the search index.
+pub fn index_metadata(
+    context: &ServerContext,
+    image: &ImageInfo,
+) -> Result<ImageMeta, DataStoreError> {
+    let mut meta = read_metadata(Path::new(&image.name));
+    if let (Some(latitude), Some(longitude)) = (meta.latitude, meta.longitude) {
+        meta.place = context.geocoder.place_of(latitude, longitude);
+    }
+
+    let path = image_relative_path(context, image)
+        .to_string_lossy()
+        .into_owned();
+
+    context
+        .datastore
+        .save_image_meta(image.id, path, meta.clone())?;
+
+    Ok(meta)
+}
+
+/// Reads the metadata of images indexed before it was collected, then
+/// resolves the places of located images that don't have one yet.
+pub fn start_metadata_backfill(context: ServerContext) {
+    thread::spawn(move || {
+        let images = match context.datastore.find_images_without_meta() {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Failed to find images without metadata: {:?}", e);
+                return;
+            }
+        };
+
+        for mut image in images {
+            match index_metadata(&context, &image) {
+                Ok(meta) => image.set_meta(&meta),
+                Err(e) => {
+                    eprintln!("Failed to read metadata of {}: {:?}", image.name, e);
+                    continue;
+                }
+            }
+
+            let parent = image_relative_path(&context, &image)
+                .parent()
+                .map(|x| x.to_path_buf())
+                .unwrap_or_default();
+            let op = GalleryModification::Update(Arc::new(image));
+            if let Err(e) = context.modify_root_gallery(&parent, op) {
+                eprintln!("Failed to update gallery: {:?}", e);
+            }
+        }
+
+        if context.geocoder.is_empty() {
+            return;
+        }
+
+        let images = match context.datastore.find_images_without_place() {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Failed to find images without place: {:?}", e);
+                return;
+            }
+        };
+
+        for image in images {
+            if let Err(e) = index_metadata(&context, &image) {
+                eprintln!("Failed to read metadata of {}: {:?}", image.name, e);
+            }
+        }
+    });
+}
@@ -0,0 +1,167 @@
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::Request;
+
+use crate::context::ServerContext;
+use crate::db::{DataStoreError, Place};
+use crate::gallery::{image_to_json, mark_favourite, user_favourites};
+use crate::search::{search_images, SearchHit};
+use crate::web::{error_response, html_response, url_decode, url_encode, Action, WebServer};
+
+/// The search parameters for each level of the places hierarchy.
+const LEVELS: &[&str] = &["country", "region", "city"];
+
+/// Reads the country, region and city parts of a places url, as far as
+/// they are given.
+pub fn parts_from_captures(caps: &Captures) -> Vec<String> {
+    (1..=LEVELS.len())
+        .filter_map(|i| caps.get(i))
+        .map(|x| url_decode(x.as_str()))
+        .collect()
+}
+
+/// The places url below /places, e.g. "Norway/Oslo".
+pub fn place_path(parts: &[String]) -> String {
+    parts
+        .iter()
+        .map(|x| url_encode(x))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn place_part(place: &Place, level: usize) -> &str {
+    match level {
+        0 => &place.country,
+        1 => &place.region,
+        _ => &place.city,
+    }
+}
+
+/// Finds the images taken within a country, region or city.
+pub fn place_hits(
+    context: &ServerContext,
+    parts: &[String],
+) -> std::result::Result<Vec<SearchHit>, DataStoreError> {
+    let params = LEVELS
+        .iter()
+        .zip(parts)
+        .map(|(level, part)| (level.to_string(), part.clone()))
+        .collect();
+
+    Ok(search_images(context, &params)?
+        .into_iter()
+        .filter(|x| x.meta.place.is_some())
+        .collect())
+}
+
+/// Describes the places one level below `parts` for the html views and the
+/// api, the ones with the most images first.
+pub fn buckets_to_json(hits: &[SearchHit], parts: &[String]) -> Json {
+    let level = parts.len();
+    if level >= LEVELS.len() {
+        return Json::Array(Vec::new());
+    }
+
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for place in hits.iter().filter_map(|x| x.meta.place.as_ref()) {
+        *counts.entry(place_part(place, level)).or_insert(0) += 1;
+    }
+
+    let mut buckets: Vec<(&str, usize)> = counts.into_iter().collect();
+    buckets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+
+    Json::Array(
+        buckets
+            .into_iter()
+            .map(|(name, count)| {
+                let mut child = parts.to_vec();
+                child.push(name.to_string());
+
+                let mut bucket_dict = BTreeMap::new();
+                bucket_dict.insert("path".to_string(), place_path(&child).to_json());
+                bucket_dict.insert("label".to_string(), name.to_json());
+                bucket_dict.insert("count".to_string(), count.to_json());
+                Json::Object(bucket_dict)
+            })
+            .collect(),
+    )
+}
+
+pub fn place_label(parts: &[String]) -> String {
+    match parts.last() {
+        Some(x) => x.clone(),
+        None => "All places".to_string(),
+    }
+}
+
+pub struct PlacesAction {}
+
+impl PlacesAction {
+    pub fn new() -> PlacesAction {
+        PlacesAction {}
+    }
+}
+
+impl Action for PlacesAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/places/?$|^/places/([^/]+)(?:/([^/]+))?(?:/([^/]+))?/?$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/places.html").to_string();
+        server.register_template("places", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let parts = parts_from_captures(caps);
+
+        let hits = match place_hits(&context, &parts) {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to find places"),
+        };
+
+        let favourites = user_favourites(&request, &context);
+        let images: Vec<Json> = hits
+            .iter()
+            .map(|x| mark_favourite(image_to_json(&context, &x.image), &x.image, &favourites))
+            .collect();
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("label".to_string(), place_label(&parts).to_json());
+        result_dict.insert(
+            "missing_places".to_string(),
+            context.geocoder.is_bundled().to_json(),
+        );
+        result_dict.insert("count".to_string(), images.len().to_json());
+        if !parts.is_empty() {
+            result_dict.insert("has_parent".to_string(), true.to_json());
+            result_dict.insert(
+                "parent".to_string(),
+                place_path(&parts[..parts.len() - 1]).to_json(),
+            );
+        }
+        result_dict.insert("buckets".to_string(), buckets_to_json(&hits, &parts));
+        result_dict.insert("images".to_string(), Json::Array(images));
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("places", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+}
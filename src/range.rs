@@ -0,0 +1,366 @@
+use std::fs::File;
+use std::io::{self, Read, Result, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::prelude::*;
+use tiny_http::{Header, HeaderField, Method, Request, StatusCode};
+
+use crate::web::{get_header, http_date};
+
+/// Requests for more ranges than this, after merging overlapping ones, are
+/// answered with the whole file, as the standard allows.
+const MAX_RANGES: usize = 16;
+
+/// What a `Range` header asks for, resolved against the length of a file.
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+    /// No range was requested, or the header can't be understood.
+    Full,
+    /// Inclusive byte ranges, sorted and without overlaps.
+    Ranges(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header such as `bytes=0-499,1000-` or `bytes=-500`.
+fn parse_range(value: &str, length: u64) -> RangeRequest {
+    let specs = match value.trim().strip_prefix("bytes=") {
+        Some(x) => x,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        let mut bounds = spec.splitn(2, '-');
+        let (first, last) = match (bounds.next(), bounds.next()) {
+            (Some(first), Some(last)) => (first.trim(), last.trim()),
+            _ => return RangeRequest::Full,
+        };
+
+        let range = if first.is_empty() {
+            // A suffix range, i.e. the last n bytes
+            match last.parse::<u64>() {
+                Ok(0) => None,
+                Ok(n) if length > 0 => Some((length.saturating_sub(n), length - 1)),
+                Ok(_) => None,
+                Err(_) => return RangeRequest::Full,
+            }
+        } else {
+            let first = match first.parse::<u64>() {
+                Ok(x) => x,
+                Err(_) => return RangeRequest::Full,
+            };
+            let last = match last {
+                "" => u64::max_value(),
+                x => match x.parse::<u64>() {
+                    Ok(x) if x >= first => x,
+                    _ => return RangeRequest::Full,
+                },
+            };
+
+            if first < length {
+                Some((first, last.min(length - 1)))
+            } else {
+                None
+            }
+        };
+
+        if let Some(range) = range {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    ranges.sort();
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some(previous) if first <= previous.1.saturating_add(1) => {
+                previous.1 = previous.1.max(last)
+            }
+            _ => merged.push((first, last)),
+        }
+    }
+
+    if merged.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    RangeRequest::Ranges(merged)
+}
+
+/// Checks the `If-Range` header, which makes a range request fall back to
+/// the whole file if it changed since the client got its part. Only strong
+/// ETags and exact modification dates count as a match.
+fn if_range_matches(value: Option<&str>, etag: &str, modified: Option<i64>) -> bool {
+    let value = match value {
+        Some(x) => x,
+        None => return true,
+    };
+
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        return !etag.starts_with("W/") && value == etag;
+    }
+
+    match (DateTime::parse_from_rfc2822(value), modified) {
+        (Ok(date), Some(modified)) => date.timestamp() == modified,
+        _ => false,
+    }
+}
+
+fn header(field: &str, value: &str) -> Header {
+    Header {
+        field: field.parse::<HeaderField>().unwrap(),
+        value: value.parse().unwrap(),
+    }
+}
+
+/// Writes a response to the connection directly, as tiny_http refuses to
+/// send `Accept-Ranges` and `Content-Range` headers.
+fn write_response<F>(
+    request: Request,
+    status: StatusCode,
+    mut headers: Vec<Header>,
+    length: u64,
+    write_body: F,
+) -> Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> Result<()>,
+{
+    headers.push(header("Date", &http_date(UTC::now().timestamp())));
+    headers.push(header("Content-Length", &length.to_string()));
+
+    let send_body = *request.method() != Method::Head;
+    let version = request.http_version().clone();
+    let mut writer = request.into_writer();
+
+    write!(
+        writer,
+        "HTTP/{}.{} {} {}\r\n",
+        version.0,
+        version.1,
+        status.0,
+        status.default_reason_phrase()
+    )?;
+    for header in &headers {
+        write!(writer, "{}\r\n", header)?;
+    }
+    write!(writer, "\r\n")?;
+
+    if send_body {
+        write_body(&mut writer)?;
+    }
+
+    writer.flush()
+}
+
+fn copy_range(file: &mut File, range: (u64, u64), writer: &mut dyn Write) -> Result<()> {
+    file.seek(SeekFrom::Start(range.0))?;
+    io::copy(&mut Read::by_ref(file).take(range.1 - range.0 + 1), writer)?;
+    Ok(())
+}
+
+/// Sends a file, or the parts of it asked for by a `Range` header. Multiple
+/// ranges are sent as `multipart/byteranges`. `headers` must include the
+/// content type, and `etag` and `modified` describe the file for `If-Range`.
+pub fn file_response(
+    request: Request,
+    mut file: File,
+    mut headers: Vec<Header>,
+    etag: &str,
+    modified: Option<i64>,
+) -> Result<()> {
+    let length = file.metadata()?.len();
+    headers.push(header("Accept-Ranges", "bytes"));
+
+    let range = match get_header(&request, "Range") {
+        Some(ref x) if *request.method() == Method::Get || *request.method() == Method::Head => {
+            let if_range = get_header(&request, "If-Range");
+            if if_range_matches(if_range.as_deref(), etag, modified) {
+                parse_range(x, length)
+            } else {
+                RangeRequest::Full
+            }
+        }
+        _ => RangeRequest::Full,
+    };
+
+    match range {
+        RangeRequest::Full => write_response(request, StatusCode(200), headers, length, |w| {
+            io::copy(&mut file, w).map(|_| ())
+        }),
+        RangeRequest::Unsatisfiable => {
+            headers.retain(|x| !x.field.equiv("Content-Type"));
+            headers.push(header("Content-Range", &format!("bytes */{}", length)));
+            write_response(request, StatusCode(416), headers, 0, |_| Ok(()))
+        }
+        RangeRequest::Ranges(ref ranges) if ranges.len() == 1 => {
+            let range = ranges[0];
+            headers.push(header(
+                "Content-Range",
+                &format!("bytes {}-{}/{}", range.0, range.1, length),
+            ));
+            write_response(
+                request,
+                StatusCode(206),
+                headers,
+                range.1 - range.0 + 1,
+                |w| copy_range(&mut file, range, w),
+            )
+        }
+        RangeRequest::Ranges(ranges) => {
+            let content_type = headers
+                .iter()
+                .find(|x| x.field.equiv("Content-Type"))
+                .map(|x| x.value.as_str().to_string())
+                .unwrap_or_else(|| "application/octet-stream".to_string());
+            headers.retain(|x| !x.field.equiv("Content-Type"));
+
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.subsec_nanos())
+                .unwrap_or(0);
+            let boundary = format!("hostimg-{:08x}{:x}", nanos, length);
+            headers.push(header(
+                "Content-Type",
+                &format!("multipart/byteranges; boundary={}", boundary),
+            ));
+
+            let part_headers: Vec<String> = ranges
+                .iter()
+                .map(|range| {
+                    format!(
+                        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        boundary, content_type, range.0, range.1, length
+                    )
+                })
+                .collect();
+            let closing = format!("\r\n--{}--\r\n", boundary);
+
+            let body_length = ranges
+                .iter()
+                .zip(&part_headers)
+                .map(|(range, part)| part.len() as u64 + range.1 - range.0 + 1)
+                .sum::<u64>()
+                + closing.len() as u64;
+
+            write_response(request, StatusCode(206), headers, body_length, |w| {
+                for (range, part) in ranges.iter().zip(&part_headers) {
+                    w.write_all(part.as_bytes())?;
+                    copy_range(&mut file, *range, w)?;
+                }
+                w.write_all(closing.as_bytes())
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            parse_range("bytes=0-499", 1000),
+            RangeRequest::Ranges(vec![(0, 499)])
+        );
+        assert_eq!(
+            parse_range("bytes=500-", 1000),
+            RangeRequest::Ranges(vec![(500, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=900-2000", 1000),
+            RangeRequest::Ranges(vec![(900, 999)])
+        );
+        assert_eq!(
+            parse_range(" bytes=0-0, 2-2 ", 1000),
+            RangeRequest::Ranges(vec![(0, 0), (2, 2)])
+        );
+    }
+
+    #[test]
+    fn test_parse_range_suffix() {
+        assert_eq!(
+            parse_range("bytes=-200", 1000),
+            RangeRequest::Ranges(vec![(800, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=-2000", 1000),
+            RangeRequest::Ranges(vec![(0, 999)])
+        );
+        assert_eq!(parse_range("bytes=-0", 1000), RangeRequest::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), RangeRequest::Unsatisfiable);
+    }
+
+    #[test]
+    fn test_parse_range_overlapping() {
+        assert_eq!(
+            parse_range("bytes=0-99,50-149,150-199,300-399", 1000),
+            RangeRequest::Ranges(vec![(0, 199), (300, 399)])
+        );
+        assert_eq!(
+            parse_range("bytes=500-599,0-99,-100", 1000),
+            RangeRequest::Ranges(vec![(0, 99), (500, 599), (900, 999)])
+        );
+        assert_eq!(
+            parse_range("bytes=0-,100-200", 1000),
+            RangeRequest::Ranges(vec![(0, 999)])
+        );
+    }
+
+    #[test]
+    fn test_parse_range_unsatisfiable() {
+        assert_eq!(
+            parse_range("bytes=1000-", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(
+            parse_range("bytes=1000-1999", 1000),
+            RangeRequest::Unsatisfiable
+        );
+        assert_eq!(parse_range("bytes=0-", 0), RangeRequest::Unsatisfiable);
+
+        // Satisfiable ranges are served even if others aren't
+        assert_eq!(
+            parse_range("bytes=2000-,0-9", 1000),
+            RangeRequest::Ranges(vec![(0, 9)])
+        );
+    }
+
+    #[test]
+    fn test_parse_range_invalid() {
+        assert_eq!(parse_range("items=0-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=abc-", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=5-1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=1", 1000), RangeRequest::Full);
+        assert_eq!(parse_range("bytes=-x", 1000), RangeRequest::Full);
+
+        let many: Vec<String> = (0..MAX_RANGES + 1)
+            .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+            .collect();
+        assert_eq!(
+            parse_range(&format!("bytes={}", many.join(",")), 1000),
+            RangeRequest::Full
+        );
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let etag = "\"abc\"";
+        assert!(if_range_matches(None, etag, None));
+        assert!(if_range_matches(Some("\"abc\""), etag, None));
+        assert!(!if_range_matches(Some("\"abd\""), etag, None));
+        assert!(!if_range_matches(Some("W/\"abc\""), etag, None));
+        assert!(!if_range_matches(Some("W/\"abc\""), "W/\"abc\"", None));
+
+        let date = "Wed, 21 Oct 2015 07:28:00 GMT";
+        assert!(if_range_matches(Some(date), etag, Some(1445412480)));
+        assert!(!if_range_matches(Some(date), etag, Some(1445412481)));
+        assert!(!if_range_matches(Some(date), etag, None));
+        assert!(!if_range_matches(Some("yesterday"), etag, Some(1445412480)));
+    }
+}
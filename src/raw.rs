@@ -0,0 +1,67 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::path::Path;
+
+use image::{DynamicImage, ImageError, ImageResult};
+
+use crate::exif::embedded_jpegs;
+
+/// Camera RAW formats, which are all TIFF based and embed JPEG previews,
+/// with the media type their originals are served as.
+const RAW_TYPES: &[(&str, &str)] = &[
+    ("cr2", "image/x-canon-cr2"),
+    ("nef", "image/x-nikon-nef"),
+    ("arw", "image/x-sony-arw"),
+    ("dng", "image/x-adobe-dng"),
+];
+
+pub fn content_type(file: &Path) -> Option<&'static str> {
+    let extension = file.extension()?.to_str()?.to_ascii_lowercase();
+    RAW_TYPES.iter().find(|x| x.0 == extension).map(|x| x.1)
+}
+
+pub fn is_raw(file: &Path) -> bool {
+    content_type(file).is_some()
+}
+
+/// Checks whether a JPEG stream uses a process the image crate decodes.
+/// The sensor data of RAW files is often stored as lossless JPEG, which it
+/// doesn't.
+fn is_decodable_jpeg(data: &[u8]) -> bool {
+    let mut pos = 2;
+    loop {
+        let segment = match data.get(pos..pos + 4) {
+            Some(x) if x[0] == 0xFF => x,
+            _ => return false,
+        };
+
+        match segment[1] {
+            // Baseline, extended and progressive frames
+            0xC0 | 0xC1 | 0xC2 => return true,
+            0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xD9 | 0xDA => return false,
+            _ => pos += 2 + ((segment[2] as usize) << 8 | segment[3] as usize),
+        }
+    }
+}
+
+/// Decodes the largest preview embedded in a RAW file, which stands in for
+/// it as the image thumbnails and previews are made from.
+pub fn open_preview(file: &Path) -> ImageResult<DynamicImage> {
+    let data = fs::read(file)?;
+
+    let mut previews: Vec<&[u8]> = embedded_jpegs(&data)
+        .into_iter()
+        .filter(|x| is_decodable_jpeg(x))
+        .collect();
+    previews.sort_by_key(|x| Reverse(x.len()));
+
+    for preview in previews {
+        if let Ok(image) = image::load_from_memory_with_format(preview, image::ImageFormat::JPEG) {
+            return Ok(image);
+        }
+    }
+
+    Err(ImageError::FormatError(
+        "No embedded preview found".to_string(),
+    ))
+}
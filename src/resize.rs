@@ -0,0 +1,480 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use handlebars::Handlebars;
+use image::{DynamicImage, GenericImage};
+use regex::{Captures, Regex};
+use tiny_http::{Request, Response, StatusCode};
+
+use crate::context::ServerContext;
+use crate::file::open_image;
+use crate::format::{encode_file, negotiate, OutputFormat};
+use crate::gallery::image_file_response;
+use crate::video::is_video;
+use crate::web::{error_response, get_header, not_found_response, query_params, Action, WebServer};
+
+/// Widths and heights that can be requested unless overridden with
+/// `HOSTIMG_RESIZE_SIZES`. Every combination of parameters is cached, so
+/// they are limited to keep the cache from growing without bounds.
+pub const DEFAULT_RESIZE_SIZES: &[u32] = &[200, 400, 800, 1200, 1600, 2400];
+
+const QUALITIES: &[u8] = &[50, 65, 80, 90];
+pub const DEFAULT_QUALITY: u8 = 80;
+
+/// Pixel ratios of high density displays, which multiply the requested size.
+pub const PIXEL_RATIOS: &[u32] = &[1, 2, 3];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    /// Scales the image to fit within the requested size.
+    Contain,
+    /// Scales the image to cover the requested size and crops the rest.
+    Cover,
+}
+
+/// A derivative of an image, as requested by the resize endpoint. Sizes
+/// are in pixels, with the pixel ratio already applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResizeParams {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub quality: u8,
+    pub format: OutputFormat,
+}
+
+/// Parses a comma separated list of sizes, e.g. "400,800".
+pub fn parse_sizes(value: &str) -> Vec<u32> {
+    value
+        .split(',')
+        .filter_map(|x| x.trim().parse::<u32>().ok())
+        .filter(|&x| x > 0)
+        .collect()
+}
+
+impl ResizeParams {
+    /// Reads the parameters of a request, rejecting anything that isn't on
+    /// the allow-lists. Without an explicit format, the best one the client
+    /// accepts is used.
+    pub fn from_query(
+        params: &BTreeMap<String, String>,
+        sizes: &[u32],
+        formats: &[OutputFormat],
+        accept: Option<&str>,
+    ) -> std::result::Result<ResizeParams, &'static str> {
+        let size = |name: &str| -> std::result::Result<Option<u32>, &'static str> {
+            match params.get(name).map(|x| x.parse::<u32>()) {
+                None => Ok(None),
+                Some(Ok(x)) if sizes.contains(&x) => Ok(Some(x)),
+                Some(_) => Err("Size is not allowed"),
+            }
+        };
+
+        let width = size("w")?;
+        let height = size("h")?;
+        if width.is_none() && height.is_none() {
+            return Err("Width or height required");
+        }
+
+        let fit = match params.get("fit").map(|x| x.as_str()) {
+            None | Some("contain") => Fit::Contain,
+            Some("cover") if width.is_some() && height.is_some() => Fit::Cover,
+            Some("cover") => return Err("Cropping requires both width and height"),
+            Some(_) => return Err("Unknown fit"),
+        };
+
+        let quality = match params.get("q").map(|x| x.parse::<u8>()) {
+            None => DEFAULT_QUALITY,
+            Some(Ok(x)) if QUALITIES.contains(&x) => x,
+            Some(_) => return Err("Quality is not allowed"),
+        };
+
+        let format = match params.get("format").map(|x| OutputFormat::from_name(x)) {
+            None => negotiate(accept, formats),
+            Some(Some(x)) if formats.contains(&x) => x,
+            Some(_) => return Err("Format is not available"),
+        };
+
+        let ratio = match params.get("dpr").map(|x| x.parse::<u32>()) {
+            None => 1,
+            Some(Ok(x)) if PIXEL_RATIOS.contains(&x) => x,
+            Some(_) => return Err("Pixel ratio is not allowed"),
+        };
+
+        Ok(ResizeParams {
+            width: width.map(|x| x * ratio),
+            height: height.map(|x| x * ratio),
+            fit,
+            quality,
+            format,
+        })
+    }
+
+    /// The name of the cached derivative, which starts with the hash of the
+    /// original so that all derivatives of an image can be found.
+    pub fn file_name(&self, hash: &str) -> String {
+        format!(
+            "{}_{}x{}_{}_q{}.{}",
+            hash,
+            self.width.unwrap_or(0),
+            self.height.unwrap_or(0),
+            match self.fit {
+                Fit::Contain => "contain",
+                Fit::Cover => "cover",
+            },
+            self.quality,
+            self.format.extension()
+        )
+    }
+}
+
+/// Scales an image as requested. Images are never enlarged.
+fn resize_image(image: &DynamicImage, params: &ResizeParams) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let max_width = params.width.unwrap_or(u32::max_value());
+    let max_height = params.height.unwrap_or(u32::max_value());
+
+    match params.fit {
+        Fit::Contain => {
+            if width <= max_width && height <= max_height {
+                image.clone()
+            } else {
+                image.resize(
+                    max_width.min(width),
+                    max_height.min(height),
+                    image::FilterType::CatmullRom,
+                )
+            }
+        }
+        Fit::Cover => {
+            let scale = (max_width as f64 / width as f64)
+                .max(max_height as f64 / height as f64)
+                .min(1.0);
+            let scaled_width = ((width as f64 * scale).round() as u32).max(1);
+            let scaled_height = ((height as f64 * scale).round() as u32).max(1);
+
+            let mut scaled =
+                image.resize_exact(scaled_width, scaled_height, image::FilterType::CatmullRom);
+
+            let crop_width = max_width.min(scaled_width);
+            let crop_height = max_height.min(scaled_height);
+            scaled.crop(
+                (scaled_width - crop_width) / 2,
+                (scaled_height - crop_height) / 2,
+                crop_width,
+                crop_height,
+            )
+        }
+    }
+}
+
+fn save_image(image: &DynamicImage, params: &ResizeParams, path: &Path) -> io::Result<()> {
+    match params.format {
+        OutputFormat::Jpeg => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            let (width, height) = image.dimensions();
+            image::jpeg::JPEGEncoder::new_with_quality(&mut writer, params.quality).encode(
+                &image.raw_pixels(),
+                width,
+                height,
+                image.color(),
+            )?;
+            writer.flush()
+        }
+        OutputFormat::Png => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            image
+                .save(&mut writer, image::ImageFormat::PNG)
+                .or(Err(io::Error::new(
+                    ErrorKind::Other,
+                    "Failed to write image data",
+                )))?;
+            writer.flush()
+        }
+        OutputFormat::Webp | OutputFormat::Avif => {
+            // The external encoders are handed a lossless copy
+            let png_path = path.with_extension("png");
+            let png_params = ResizeParams {
+                format: OutputFormat::Png,
+                ..*params
+            };
+            save_image(image, &png_params, &png_path)?;
+
+            let result = encode_file(params.format, params.quality, &png_path, path);
+            let _ = fs::remove_file(&png_path);
+            result
+        }
+    }
+}
+
+/// Returns a thumbnail or preview in the given format, converting the JPEG
+/// written during indexing on first use.
+pub fn derivative_in_format(dir: &Path, hash: &str, format: OutputFormat) -> io::Result<PathBuf> {
+    let jpeg_path = dir.join(format!("{}.jpg", hash));
+    if format == OutputFormat::Jpeg {
+        return Ok(jpeg_path);
+    }
+
+    let path = dir.join(format!("{}.{}", hash, format.extension()));
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let temp_path = dir.join(format!(".{:?}.tmp", thread::current().id()));
+    encode_file(format, DEFAULT_QUALITY, &jpeg_path, &temp_path)?;
+    fs::rename(&temp_path, &path)?;
+
+    Ok(path)
+}
+
+/// Returns the path of a derivative, generating it from the original on
+/// first use.
+pub fn resized_image(
+    context: &ServerContext,
+    hash: &str,
+    params: &ResizeParams,
+) -> io::Result<Option<PathBuf>> {
+    let path = context.resize_dir.join(params.file_name(hash));
+    if path.exists() {
+        return Ok(Some(path));
+    }
+
+    let images = context
+        .datastore
+        .find_images_by_hash(hash.to_string())
+        .map_err(|e| io::Error::new(ErrorKind::Other, format!("{:?}", e)))?;
+    let original = match images
+        .iter()
+        .map(|x| Path::new(&x.name))
+        .find(|x| x.exists())
+    {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    // Videos are resized from their poster frame
+    let source = if is_video(original) {
+        context.preview_dir.join(format!("{}.jpg", hash))
+    } else {
+        original.to_path_buf()
+    };
+
+    let image = open_image(&source).or(Err(io::Error::new(
+        ErrorKind::InvalidData,
+        "Failed to open image",
+    )))?;
+
+    // Concurrent requests for the same derivative each write their own file,
+    // so that no one is served a partial one
+    let temp_path = context
+        .resize_dir
+        .join(format!(".{:?}.tmp", thread::current().id()));
+    save_image(&resize_image(&image, params), params, &temp_path)?;
+    fs::rename(&temp_path, &path)?;
+
+    Ok(Some(path))
+}
+
+/// Removes the cached derivatives of an image.
+pub fn remove_resized_images(context: &ServerContext, hash: &str) -> io::Result<()> {
+    let prefix = format!("{}_", hash);
+    for entry in fs::read_dir(&context.resize_dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            fs::remove_file(entry.path())?;
+        }
+    }
+
+    Ok(())
+}
+
+pub struct ResizeAction {}
+
+impl ResizeAction {
+    pub fn new() -> ResizeAction {
+        ResizeAction {}
+    }
+}
+
+impl Action for ResizeAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/image/([0-9A-Fa-f]+)/resize$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let hash = match caps.get(1) {
+            Some(x) => x.as_str().to_string(),
+            None => return error_response(request, "No hash specified"),
+        };
+
+        let query = query_params(request.url());
+        let accept = get_header(&request, "Accept");
+        let params = match ResizeParams::from_query(
+            &query,
+            &context.resize_sizes,
+            &context.image_formats,
+            accept.as_ref().map(|x| x.as_str()),
+        ) {
+            Ok(x) => x,
+            Err(e) => return error_response(request, e),
+        };
+        let negotiated = !query.contains_key("format");
+
+        match resized_image(&context, &hash, &params) {
+            Ok(Some(path)) => {
+                image_file_response(request, &path, params.format.content_type(), negotiated)
+            }
+            Ok(None) => not_found_response(request, "Image not found"),
+            Err(e) => {
+                let _ = request.respond(Response::empty(StatusCode(500)));
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FORMATS: &[OutputFormat] = &[OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Webp];
+
+    fn from_query(
+        pairs: &[(&str, &str)],
+        accept: Option<&str>,
+    ) -> std::result::Result<ResizeParams, &'static str> {
+        let params: BTreeMap<String, String> = pairs
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        ResizeParams::from_query(&params, DEFAULT_RESIZE_SIZES, FORMATS, accept)
+    }
+
+    #[test]
+    fn test_from_query() {
+        assert_eq!(
+            from_query(&[("w", "400")], None),
+            Ok(ResizeParams {
+                width: Some(400),
+                height: None,
+                fit: Fit::Contain,
+                quality: DEFAULT_QUALITY,
+                format: OutputFormat::Jpeg,
+            })
+        );
+        assert_eq!(
+            from_query(
+                &[
+                    ("w", "400"),
+                    ("h", "200"),
+                    ("fit", "cover"),
+                    ("q", "90"),
+                    ("format", "png")
+                ],
+                None
+            ),
+            Ok(ResizeParams {
+                width: Some(400),
+                height: Some(200),
+                fit: Fit::Cover,
+                quality: 90,
+                format: OutputFormat::Png,
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_query_pixel_ratio() {
+        let params = from_query(&[("w", "400"), ("h", "800"), ("dpr", "2")], None).unwrap();
+        assert_eq!(params.width, Some(800));
+        assert_eq!(params.height, Some(1600));
+    }
+
+    #[test]
+    fn test_from_query_negotiation() {
+        // AVIF would be preferred, but isn't available
+        let accept = Some("image/avif,image/webp,image/apng,*/*;q=0.8");
+        assert_eq!(
+            from_query(&[("w", "400")], accept).map(|x| x.format),
+            Ok(OutputFormat::Webp)
+        );
+        assert_eq!(
+            from_query(&[("w", "400")], Some("image/webp;q=0, */*")).map(|x| x.format),
+            Ok(OutputFormat::Jpeg)
+        );
+        assert_eq!(
+            from_query(&[("w", "400"), ("format", "jpg")], accept).map(|x| x.format),
+            Ok(OutputFormat::Jpeg)
+        );
+    }
+
+    #[test]
+    fn test_from_query_rejected() {
+        let error = |pairs: &[(&str, &str)]| from_query(pairs, None).unwrap_err();
+
+        assert_eq!(error(&[]), "Width or height required");
+        assert_eq!(error(&[("q", "80")]), "Width or height required");
+        assert_eq!(error(&[("w", "500")]), "Size is not allowed");
+        assert_eq!(error(&[("w", "-400")]), "Size is not allowed");
+        assert_eq!(error(&[("w", "400"), ("h", "big")]), "Size is not allowed");
+        assert_eq!(
+            error(&[("w", "400"), ("fit", "cover")]),
+            "Cropping requires both width and height"
+        );
+        assert_eq!(error(&[("w", "400"), ("fit", "stretch")]), "Unknown fit");
+        assert_eq!(
+            error(&[("w", "400"), ("q", "100")]),
+            "Quality is not allowed"
+        );
+        assert_eq!(
+            error(&[("w", "400"), ("format", "avif")]),
+            "Format is not available"
+        );
+        assert_eq!(
+            error(&[("w", "400"), ("format", "gif")]),
+            "Format is not available"
+        );
+        assert_eq!(
+            error(&[("w", "400"), ("dpr", "4")]),
+            "Pixel ratio is not allowed"
+        );
+        assert_eq!(
+            error(&[("w", "400"), ("dpr", "0")]),
+            "Pixel ratio is not allowed"
+        );
+    }
+
+    #[test]
+    fn test_file_name() {
+        let params = ResizeParams {
+            width: Some(400),
+            height: Some(200),
+            fit: Fit::Cover,
+            quality: 90,
+            format: OutputFormat::Webp,
+        };
+        assert_eq!(params.file_name("ABC"), "ABC_400x200_cover_q90.webp");
+
+        let params = ResizeParams {
+            height: None,
+            fit: Fit::Contain,
+            format: OutputFormat::Jpeg,
+            ..params
+        };
+        assert_eq!(params.file_name("ABC"), "ABC_400x0_contain_q90.jpg");
+    }
+}
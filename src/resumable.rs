@@ -0,0 +1,682 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, read_dir, File, OpenOptions};
+use std::io::{self, Read, Result, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use chrono::prelude::*;
+use chrono::Duration;
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::{Json, ToJson};
+use sha2::Digest;
+use tiny_http::{Header, HeaderField, Method, Request, Response, StatusCode};
+
+use crate::auth::{current_user, forbidden_response};
+use crate::context::ServerContext;
+use crate::file::hash_file;
+use crate::manage::move_file;
+use crate::upload::{enqueue, find_duplicate, gallery_directory, target_file};
+use crate::web::{get_header, http_date, json_response, Action, WebServer};
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration,checksum,termination";
+const TUS_CHECKSUM_ALGORITHMS: &str = "sha1,sha256";
+
+/// Largest file accepted through the resumable upload protocol.
+pub const MAX_RESUMABLE_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Partial uploads that haven't received any data for this long are removed.
+pub const UPLOAD_EXPIRY_HOURS: i64 = 24;
+
+fn build_header(field: &str, value: &str) -> Header {
+    Header {
+        field: field.parse::<HeaderField>().unwrap(),
+        value: value.parse().unwrap(),
+    }
+}
+
+fn respond_empty(request: Request, status: u16, headers: Vec<Header>) -> Result<()> {
+    let mut response = Response::empty(StatusCode(status));
+    response.add_header(build_header("Tus-Resumable", TUS_VERSION));
+    for header in headers {
+        response.add_header(header);
+    }
+
+    request.respond(response)
+}
+
+/// Checks the `Tus-Resumable` header against the protocol version
+/// implemented here, which tus requires every request but OPTIONS to state.
+fn is_supported_version(header: Option<&str>) -> bool {
+    header.map_or(false, |x| x.trim() == TUS_VERSION)
+}
+
+/// Checks that the `Upload-Offset` header of a PATCH continues where the
+/// data received so far ends.
+fn is_expected_offset(header: Option<&str>, offset: u64) -> bool {
+    header.and_then(|x| x.trim().parse::<u64>().ok()) == Some(offset)
+}
+
+/// Parses the `Upload-Metadata` header, which is a comma separated list of
+/// keys and base64 encoded values.
+fn parse_metadata(value: &str) -> BTreeMap<String, String> {
+    value
+        .split(',')
+        .filter_map(|pair| {
+            let mut parts = pair.trim().splitn(2, ' ');
+            let key = parts.next()?.to_string();
+            let value = parts
+                .next()
+                .and_then(|x| x.from_base64().ok())
+                .map(|x| String::from_utf8_lossy(&x).into_owned())
+                .unwrap_or_default();
+            Some((key, value))
+        })
+        .collect()
+}
+
+/// Generates a random looking identifier for a new upload.
+fn generate_id(seed: &str) -> String {
+    let now = UTC::now();
+    let mut hasher = sha2::Sha256::new();
+    hasher.input(seed.as_bytes());
+    hasher.input(format!("{}.{}", now.timestamp(), now.timestamp_subsec_nanos()).as_bytes());
+    hasher.input(format!("{:?}", thread::current().id()).as_bytes());
+
+    hasher
+        .result()
+        .iter()
+        .take(16)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+enum Checksum {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+impl Checksum {
+    fn from_algorithm(name: &str) -> Option<Checksum> {
+        match name {
+            "sha1" => Some(Checksum::Sha1(sha1::Sha1::new())),
+            "sha256" => Some(Checksum::Sha256(sha2::Sha256::new())),
+            _ => None,
+        }
+    }
+
+    /// Parses an `Upload-Checksum` header, which names the algorithm and
+    /// gives the expected digest in base64.
+    fn from_header(value: &str) -> Option<(Checksum, Vec<u8>)> {
+        let mut parts = value.splitn(2, ' ');
+        let checksum = Checksum::from_algorithm(parts.next()?)?;
+        let expected = parts.next()?.trim().from_base64().ok()?;
+
+        Some((checksum, expected))
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match *self {
+            Checksum::Sha1(ref mut x) => x.update(data),
+            Checksum::Sha256(ref mut x) => x.input(data),
+        }
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Checksum::Sha1(x) => x.digest().bytes().to_vec(),
+            Checksum::Sha256(x) => x.result().to_vec(),
+        }
+    }
+}
+
+/// The state of an upload, persisted next to its data so that uploads
+/// survive restarts.
+struct UploadInfo {
+    id: String,
+    length: u64,
+    file_name: String,
+    gallery: String,
+    expires: i64,
+    result: Option<Json>,
+}
+
+impl UploadInfo {
+    fn info_path(upload_dir: &Path, id: &str) -> PathBuf {
+        upload_dir.join(format!("{}.info", id))
+    }
+
+    fn data_path(upload_dir: &Path, id: &str) -> PathBuf {
+        upload_dir.join(format!("{}.part", id))
+    }
+
+    fn load(upload_dir: &Path, id: &str) -> Option<UploadInfo> {
+        let mut data = String::new();
+        File::open(UploadInfo::info_path(upload_dir, id))
+            .and_then(|mut x| x.read_to_string(&mut data))
+            .ok()?;
+
+        let json = Json::from_str(&data).ok()?;
+        Some(UploadInfo {
+            id: id.to_string(),
+            length: json.find("length")?.as_u64()?,
+            file_name: json.find("file_name")?.as_string()?.to_string(),
+            gallery: json.find("gallery")?.as_string()?.to_string(),
+            expires: json.find("expires")?.as_i64()?,
+            result: json.find("result").cloned().filter(|x| !x.is_null()),
+        })
+    }
+
+    fn save(&self, upload_dir: &Path) -> io::Result<()> {
+        let mut dict = BTreeMap::new();
+        dict.insert("length".to_string(), self.length.to_json());
+        dict.insert("file_name".to_string(), self.file_name.to_json());
+        dict.insert("gallery".to_string(), self.gallery.to_json());
+        dict.insert("expires".to_string(), self.expires.to_json());
+        dict.insert("result".to_string(), self.result.to_json());
+
+        let mut file = File::create(UploadInfo::info_path(upload_dir, &self.id))?;
+        file.write_all(Json::Object(dict).to_string().as_bytes())
+    }
+
+    fn remove(&self, upload_dir: &Path) {
+        let _ = fs::remove_file(UploadInfo::data_path(upload_dir, &self.id));
+        let _ = fs::remove_file(UploadInfo::info_path(upload_dir, &self.id));
+    }
+
+    fn offset(&self, upload_dir: &Path) -> u64 {
+        fs::metadata(UploadInfo::data_path(upload_dir, &self.id))
+            .map(|x| x.len())
+            .unwrap_or(0)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.result.is_none() && self.expires < UTC::now().timestamp()
+    }
+
+    fn touch(&mut self) {
+        self.expires = (UTC::now() + Duration::hours(UPLOAD_EXPIRY_HOURS)).timestamp();
+    }
+
+    fn expires_header(&self) -> Header {
+        build_header("Upload-Expires", &http_date(self.expires))
+    }
+}
+
+/// Moves a completed upload into its gallery, unless its content is known
+/// already, and returns a description of the outcome.
+fn complete_upload(context: &ServerContext, info: &UploadInfo) -> io::Result<Json> {
+    let data_path = UploadInfo::data_path(&context.upload_dir, &info.id);
+    let hash = hash_file(&data_path)?;
+
+    let mut result = BTreeMap::new();
+    result.insert("name".to_string(), info.file_name.to_json());
+    result.insert("hash".to_string(), hash.to_json());
+
+    if let Some(existing) = find_duplicate(context, &hash) {
+        fs::remove_file(&data_path)?;
+        result.insert(
+            "existing".to_string(),
+            existing.to_str().unwrap_or("").to_json(),
+        );
+        return Ok(Json::Object(result));
+    }
+
+    let target = gallery_directory(context, &info.gallery)
+        .and_then(|dir| target_file(&dir, &info.file_name))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Gallery has disappeared"))?;
+
+    move_file(&data_path, &target)?;
+
+    println!("Completed resumable upload {:?}", target);
+
+    let relative = target
+        .strip_prefix(&context.gallery_dir)
+        .unwrap_or(&target)
+        .to_path_buf();
+    result.insert(
+        "path".to_string(),
+        relative.to_str().unwrap_or("").to_json(),
+    );
+
+    enqueue(context, target)?;
+
+    Ok(Json::Object(result))
+}
+
+/// Removes partial uploads that have expired, along with the bookkeeping of
+/// completed uploads past their expiry.
+pub fn purge_expired_uploads(upload_dir: &Path) -> io::Result<()> {
+    let now = UTC::now().timestamp();
+    for entry in read_dir(upload_dir)?.filter_map(|x| x.ok()) {
+        let path = entry.path();
+        if path.extension().and_then(|x| x.to_str()) != Some("info") {
+            continue;
+        }
+
+        let id = match path.file_stem().and_then(|x| x.to_str()) {
+            Some(x) => x.to_string(),
+            None => continue,
+        };
+
+        match UploadInfo::load(upload_dir, &id) {
+            Some(ref info) if info.expires < now => {
+                println!("Removing expired upload {}", id);
+                info.remove(upload_dir);
+            }
+            Some(_) => {}
+            None => {
+                let _ = fs::remove_file(&path);
+                let _ = fs::remove_file(UploadInfo::data_path(upload_dir, &id));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Periodically purges expired uploads in a background thread.
+pub fn start_upload_reaper(context: ServerContext) {
+    thread::spawn(move || loop {
+        if let Err(e) = purge_expired_uploads(&context.upload_dir) {
+            eprintln!("Failed to purge expired uploads: {:?}", e);
+        }
+
+        thread::sleep(StdDuration::from_secs(3600));
+    });
+}
+
+/// Implements the tus resumable upload protocol (https://tus.io), with
+/// the creation, expiration, checksum and termination extensions.
+pub struct ResumableUploadAction {
+    active: Mutex<BTreeSet<String>>,
+}
+
+impl ResumableUploadAction {
+    pub fn new() -> ResumableUploadAction {
+        ResumableUploadAction {
+            active: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    fn options(&self, request: Request) -> Result<()> {
+        respond_empty(
+            request,
+            204,
+            vec![
+                build_header("Tus-Version", TUS_VERSION),
+                build_header("Tus-Extension", TUS_EXTENSIONS),
+                build_header("Tus-Max-Size", &MAX_RESUMABLE_SIZE.to_string()),
+                build_header("Tus-Checksum-Algorithm", TUS_CHECKSUM_ALGORITHMS),
+            ],
+        )
+    }
+
+    fn create(&self, request: Request, context: &ServerContext) -> Result<()> {
+        let length = match get_header(&request, "Upload-Length").and_then(|x| x.parse::<u64>().ok())
+        {
+            Some(x) => x,
+            None => return respond_empty(request, 400, vec![]),
+        };
+
+        if length > MAX_RESUMABLE_SIZE {
+            return respond_empty(request, 413, vec![]);
+        }
+
+        let metadata = parse_metadata(&get_header(&request, "Upload-Metadata").unwrap_or_default());
+        let file_name = metadata.get("filename").cloned().unwrap_or_default();
+        let gallery = metadata.get("gallery").cloned().unwrap_or_default();
+
+        // Validate the destination up front, so that clients don't upload
+        // hundreds of megabytes only to have them rejected at the end.
+        let acceptable = gallery_directory(context, &gallery)
+            .and_then(|dir| target_file(&dir, &file_name))
+            .is_some();
+        if !acceptable {
+            return respond_empty(request, 400, vec![]);
+        }
+
+        let mut info = UploadInfo {
+            id: generate_id(&file_name),
+            length,
+            file_name,
+            gallery,
+            expires: 0,
+            result: None,
+        };
+        info.touch();
+
+        File::create(UploadInfo::data_path(&context.upload_dir, &info.id))?;
+        info.save(&context.upload_dir)?;
+
+        respond_empty(
+            request,
+            201,
+            vec![
+                build_header("Location", &format!("/api/v1/uploads/{}", info.id)),
+                info.expires_header(),
+            ],
+        )
+    }
+
+    fn head(&self, request: Request, context: &ServerContext, info: UploadInfo) -> Result<()> {
+        let offset = match info.result {
+            Some(_) => info.length,
+            None => info.offset(&context.upload_dir),
+        };
+
+        respond_empty(
+            request,
+            200,
+            vec![
+                build_header("Upload-Offset", &offset.to_string()),
+                build_header("Upload-Length", &info.length.to_string()),
+                build_header("Cache-Control", "no-store"),
+                info.expires_header(),
+            ],
+        )
+    }
+
+    fn status(&self, request: Request, context: &ServerContext, info: UploadInfo) -> Result<()> {
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("id".to_string(), info.id.to_json());
+        result_dict.insert("name".to_string(), info.file_name.to_json());
+        result_dict.insert("gallery".to_string(), info.gallery.to_json());
+        result_dict.insert("length".to_string(), info.length.to_json());
+        result_dict.insert(
+            "offset".to_string(),
+            match info.result {
+                Some(_) => info.length,
+                None => info.offset(&context.upload_dir),
+            }
+            .to_json(),
+        );
+        result_dict.insert("complete".to_string(), info.result.is_some().to_json());
+        result_dict.insert("result".to_string(), info.result.to_json());
+
+        json_response(request, &Json::Object(result_dict))
+    }
+
+    fn patch(
+        &self,
+        mut request: Request,
+        context: &ServerContext,
+        mut info: UploadInfo,
+    ) -> Result<()> {
+        let content_type = get_header(&request, "Content-Type").unwrap_or_default();
+        if content_type != "application/offset+octet-stream" {
+            return respond_empty(request, 415, vec![]);
+        }
+
+        let offset = info.offset(&context.upload_dir);
+        let requested_offset = get_header(&request, "Upload-Offset");
+        if info.result.is_some() || !is_expected_offset(requested_offset.as_deref(), offset) {
+            return respond_empty(request, 409, vec![]);
+        }
+
+        let mut checksum = None;
+        if let Some(header) = get_header(&request, "Upload-Checksum") {
+            match Checksum::from_header(&header) {
+                Some(x) => checksum = Some(x),
+                None => return respond_empty(request, 400, vec![]),
+            }
+        }
+
+        let mut output = OpenOptions::new()
+            .append(true)
+            .open(UploadInfo::data_path(&context.upload_dir, &info.id))?;
+
+        // Data is streamed straight to disk. Without a checksum whatever
+        // arrived before a dropped connection is kept, so that the client
+        // can resume from there.
+        let mut remaining = info.length - offset;
+        let mut buffer = [0; 65536];
+        let mut received = 0;
+        let mut write_failed = false;
+        let read_result = loop {
+            if remaining == 0 {
+                break Ok(());
+            }
+
+            let max = std::cmp::min(remaining, buffer.len() as u64) as usize;
+            let bytes_read = match request.as_reader().read(&mut buffer[0..max]) {
+                Ok(0) => break Ok(()),
+                Ok(x) => x,
+                Err(e) => break Err(e),
+            };
+
+            if let Some((ref mut hasher, _)) = checksum {
+                hasher.update(&buffer[0..bytes_read]);
+            }
+            if let Err(e) = output.write_all(&buffer[0..bytes_read]) {
+                write_failed = true;
+                break Err(e);
+            }
+
+            remaining -= bytes_read as u64;
+            received += bytes_read as u64;
+        };
+
+        // A buffer that couldn't be written may have made it to disk in part,
+        // so it's dropped. With a checksum nothing of the request is kept.
+        if write_failed {
+            eprintln!("Failed to write upload {}: {:?}", info.id, read_result);
+            let written = if checksum.is_some() { 0 } else { received };
+            if let Err(e) = output.set_len(offset + written) {
+                eprintln!("Failed to truncate upload {}: {:?}", info.id, e);
+            }
+            return respond_empty(request, 500, vec![]);
+        }
+
+        if let Some((hasher, expected)) = checksum {
+            if read_result.is_err() || hasher.finish() != expected {
+                output.set_len(offset)?;
+                return respond_empty(request, 460, vec![]);
+            }
+        }
+
+        info.touch();
+
+        let new_offset = offset + received;
+        if new_offset == info.length {
+            match complete_upload(context, &info) {
+                Ok(result) => info.result = Some(result),
+                Err(e) => {
+                    eprintln!("Failed to complete upload {}: {:?}", info.id, e);
+                    info.save(&context.upload_dir)?;
+                    return respond_empty(request, 500, vec![]);
+                }
+            }
+        }
+
+        info.save(&context.upload_dir)?;
+
+        respond_empty(
+            request,
+            204,
+            vec![
+                build_header("Upload-Offset", &new_offset.to_string()),
+                info.expires_header(),
+            ],
+        )
+    }
+}
+
+impl Action for ResumableUploadAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/api/v1/uploads/?$|^/api/v1/uploads/([0-9a-f]+)$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let method = request.method().clone();
+
+        if method != Method::Options && current_user(&request, &context).is_none() {
+            return forbidden_response(request, &context);
+        }
+
+        if method == Method::Options {
+            return self.options(request);
+        }
+
+        // The status of an upload as JSON is our own addition, which plain
+        // browsers ask for
+        let version = get_header(&request, "Tus-Resumable");
+        if method != Method::Get && !is_supported_version(version.as_deref()) {
+            return respond_empty(request, 412, vec![build_header("Tus-Version", TUS_VERSION)]);
+        }
+
+        let id = match caps.get(1) {
+            Some(x) => x.as_str().to_string(),
+            None => {
+                return match method {
+                    Method::Post => self.create(request, &context),
+                    _ => respond_empty(request, 405, vec![]),
+                };
+            }
+        };
+
+        // Only one request at a time may touch an upload, from reading its
+        // state until it has been written back.
+        let locked = match self.active.lock() {
+            Ok(mut active) => active.insert(id.clone()),
+            Err(_) => false,
+        };
+        if !locked {
+            return respond_empty(request, 423, vec![]);
+        }
+
+        let result = match UploadInfo::load(&context.upload_dir, &id) {
+            Some(ref x) if x.is_expired() => {
+                x.remove(&context.upload_dir);
+                respond_empty(request, 410, vec![])
+            }
+            Some(info) => match method {
+                Method::Head => self.head(request, &context, info),
+                Method::Get => self.status(request, &context, info),
+                Method::Patch => self.patch(request, &context, info),
+                Method::Delete => {
+                    info.remove(&context.upload_dir);
+                    respond_empty(request, 204, vec![])
+                }
+                _ => respond_empty(request, 405, vec![]),
+            },
+            None => respond_empty(request, 404, vec![]),
+        };
+
+        if let Ok(mut active) = self.active.lock() {
+            active.remove(&id);
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_supported_version() {
+        assert!(is_supported_version(Some("1.0.0")));
+        assert!(is_supported_version(Some(" 1.0.0 ")));
+        assert!(!is_supported_version(Some("0.2.2")));
+        assert!(!is_supported_version(Some("")));
+        assert!(!is_supported_version(None));
+    }
+
+    #[test]
+    fn test_is_expected_offset() {
+        assert!(is_expected_offset(Some("0"), 0));
+        assert!(is_expected_offset(Some("1024"), 1024));
+        assert!(!is_expected_offset(Some("512"), 1024));
+        assert!(!is_expected_offset(Some("2048"), 1024));
+        assert!(!is_expected_offset(Some("-1"), 0));
+        assert!(!is_expected_offset(Some("abc"), 0));
+        assert!(!is_expected_offset(None, 0));
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata =
+            parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==, is_confidential");
+        assert_eq!(
+            metadata.get("filename").map(|x| x.as_str()),
+            Some("world_domination_plan.pdf")
+        );
+        assert_eq!(
+            metadata.get("is_confidential").map(|x| x.as_str()),
+            Some("")
+        );
+        assert_eq!(metadata.len(), 2);
+    }
+
+    #[test]
+    fn test_checksum_from_header() {
+        let (mut checksum, expected) =
+            Checksum::from_header("sha1 Kq5sNclPz7QV2+lfQIuc6R7oRu0=").unwrap();
+        checksum.update(b"hello ");
+        checksum.update(b"world");
+        assert_eq!(checksum.finish(), expected);
+
+        let (mut checksum, expected) =
+            Checksum::from_header("sha256 uU0nuZNNPgilLlLX2n2r+sSE7+N6U4DukIj3rOLvzek=").unwrap();
+        checksum.update(b"hello world!");
+        assert!(checksum.finish() != expected);
+
+        assert!(Checksum::from_header("md5 XrY7u+Ae7tCTyyK7j1rNww==").is_none());
+        assert!(Checksum::from_header("sha1").is_none());
+        assert!(Checksum::from_header("sha1 ***").is_none());
+    }
+
+    #[test]
+    fn test_upload_info_offset() {
+        let upload_dir = std::env::temp_dir().join(format!("hostimg_tus_{}", generate_id("test")));
+        fs::create_dir_all(&upload_dir).unwrap();
+
+        let mut info = UploadInfo {
+            id: "0123abcd".to_string(),
+            length: 10,
+            file_name: "a.jpg".to_string(),
+            gallery: "2019/Holiday".to_string(),
+            expires: 0,
+            result: None,
+        };
+        info.touch();
+        info.save(&upload_dir).unwrap();
+
+        let loaded = UploadInfo::load(&upload_dir, "0123abcd").unwrap();
+        assert_eq!(loaded.length, 10);
+        assert_eq!(loaded.file_name, "a.jpg");
+        assert_eq!(loaded.gallery, "2019/Holiday");
+        assert_eq!(loaded.expires, info.expires);
+        assert!(loaded.result.is_none());
+        assert!(!loaded.is_expired());
+        assert_eq!(loaded.offset(&upload_dir), 0);
+
+        fs::write(UploadInfo::data_path(&upload_dir, &info.id), b"12345").unwrap();
+        assert_eq!(loaded.offset(&upload_dir), 5);
+
+        info.expires = 0;
+        assert!(info.is_expired());
+
+        info.remove(&upload_dir);
+        assert!(UploadInfo::load(&upload_dir, "0123abcd").is_none());
+        assert!(UploadInfo::load(&upload_dir, "missing").is_none());
+
+        let _ = fs::remove_dir(&upload_dir);
+    }
+}
@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Result;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::Request;
+
+use crate::context::ServerContext;
+use crate::db::{DataStoreError, ImageInfo, ImageMeta, SearchQuery};
+use crate::gallery::{
+    image_relative_path, image_to_json, mark_favourite, user_favourites, ImageFilter,
+};
+use crate::smart::rules_from_params;
+use crate::web::{error_response, html_response, query_params, query_string, Action, WebServer};
+
+pub const DEFAULT_SEARCH_LIMIT: usize = 100;
+
+/// Parameters that narrow a search down to a single value, along with the
+/// label of their facet.
+const FACETS: &[(&str, &str)] = &[
+    ("camera", "Camera"),
+    ("lens", "Lens"),
+    ("tag", "Tag"),
+    ("year", "Year"),
+    ("country", "Country"),
+    ("city", "City"),
+];
+
+/// Other parameters a search can be restricted by.
+const FILTERS: &[(&str, &str)] = &[
+    ("q", "Text"),
+    ("gallery", "Gallery"),
+    ("from", "From"),
+    ("to", "To"),
+    ("bbox", "Area"),
+    ("region", "Region"),
+    ("rating", "Minimum rating"),
+    ("label", "Label"),
+];
+
+/// Every parameter a search can be restricted by, with its label. Smart
+/// albums are defined by the same ones.
+pub fn search_parameters() -> impl Iterator<Item = &'static (&'static str, &'static str)> {
+    FILTERS.iter().chain(FACETS.iter())
+}
+
+pub struct SearchHit {
+    pub image: Arc<ImageInfo>,
+    pub meta: ImageMeta,
+}
+
+pub struct Facet {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub values: Vec<(String, usize)>,
+}
+
+/// Turns free text into an FTS query matching every word, including words
+/// that merely start with it.
+fn text_query(text: &str) -> String {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("{}*", x.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn parse_date(value: &str) -> Option<i64> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .map(|x| x.and_hms(0, 0, 0).timestamp())
+}
+
+/// Parses a bounding box given as "west,south,east,north" in degrees.
+fn parse_bounds(value: &str) -> Option<(f64, f64, f64, f64)> {
+    let parts = value
+        .split(',')
+        .map(|x| x.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+
+    match parts[..] {
+        [west, south, east, north] if south <= north => Some((west, south, east, north)),
+        _ => None,
+    }
+}
+
+fn year_of(timestamp: i64) -> i32 {
+    NaiveDateTime::from_timestamp(timestamp, 0).year()
+}
+
+fn build_query(params: &BTreeMap<String, String>) -> SearchQuery {
+    let param = |name: &str| params.get(name).filter(|x| !x.is_empty()).cloned();
+
+    let mut taken_from = param("from").and_then(|x| parse_date(&x));
+    let mut taken_until = param("to")
+        .and_then(|x| parse_date(&x))
+        .map(|x| x + 24 * 3600);
+
+    if let Some(year) = param("year").and_then(|x| x.parse::<i32>().ok()) {
+        let start = NaiveDate::from_ymd_opt(year, 1, 1).map(|x| x.and_hms(0, 0, 0).timestamp());
+        let end = NaiveDate::from_ymd_opt(year + 1, 1, 1).map(|x| x.and_hms(0, 0, 0).timestamp());
+        taken_from = taken_from.max(start);
+        taken_until = match (taken_until, end) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    SearchQuery {
+        text: text_query(&param("q").unwrap_or_default()),
+        camera: param("camera"),
+        lens: param("lens"),
+        tag: param("tag"),
+        taken_from,
+        taken_until,
+        bounds: param("bbox").and_then(|x| parse_bounds(&x)),
+        country: param("country"),
+        region: param("region"),
+        city: param("city"),
+    }
+}
+
+/// Runs a search described by request parameters. Only images that are
+/// currently part of the gallery tree are returned, narrowed down by rating
+/// and label like the galleries.
+pub fn search_images(
+    context: &ServerContext,
+    params: &BTreeMap<String, String>,
+) -> std::result::Result<Vec<SearchHit>, DataStoreError> {
+    let root_gallery = match context.get_root_gallery() {
+        Ok(x) => x,
+        Err(_) => return Ok(Vec::new()),
+    };
+
+    let scope: PathBuf = params.get("gallery").cloned().unwrap_or_default().into();
+    let filter = ImageFilter::from_query(params);
+    let hits = context.datastore.search_images(build_query(params))?;
+
+    // Files that were indexed more than once show up once per row
+    let mut seen = BTreeSet::new();
+
+    Ok(hits
+        .into_iter()
+        .filter_map(|(info, meta)| {
+            let path = image_relative_path(context, &info);
+            if !path.starts_with(&scope)
+                || !filter.matches(&info)
+                || !seen.insert(info.name.clone())
+            {
+                return None;
+            }
+
+            let parent = path.parent().map(|x| x.to_path_buf()).unwrap_or_default();
+            root_gallery
+                .find_image(&parent, &info.name)
+                .map(|image| SearchHit { image, meta })
+        })
+        .collect())
+}
+
+fn facet_values(name: &str, meta: &ImageMeta) -> Vec<String> {
+    match name {
+        "camera" => meta.camera.iter().cloned().collect(),
+        "lens" => meta.lens.iter().cloned().collect(),
+        "tag" => meta.tags.clone(),
+        "year" => meta
+            .taken
+            .map(|x| year_of(x).to_string())
+            .into_iter()
+            .collect(),
+        "country" => meta.place.iter().map(|x| x.country.clone()).collect(),
+        "city" => meta.place.iter().map(|x| x.city.clone()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Counts the values of each facet among the hits, most common first.
+/// Years are listed newest first instead.
+pub fn facets(hits: &[SearchHit]) -> Vec<Facet> {
+    FACETS
+        .iter()
+        .map(|&(name, label)| {
+            let mut counts = BTreeMap::new();
+            for hit in hits {
+                for value in facet_values(name, &hit.meta) {
+                    *counts.entry(value).or_insert(0) += 1;
+                }
+            }
+
+            let mut values: Vec<(String, usize)> = counts.into_iter().collect();
+            if name == "year" {
+                values.reverse();
+            } else {
+                values.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            }
+
+            Facet {
+                name,
+                label,
+                values,
+            }
+        })
+        .collect()
+}
+
+/// Links to the search page with one parameter changed, or removed if
+/// `value` is empty.
+fn search_url(params: &BTreeMap<String, String>, name: &str, value: &str) -> String {
+    let mut params = params.clone();
+    params.remove("offset");
+    params.insert(name.to_string(), value.to_string());
+
+    let query = query_string(&params);
+    if query.is_empty() {
+        "/search".to_string()
+    } else {
+        format!("/search?{}", query)
+    }
+}
+
+pub struct SearchAction {}
+
+impl SearchAction {
+    pub fn new() -> SearchAction {
+        SearchAction {}
+    }
+}
+
+impl Action for SearchAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/search$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/search.html").to_string();
+        server.register_template("search", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        _: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let params = query_params(request.url());
+
+        let hits = match search_images(&context, &params) {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Search failed"),
+        };
+
+        let facets = facets(&hits)
+            .into_iter()
+            .filter(|facet| !facet.values.is_empty())
+            .map(|facet| {
+                let selected = params.get(facet.name).cloned().unwrap_or_default();
+                let values = facet
+                    .values
+                    .iter()
+                    .map(|&(ref value, count)| {
+                        let active = *value == selected;
+                        let target = if active { "" } else { value.as_str() };
+
+                        let mut value_dict = BTreeMap::new();
+                        value_dict.insert("value".to_string(), value.to_json());
+                        value_dict.insert("count".to_string(), count.to_json());
+                        value_dict.insert("active".to_string(), active.to_json());
+                        value_dict.insert(
+                            "url".to_string(),
+                            search_url(&params, facet.name, target).to_json(),
+                        );
+                        Json::Object(value_dict)
+                    })
+                    .collect();
+
+                let mut facet_dict = BTreeMap::new();
+                facet_dict.insert("label".to_string(), facet.label.to_json());
+                facet_dict.insert("values".to_string(), Json::Array(values));
+                Json::Object(facet_dict)
+            })
+            .collect();
+
+        let filters = search_parameters()
+            .filter(|&&(name, _)| name != "q")
+            .filter_map(|&(name, label)| {
+                let value = params.get(name).filter(|x| !x.is_empty())?;
+
+                let mut filter_dict = BTreeMap::new();
+                filter_dict.insert("name".to_string(), name.to_json());
+                filter_dict.insert("label".to_string(), label.to_json());
+                filter_dict.insert("value".to_string(), value.to_json());
+                filter_dict.insert(
+                    "remove_url".to_string(),
+                    search_url(&params, name, "").to_json(),
+                );
+                Some(Json::Object(filter_dict))
+            })
+            .collect::<Vec<Json>>();
+
+        let favourites = user_favourites(&request, &context);
+        let images = hits
+            .iter()
+            .take(DEFAULT_SEARCH_LIMIT)
+            .map(|x| mark_favourite(image_to_json(&context, &x.image), &x.image, &favourites))
+            .collect();
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert(
+            "query".to_string(),
+            params.get("q").cloned().unwrap_or_default().to_json(),
+        );
+        result_dict.insert("count".to_string(), hits.len().to_json());
+        result_dict.insert(
+            "truncated".to_string(),
+            (hits.len() > DEFAULT_SEARCH_LIMIT).to_json(),
+        );
+        result_dict.insert("limit".to_string(), DEFAULT_SEARCH_LIMIT.to_json());
+        result_dict.insert(
+            "map_url".to_string(),
+            format!("/map?{}", query_string(&params)).to_json(),
+        );
+        result_dict.insert(
+            "rules".to_string(),
+            query_string(&rules_from_params(&params)).to_json(),
+        );
+        result_dict.insert(
+            "filter".to_string(),
+            ImageFilter::from_query(&params).options_to_json(),
+        );
+        result_dict.insert("has_filters".to_string(), (!filters.is_empty()).to_json());
+        result_dict.insert("filters".to_string(), Json::Array(filters));
+        result_dict.insert("facets".to_string(), Json::Array(facets));
+        result_dict.insert("images".to_string(), Json::Array(images));
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("search", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+}
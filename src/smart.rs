@@ -0,0 +1,340 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Result;
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::{Method, Request, Response, StatusCode};
+
+use crate::auth::{current_user, forbidden_response};
+use crate::context::ServerContext;
+use crate::db::{DataStoreError, SmartAlbum};
+use crate::gallery::{image_to_json, mark_favourite, page_params, user_favourites};
+use crate::manage::{manage_response, ManageError};
+use crate::search::{search_images, search_parameters, SearchHit, DEFAULT_SEARCH_LIMIT};
+use crate::web::{
+    error_response, html_response, json_response, not_found_response, query_params, query_string,
+    Action, WebServer,
+};
+
+/// Picks the rules of a smart album out of request parameters, leaving out
+/// everything else, e.g. the title.
+pub fn rules_from_params(params: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    search_parameters()
+        .filter_map(|&(name, _)| {
+            let value = params
+                .get(name)
+                .map(|x| x.trim())
+                .filter(|x| !x.is_empty())?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn album_rules(album: &SmartAlbum) -> BTreeMap<String, String> {
+    rules_from_params(&query_params(&format!("?{}", album.rules)))
+}
+
+/// Finds the images currently matching the rules of a smart album. They're
+/// evaluated anew every time, so newly indexed images show up right away.
+pub fn smart_album_hits(
+    context: &ServerContext,
+    album: &SmartAlbum,
+) -> std::result::Result<Vec<SearchHit>, DataStoreError> {
+    search_images(context, &album_rules(album))
+}
+
+/// Builds the summary of a smart album shared by the html views and the
+/// api. The first matching image serves as the cover.
+pub fn smart_album_to_json(album: &SmartAlbum, hits: &[SearchHit]) -> Json {
+    let rules = album_rules(album);
+
+    let mut album_dict = BTreeMap::new();
+    album_dict.insert("id".to_string(), album.id.to_json());
+    album_dict.insert("title".to_string(), album.title.to_json());
+    album_dict.insert("description".to_string(), album.description.to_json());
+    album_dict.insert(
+        "rules".to_string(),
+        Json::Array(
+            search_parameters()
+                .filter_map(|&(name, label)| {
+                    let value = rules.get(name)?;
+
+                    let mut rule_dict = BTreeMap::new();
+                    rule_dict.insert("name".to_string(), name.to_json());
+                    rule_dict.insert("label".to_string(), label.to_json());
+                    rule_dict.insert("value".to_string(), value.to_json());
+                    Some(Json::Object(rule_dict))
+                })
+                .collect(),
+        ),
+    );
+    album_dict.insert(
+        "search_url".to_string(),
+        format!("/search?{}", query_string(&rules)).to_json(),
+    );
+    album_dict.insert("imagecount".to_string(), hits.len().to_json());
+    album_dict.insert(
+        "cover".to_string(),
+        hits.first().map(|x| x.image.hash.clone()).to_json(),
+    );
+    Json::Object(album_dict)
+}
+
+fn create_smart_album(
+    context: &ServerContext,
+    params: &BTreeMap<String, String>,
+) -> std::result::Result<Json, ManageError> {
+    let title = params
+        .get("title")
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .ok_or(ManageError::BadRequest("No title specified"))?;
+    let rules = rules_from_params(params);
+    if rules.is_empty() {
+        return Err(ManageError::BadRequest("No rules specified"));
+    }
+
+    let mut album = SmartAlbum {
+        id: 0,
+        title,
+        description: params
+            .get("description")
+            .map(|x| x.trim().to_string())
+            .unwrap_or_default(),
+        rules: query_string(&rules),
+    };
+    album.id = context.datastore.create_smart_album(album.clone())?;
+
+    let hits = smart_album_hits(context, &album)?;
+    Ok(smart_album_to_json(&album, &hits))
+}
+
+/// Changes the title and description of a smart album, or replaces its
+/// rules with the ones given.
+fn edit_smart_album(
+    context: &ServerContext,
+    mut album: SmartAlbum,
+    operation: &str,
+    params: &BTreeMap<String, String>,
+) -> std::result::Result<Json, ManageError> {
+    if operation == "rules" {
+        let rules = rules_from_params(params);
+        if rules.is_empty() {
+            return Err(ManageError::BadRequest("No rules specified"));
+        }
+        album.rules = query_string(&rules);
+    } else {
+        if let Some(title) = params.get("title") {
+            let title = title.trim();
+            if title.is_empty() {
+                return Err(ManageError::BadRequest("Title can't be empty"));
+            }
+            album.title = title.to_string();
+        }
+        if let Some(description) = params.get("description") {
+            album.description = description.trim().to_string();
+        }
+    }
+
+    context.datastore.update_smart_album(album.clone())?;
+
+    let hits = smart_album_hits(context, &album)?;
+    Ok(smart_album_to_json(&album, &hits))
+}
+
+fn delete_smart_album(
+    context: &ServerContext,
+    album: SmartAlbum,
+) -> std::result::Result<Json, ManageError> {
+    context.datastore.delete_smart_album(album.id)?;
+
+    let mut result_dict = BTreeMap::new();
+    result_dict.insert("deleted".to_string(), album.id.to_json());
+    Ok(Json::Object(result_dict))
+}
+
+fn images_to_json(
+    context: &ServerContext,
+    hits: &[SearchHit],
+    favourites: &BTreeSet<String>,
+    offset: usize,
+    limit: usize,
+) -> Json {
+    Json::Array(
+        hits.iter()
+            .skip(offset)
+            .take(limit)
+            .map(|x| mark_favourite(image_to_json(context, &x.image), &x.image, favourites))
+            .collect(),
+    )
+}
+
+/// Shows the images of a smart album with the same grid as the galleries.
+pub struct SmartAlbumAction {}
+
+impl SmartAlbumAction {
+    pub fn new() -> SmartAlbumAction {
+        SmartAlbumAction {}
+    }
+}
+
+impl Action for SmartAlbumAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/smartalbum/([0-9]+)$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/smart_album.html").to_string();
+        server.register_template("smart_album", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let id = caps
+            .get(1)
+            .and_then(|x| x.as_str().parse::<u32>().ok())
+            .unwrap_or(0);
+        let album = match context.datastore.find_smart_album(id) {
+            Ok(Some(x)) => x,
+            Ok(None) => return not_found_response(request, "Smart album not found"),
+            Err(_) => return error_response(request, "Failed to read smart album"),
+        };
+
+        let hits = match smart_album_hits(&context, &album) {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Search failed"),
+        };
+
+        let favourites = user_favourites(&request, &context);
+        let mut result_dict = match smart_album_to_json(&album, &hits) {
+            Json::Object(x) => x,
+            _ => BTreeMap::new(),
+        };
+        result_dict.insert(
+            "images".to_string(),
+            images_to_json(&context, &hits, &favourites, 0, hits.len()),
+        );
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("smart_album", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+}
+
+/// Lists, creates and changes smart albums. Reading is open to everyone who
+/// can see the galleries, changes require a logged in user.
+pub struct ApiSmartAlbumAction {}
+
+impl ApiSmartAlbumAction {
+    pub fn new() -> ApiSmartAlbumAction {
+        ApiSmartAlbumAction {}
+    }
+}
+
+impl Action for ApiSmartAlbumAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(
+            r"^/api/v1/smartalbums/?$|^/api/v1/smartalbum/([0-9]+)(?:/(edit|rules|delete))?$",
+        )
+        .unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        let id = caps.get(1).and_then(|x| x.as_str().parse::<u32>().ok());
+        let operation = caps.get(2).map(|x| x.as_str());
+        let params = query_params(request.url());
+
+        let album = match id {
+            Some(id) => match context.datastore.find_smart_album(id) {
+                Ok(Some(x)) => Some(x),
+                Ok(None) => return not_found_response(request, "Smart album not found"),
+                Err(_) => return error_response(request, "Failed to read smart album"),
+            },
+            None => None,
+        };
+
+        if *request.method() != Method::Post {
+            if operation.is_some() {
+                let _ = request.respond(Response::empty(StatusCode(405)));
+                return Ok(());
+            }
+
+            let album = match album {
+                Some(x) => x,
+                None => {
+                    let albums = match context.datastore.find_smart_albums() {
+                        Ok(x) => x,
+                        Err(_) => return error_response(request, "Failed to read smart albums"),
+                    };
+
+                    let mut summaries = Vec::new();
+                    for album in &albums {
+                        match smart_album_hits(&context, album) {
+                            Ok(hits) => summaries.push(smart_album_to_json(album, &hits)),
+                            Err(_) => return error_response(request, "Search failed"),
+                        }
+                    }
+
+                    return json_response(request, &Json::Array(summaries));
+                }
+            };
+
+            let (offset, limit) = page_params(&params, DEFAULT_SEARCH_LIMIT);
+
+            let hits = match smart_album_hits(&context, &album) {
+                Ok(x) => x,
+                Err(_) => return error_response(request, "Search failed"),
+            };
+
+            let favourites = user_favourites(&request, &context);
+            let mut result_dict = match smart_album_to_json(&album, &hits) {
+                Json::Object(x) => x,
+                _ => BTreeMap::new(),
+            };
+            result_dict.insert("offset".to_string(), offset.to_json());
+            result_dict.insert("limit".to_string(), limit.to_json());
+            result_dict.insert(
+                "images".to_string(),
+                images_to_json(&context, &hits, &favourites, offset, limit),
+            );
+
+            return json_response(request, &Json::Object(result_dict));
+        }
+
+        if current_user(&request, &context).is_none() {
+            return forbidden_response(request, &context);
+        }
+
+        let result = match (album, operation) {
+            (None, _) => create_smart_album(&context, &params),
+            (Some(album), Some("delete")) => delete_smart_album(&context, album),
+            (Some(album), Some(operation)) => edit_smart_album(&context, album, operation, &params),
+            _ => Err(ManageError::BadRequest("Unknown operation")),
+        };
+
+        manage_response(request, result)
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use crate::context::ServerContext;
+use crate::db::ImageInfo;
+use crate::stack::Stack;
+
+/// What the images of a gallery can be ordered by, with the names used in
+/// urls and the labels shown in the gallery.
+const SORT_KEYS: &[(SortKey, &str, &str)] = &[
+    (SortKey::Name, "name", "Name"),
+    (SortKey::Taken, "taken", "Capture date"),
+    (SortKey::Modified, "modified", "Modification date"),
+    (SortKey::Rating, "rating", "Rating"),
+    (SortKey::Size, "size", "File size"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    Name,
+    Taken,
+    Modified,
+    Rating,
+    Size,
+}
+
+impl SortKey {
+    pub fn parse(name: &str) -> Option<SortKey> {
+        SORT_KEYS
+            .iter()
+            .find(|&&(_, x, _)| x == name)
+            .map(|&(key, _, _)| key)
+    }
+
+    pub fn name(&self) -> &'static str {
+        SORT_KEYS
+            .iter()
+            .find(|&&(key, _, _)| key == *self)
+            .map(|&(_, name, _)| name)
+            .unwrap_or("name")
+    }
+}
+
+/// The order of a gallery listing. Images that are equal by the key, e.g.
+/// because they have the same rating, are ordered by name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortOrder {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Default for SortOrder {
+    fn default() -> SortOrder {
+        SortOrder {
+            key: SortKey::Name,
+            descending: false,
+        }
+    }
+}
+
+impl SortOrder {
+    /// Takes the order from the `sort` and `order` parameters of a query,
+    /// falling back to `default` for those that aren't given.
+    pub fn from_query(params: &BTreeMap<String, String>, default: SortOrder) -> SortOrder {
+        SortOrder {
+            key: params
+                .get("sort")
+                .and_then(|x| SortKey::parse(x))
+                .unwrap_or(default.key),
+            descending: match params.get("order").map(|x| x.as_str()) {
+                Some("desc") => true,
+                Some("asc") => false,
+                _ => default.descending,
+            },
+        }
+    }
+
+    pub fn order_name(&self) -> &'static str {
+        if self.descending {
+            "desc"
+        } else {
+            "asc"
+        }
+    }
+
+    /// The value images are compared by. Modification times and sizes are
+    /// those recorded by the last scan.
+    fn sort_value(&self, image: &ImageInfo) -> (i64, String) {
+        let value = match self.key {
+            SortKey::Name => 0,
+            SortKey::Taken => image.taken.unwrap_or(i64::min_value()),
+            SortKey::Rating => i64::from(image.rating.unwrap_or(0)),
+            SortKey::Modified => image.modified,
+            SortKey::Size => image.size,
+        };
+
+        (value, image.name.clone())
+    }
+
+    pub fn sort_images(&self, images: &mut Vec<Arc<ImageInfo>>) {
+        images.sort_by_cached_key(|x| self.sort_value(x));
+        if self.descending {
+            images.reverse();
+        }
+    }
+
+    /// Orders stacks by their cover.
+    pub fn sort_stacks(&self, stacks: &mut Vec<Stack>) {
+        stacks.sort_by_cached_key(|x| self.sort_value(x.cover()));
+        if self.descending {
+            stacks.reverse();
+        }
+    }
+
+    pub fn to_json(&self) -> Json {
+        let mut sort_dict = BTreeMap::new();
+        sort_dict.insert("sort".to_string(), self.key.name().to_json());
+        sort_dict.insert("order".to_string(), self.order_name().to_json());
+        Json::Object(sort_dict)
+    }
+
+    /// Lists the choices of the sort form, with the current ones selected.
+    pub fn options_to_json(&self) -> Json {
+        let option = |value: &str, name: &str, selected: bool| {
+            let mut option_dict = BTreeMap::new();
+            option_dict.insert("value".to_string(), value.to_json());
+            option_dict.insert("name".to_string(), name.to_json());
+            option_dict.insert("selected".to_string(), selected.to_json());
+            Json::Object(option_dict)
+        };
+
+        let keys = SORT_KEYS
+            .iter()
+            .map(|&(key, value, name)| option(value, name, key == self.key))
+            .collect();
+        let orders = vec![
+            option("asc", "Ascending", !self.descending),
+            option("desc", "Descending", self.descending),
+        ];
+
+        let mut options_dict = BTreeMap::new();
+        options_dict.insert("keys".to_string(), Json::Array(keys));
+        options_dict.insert("orders".to_string(), Json::Array(orders));
+        Json::Object(options_dict)
+    }
+}
+
+/// Returns the order a gallery is listed in unless a request asks for
+/// another one. Galleries without a default of their own use the one of
+/// the closest parent that has one.
+pub fn gallery_sort(context: &ServerContext, path: &Path) -> SortOrder {
+    let defaults = match context.datastore.find_gallery_sorts() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Failed to read gallery sort orders: {:?}", e);
+            return SortOrder::default();
+        }
+    };
+
+    path.ancestors()
+        .filter_map(|x| x.to_str())
+        .filter_map(|x| defaults.get(x))
+        .filter_map(|&(ref key, descending)| {
+            SortKey::parse(key).map(|key| SortOrder { key, descending })
+        })
+        .next()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn image(name: &str, taken: Option<i64>, rating: Option<u32>, size: i64) -> Arc<ImageInfo> {
+        Arc::new(ImageInfo {
+            id: 0,
+            name: name.to_string(),
+            hash: String::new(),
+            width: 0,
+            height: 0,
+            img_type: String::new(),
+            taken,
+            rating,
+            label: None,
+            size,
+            modified: 0,
+        })
+    }
+
+    #[test]
+    fn test_sort_key_parse() {
+        for &(key, name, _) in SORT_KEYS {
+            assert_eq!(SortKey::parse(name), Some(key));
+            assert_eq!(key.name(), name);
+        }
+        assert_eq!(SortKey::parse("Name"), None);
+        assert_eq!(SortKey::parse(""), None);
+        assert_eq!(SortKey::parse("colour"), None);
+    }
+
+    #[test]
+    fn test_from_query() {
+        let default = SortOrder {
+            key: SortKey::Taken,
+            descending: true,
+        };
+
+        assert_eq!(SortOrder::from_query(&query(&[]), default), default);
+        assert_eq!(
+            SortOrder::from_query(&query(&[("sort", "size"), ("order", "asc")]), default),
+            SortOrder {
+                key: SortKey::Size,
+                descending: false,
+            }
+        );
+        assert_eq!(
+            SortOrder::from_query(&query(&[("sort", "rating")]), default),
+            SortOrder {
+                key: SortKey::Rating,
+                descending: true,
+            }
+        );
+
+        // Values that can't be understood keep the default
+        assert_eq!(
+            SortOrder::from_query(&query(&[("sort", "bogus"), ("order", "up")]), default),
+            default
+        );
+        assert_eq!(
+            SortOrder::from_query(&query(&[("order", "desc")]), SortOrder::default()),
+            SortOrder {
+                key: SortKey::Name,
+                descending: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_sort_images() {
+        let images = vec![
+            image("c.jpg", Some(300), Some(3), 10),
+            image("a.jpg", None, Some(3), 30),
+            image("b.jpg", Some(100), Some(5), 20),
+        ];
+        let sorted = |key: SortKey, descending: bool| {
+            let mut sorted = images.clone();
+            SortOrder { key, descending }.sort_images(&mut sorted);
+            sorted
+                .iter()
+                .map(|x| x.name.clone())
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            sorted(SortKey::Name, false),
+            vec!["a.jpg", "b.jpg", "c.jpg"]
+        );
+        assert_eq!(sorted(SortKey::Name, true), vec!["c.jpg", "b.jpg", "a.jpg"]);
+        assert_eq!(
+            sorted(SortKey::Taken, false),
+            vec!["a.jpg", "b.jpg", "c.jpg"]
+        );
+        assert_eq!(
+            sorted(SortKey::Taken, true),
+            vec!["c.jpg", "b.jpg", "a.jpg"]
+        );
+        assert_eq!(
+            sorted(SortKey::Rating, false),
+            vec!["a.jpg", "c.jpg", "b.jpg"]
+        );
+        assert_eq!(
+            sorted(SortKey::Rating, true),
+            vec!["b.jpg", "c.jpg", "a.jpg"]
+        );
+        assert_eq!(
+            sorted(SortKey::Size, false),
+            vec!["c.jpg", "b.jpg", "a.jpg"]
+        );
+    }
+}
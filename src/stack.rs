@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::db::ImageInfo;
+use crate::raw::is_raw;
+use crate::video::is_video;
+
+/// Images taken at most this many seconds apart are stacked as a burst,
+/// unless overridden with `HOSTIMG_STACK_WINDOW`.
+pub const DEFAULT_STACK_WINDOW: i64 = 2;
+
+/// Images shown as a single tile, like the RAW and JPEG files of a shot or
+/// the frames of a burst.
+pub struct Stack {
+    /// All images of the stack, the one shown for it first.
+    pub images: Vec<Arc<ImageInfo>>,
+}
+
+impl Stack {
+    pub fn cover(&self) -> &Arc<ImageInfo> {
+        &self.images[0]
+    }
+}
+
+/// The file name without its extension, which RAW files, their JPEG
+/// versions and XMP sidecars share.
+fn base_name(image: &ImageInfo) -> String {
+    Path::new(&image.name)
+        .file_stem()
+        .and_then(|x| x.to_str())
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+/// Plain images make better covers than RAW files and videos, which are
+/// shown through a preview.
+fn shown_through_preview(image: &ImageInfo) -> bool {
+    let path = Path::new(&image.name);
+    is_raw(path) || is_video(path)
+}
+
+/// Groups the images of a gallery into stacks. Files sharing a base name are
+/// stacked first, then shots of the same size taken at most `window`
+/// seconds after one another. A window of zero leaves bursts alone. Stacks are
+/// ordered by the name of their cover, as images are.
+pub fn build_stacks<'a, I>(images: I, window: i64) -> Vec<Stack>
+where
+    I: IntoIterator<Item = &'a Arc<ImageInfo>>,
+{
+    let mut by_name: BTreeMap<String, Vec<Arc<ImageInfo>>> = BTreeMap::new();
+    for image in images {
+        by_name
+            .entry(base_name(image))
+            .or_insert_with(Vec::new)
+            .push(image.clone());
+    }
+
+    let mut shots: Vec<Stack> = by_name
+        .into_iter()
+        .map(|(_, mut images)| {
+            images.sort_by(|a, b| {
+                (shown_through_preview(a), &a.name).cmp(&(shown_through_preview(b), &b.name))
+            });
+            Stack { images }
+        })
+        .collect();
+
+    let mut stacks: Vec<Stack> = Vec::new();
+    if window > 0 {
+        shots.sort_by(|a, b| {
+            (a.cover().taken, &a.cover().name).cmp(&(b.cover().taken, &b.cover().name))
+        });
+
+        // Bursts the next shot may still join, with the last shot in each.
+        // Shots are compared to the last one of a burst, so that bursts can
+        // be longer than the window, and shots of a different size taken in
+        // between don't break them up.
+        let mut open: Vec<(usize, Arc<ImageInfo>)> = Vec::new();
+        for shot in shots {
+            let cover = shot.cover().clone();
+            let taken = match cover.taken {
+                Some(x) => x,
+                None => {
+                    stacks.push(shot);
+                    continue;
+                }
+            };
+
+            open.retain(|&(_, ref last)| last.taken.map_or(false, |x| taken - x <= window));
+            match open.iter_mut().find(|&&mut (_, ref last)| {
+                last.width == cover.width && last.height == cover.height
+            }) {
+                Some(burst) => {
+                    stacks[burst.0].images.extend(shot.images);
+                    burst.1 = cover;
+                }
+                None => {
+                    open.push((stacks.len(), cover));
+                    stacks.push(shot);
+                }
+            }
+        }
+    } else {
+        stacks = shots;
+    }
+
+    stacks.sort_by(|a, b| a.cover().name.cmp(&b.cover().name));
+    stacks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(name: &str, width: u32, height: u32, taken: Option<i64>) -> Arc<ImageInfo> {
+        Arc::new(ImageInfo {
+            id: 0,
+            name: format!("/gallery/{}", name),
+            hash: name.to_string(),
+            width,
+            height,
+            img_type: String::new(),
+            taken,
+            rating: None,
+            label: None,
+            size: 0,
+            modified: 0,
+        })
+    }
+
+    fn names(stacks: &[Stack]) -> Vec<Vec<&str>> {
+        stacks
+            .iter()
+            .map(|x| x.images.iter().map(|x| x.hash.as_str()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_raw_and_jpeg() {
+        let images = vec![
+            image("IMG_1.CR2", 6000, 4000, None),
+            image("IMG_1.JPG", 6000, 4000, None),
+            image("IMG_2.JPG", 6000, 4000, None),
+            image("img_3.nef", 6000, 4000, None),
+            image("IMG_3.jpg", 6000, 4000, None),
+            image("IMG_4.MP4", 1920, 1080, None),
+        ];
+
+        assert_eq!(
+            names(&build_stacks(&images, 0)),
+            vec![
+                vec!["IMG_1.JPG", "IMG_1.CR2"],
+                vec!["IMG_2.JPG"],
+                vec!["IMG_3.jpg", "img_3.nef"],
+                vec!["IMG_4.MP4"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bursts() {
+        let images = vec![
+            image("a.jpg", 4000, 3000, Some(100)),
+            image("b.jpg", 4000, 3000, Some(101)),
+            image("c.jpg", 4000, 3000, Some(103)),
+            image("d.jpg", 4000, 3000, Some(110)),
+            image("e.jpg", 3000, 4000, Some(102)),
+            image("f.jpg", 4000, 3000, None),
+        ];
+
+        // Shots of another size taken in between don't break up a burst,
+        // which can last longer than the window
+        assert_eq!(
+            names(&build_stacks(&images, 2)),
+            vec![
+                vec!["a.jpg", "b.jpg", "c.jpg"],
+                vec!["d.jpg"],
+                vec!["e.jpg"],
+                vec!["f.jpg"],
+            ]
+        );
+
+        assert_eq!(build_stacks(&images, 0).len(), images.len());
+        assert_eq!(build_stacks(&images, 1).len(), 5);
+    }
+
+    #[test]
+    fn test_raw_pair_in_burst() {
+        let images = vec![
+            image("b.jpg", 4000, 3000, Some(101)),
+            image("a.cr2", 4000, 3000, Some(100)),
+            image("a.jpg", 4000, 3000, Some(100)),
+        ];
+
+        assert_eq!(
+            names(&build_stacks(&images, 2)),
+            vec![vec!["a.jpg", "a.cr2", "b.jpg"]]
+        );
+
+        let empty: Vec<Arc<ImageInfo>> = Vec::new();
+        assert!(build_stacks(&empty, 2).is_empty());
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Result;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use chrono::prelude::*;
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::Request;
+
+use crate::context::ServerContext;
+use crate::db::ImageInfo;
+use crate::gallery::{image_to_json, mark_favourite, user_favourites, GALLERY_PAGE_SIZE};
+use crate::web::{error_response, html_response, not_found_response, Action, WebServer};
+
+/// A year, month or day of the timeline, or all of it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    All,
+    Year(i32),
+    Month(i32, u32),
+    Day(NaiveDate),
+}
+
+impl Period {
+    /// Parses the year, month and day parts of a timeline url.
+    pub fn from_captures(caps: &Captures) -> Option<Period> {
+        let part = |i| caps.get(i).map(|x| x.as_str().parse::<u32>());
+
+        match (part(1), part(2), part(3)) {
+            (None, None, None) => Some(Period::All),
+            (Some(Ok(year)), None, None) => Some(Period::Year(year as i32)),
+            (Some(Ok(year)), Some(Ok(month)), None) => {
+                NaiveDate::from_ymd_opt(year as i32, month, 1)?;
+                Some(Period::Month(year as i32, month))
+            }
+            (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) => {
+                NaiveDate::from_ymd_opt(year as i32, month, day).map(Period::Day)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the days of the period, which for all of the timeline has no
+    /// bounds at all.
+    fn range(&self) -> (Bound<NaiveDate>, Bound<NaiveDate>) {
+        let (start, end) = match *self {
+            Period::All => return (Bound::Unbounded, Bound::Unbounded),
+            Period::Year(year) => (
+                NaiveDate::from_ymd(year, 1, 1),
+                NaiveDate::from_ymd(year + 1, 1, 1),
+            ),
+            Period::Month(year, month) => {
+                let start = NaiveDate::from_ymd(year, month, 1);
+                let end = if month == 12 {
+                    NaiveDate::from_ymd(year + 1, 1, 1)
+                } else {
+                    NaiveDate::from_ymd(year, month + 1, 1)
+                };
+                (start, end)
+            }
+            Period::Day(day) => (day, day.succ()),
+        };
+
+        (Bound::Included(start), Bound::Excluded(end))
+    }
+
+    /// Returns the period one level down which contains `day`.
+    fn child(&self, day: NaiveDate) -> Option<Period> {
+        match *self {
+            Period::All => Some(Period::Year(day.year())),
+            Period::Year(_) => Some(Period::Month(day.year(), day.month())),
+            Period::Month(_, _) => Some(Period::Day(day)),
+            Period::Day(_) => None,
+        }
+    }
+
+    pub fn parent(&self) -> Option<Period> {
+        match *self {
+            Period::All => None,
+            Period::Year(_) => Some(Period::All),
+            Period::Month(year, _) => Some(Period::Year(year)),
+            Period::Day(day) => Some(Period::Month(day.year(), day.month())),
+        }
+    }
+
+    /// The period as used in urls, e.g. "2017/06".
+    pub fn path(&self) -> String {
+        match *self {
+            Period::All => String::new(),
+            Period::Year(year) => format!("{:04}", year),
+            Period::Month(year, month) => format!("{:04}/{:02}", year, month),
+            Period::Day(day) => day.format("%Y/%m/%d").to_string(),
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match *self {
+            Period::All => "All years".to_string(),
+            Period::Year(year) => year.to_string(),
+            Period::Month(year, month) => NaiveDate::from_ymd(year, month, 1)
+                .format("%B %Y")
+                .to_string(),
+            Period::Day(day) => format!("{} {}", day.day(), day.format("%B %Y")),
+        }
+    }
+}
+
+/// All images of the gallery tree, grouped by the day they were taken.
+/// Images whose capture time isn't known yet are left out.
+pub struct Timeline {
+    days: BTreeMap<NaiveDate, BTreeSet<(i64, Arc<ImageInfo>)>>,
+    taken: BTreeMap<String, i64>,
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline {
+            days: BTreeMap::new(),
+            taken: BTreeMap::new(),
+        }
+    }
+
+    pub fn from_images(images: Vec<Arc<ImageInfo>>) -> Timeline {
+        let mut timeline = Timeline::new();
+        for image in images {
+            timeline.add(image);
+        }
+
+        timeline
+    }
+
+    fn day_of(taken: i64) -> NaiveDate {
+        NaiveDateTime::from_timestamp(taken, 0).date()
+    }
+
+    /// Adds an image, replacing any earlier entry for the same file.
+    pub fn add(&mut self, image: Arc<ImageInfo>) {
+        self.remove(&image);
+
+        let taken = match image.taken {
+            Some(x) => x,
+            None => return,
+        };
+
+        self.taken.insert(image.name.clone(), taken);
+        self.days
+            .entry(Timeline::day_of(taken))
+            .or_insert_with(BTreeSet::new)
+            .insert((taken, image));
+    }
+
+    pub fn remove(&mut self, image: &ImageInfo) {
+        let taken = match self.taken.remove(&image.name) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let day = Timeline::day_of(taken);
+        let is_empty = match self.days.get_mut(&day) {
+            Some(images) => {
+                images.retain(|x| x.1.name != image.name);
+                images.is_empty()
+            }
+            None => false,
+        };
+
+        if is_empty {
+            self.days.remove(&day);
+        }
+    }
+
+    /// Counts the images of each period one level below `period`, in
+    /// chronological order.
+    pub fn buckets(&self, period: Period) -> Vec<(Period, usize)> {
+        let mut buckets: Vec<(Period, usize)> = Vec::new();
+        for (day, images) in self.days.range(period.range()) {
+            let child = match period.child(*day) {
+                Some(x) => x,
+                None => break,
+            };
+
+            match buckets.last_mut() {
+                Some(ref mut last) if last.0 == child => last.1 += images.len(),
+                _ => buckets.push((child, images.len())),
+            }
+        }
+
+        buckets
+    }
+
+    /// Returns the images taken within a period, in chronological order.
+    pub fn images(&self, period: Period) -> Vec<Arc<ImageInfo>> {
+        self.days
+            .range(period.range())
+            .flat_map(|(_, images)| images.iter().map(|x| x.1.clone()))
+            .collect()
+    }
+}
+
+/// Links to a page of the images of a period in the api.
+pub fn timeline_page_url(period: Period, offset: usize, limit: usize) -> String {
+    format!(
+        "/api/v1/timeline/{}?offset={}&limit={}",
+        period.path(),
+        offset,
+        limit
+    )
+}
+
+/// Describes the periods below `period` for the html views and the api.
+pub fn buckets_to_json(timeline: &Timeline, period: Period) -> Json {
+    Json::Array(
+        timeline
+            .buckets(period)
+            .into_iter()
+            .map(|(child, count)| {
+                let mut bucket_dict = BTreeMap::new();
+                bucket_dict.insert("path".to_string(), child.path().to_json());
+                bucket_dict.insert("label".to_string(), child.label().to_json());
+                bucket_dict.insert("count".to_string(), count.to_json());
+                Json::Object(bucket_dict)
+            })
+            .collect(),
+    )
+}
+
+pub struct TimelineAction {}
+
+impl TimelineAction {
+    pub fn new() -> TimelineAction {
+        TimelineAction {}
+    }
+}
+
+impl Action for TimelineAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/timeline/?$|^/timeline/([0-9]{4})(?:/([0-9]{2}))?(?:/([0-9]{2}))?/?$")
+            .unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/timeline.html").to_string();
+        server.register_template("timeline", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let period = match Period::from_captures(caps) {
+            Some(x) => x,
+            None => return not_found_response(request, "Invalid date"),
+        };
+
+        let timeline = match context.timeline.read() {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to access timeline"),
+        };
+
+        // Like in the gallery page only the first images are listed, the
+        // rest are loaded from the api while scrolling
+        let favourites = user_favourites(&request, &context);
+        let images = timeline.images(period);
+        let page: Vec<Json> = images
+            .iter()
+            .take(GALLERY_PAGE_SIZE)
+            .map(|x| mark_favourite(image_to_json(&context, x), x, &favourites))
+            .collect();
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("label".to_string(), period.label().to_json());
+        result_dict.insert("count".to_string(), images.len().to_json());
+        if let Some(parent) = period.parent() {
+            result_dict.insert("has_parent".to_string(), true.to_json());
+            result_dict.insert("parent".to_string(), parent.path().to_json());
+        }
+        result_dict.insert("buckets".to_string(), buckets_to_json(&timeline, period));
+        result_dict.insert("images".to_string(), Json::Array(page));
+        if images.len() > GALLERY_PAGE_SIZE {
+            result_dict.insert(
+                "next_url".to_string(),
+                timeline_page_url(period, GALLERY_PAGE_SIZE, GALLERY_PAGE_SIZE).to_json(),
+            );
+        }
+        let result_obj = Json::Object(result_dict);
+
+        drop(timeline);
+
+        let html_data = match handlebars.render("timeline", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+}
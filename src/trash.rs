@@ -0,0 +1,281 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Result};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration as StdDuration;
+
+use chrono::prelude::*;
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::{Header, HeaderField, Method, Request, Response, StatusCode};
+
+use crate::auth::{current_user, forbidden_response};
+use crate::context::ServerContext;
+use crate::db::{ImageInfo, TrashEntry};
+use crate::file::{file_stats, GalleryModification};
+use crate::format::OutputFormat;
+use crate::gallery::image_to_json;
+use crate::manage::{image_gallery_path, manage_response, move_file, move_sidecar, ManageError};
+use crate::metadata::index_metadata;
+use crate::resize::remove_resized_images;
+use crate::upload::target_file;
+use crate::web::{error_response, html_response, not_found_response, Action, WebServer};
+use crate::xmp::own_sidecar;
+
+/// Number of days deleted images are kept, unless overridden with
+/// `HOSTIMG_TRASH_RETENTION_DAYS`.
+pub const DEFAULT_TRASH_RETENTION_DAYS: i64 = 30;
+
+fn format_timestamp(timestamp: i64) -> String {
+    NaiveDateTime::from_timestamp(timestamp, 0)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+fn entry_to_json(context: &ServerContext, entry: &TrashEntry) -> Json {
+    let mut entry_dict = match image_to_json(context, &entry.image) {
+        Json::Object(x) => x,
+        _ => BTreeMap::new(),
+    };
+
+    let purge_at = entry.deleted_at + context.trash_retention_days * 24 * 3600;
+    entry_dict.insert("id".to_string(), entry.id.to_json());
+    entry_dict.insert(
+        "deleted_at".to_string(),
+        format_timestamp(entry.deleted_at).to_json(),
+    );
+    entry_dict.insert("deleted_by".to_string(), entry.deleted_by.to_json());
+    entry_dict.insert("purge_at".to_string(), format_timestamp(purge_at).to_json());
+    Json::Object(entry_dict)
+}
+
+/// Moves a trashed image back to where it was deleted from. If another file
+/// has taken its place in the meantime, a counter is appended to the name.
+fn restore_image(
+    context: &ServerContext,
+    entry: TrashEntry,
+) -> std::result::Result<Arc<ImageInfo>, ManageError> {
+    let source = context.trash_dir.join(&entry.trash_file);
+    let original = PathBuf::from(&entry.image.name);
+
+    let dir = match original.parent() {
+        Some(x) if x.starts_with(&context.gallery_dir) => x,
+        _ => return Err(ManageError::Conflict("Image is not from this gallery")),
+    };
+    let file_name = original
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or(ManageError::BadRequest("Invalid file name"))?;
+
+    fs::create_dir_all(dir)?;
+    let target = target_file(dir, file_name).ok_or(ManageError::BadRequest("Invalid file name"))?;
+    let name = target
+        .to_str()
+        .ok_or(ManageError::BadRequest("Invalid file name"))?
+        .to_string();
+
+    // Each step is undone if a later one fails, so that the image is either
+    // in the library or in the trash
+    move_file(&source, &target)?;
+    context.suppress_watcher(&target);
+    move_sidecar(context, &source, &target);
+    let move_back = || {
+        if move_file(&target, &source).is_err() {
+            eprintln!("Failed to move {:?} back to trash", target);
+        }
+        move_sidecar(context, &target, &source);
+    };
+
+    let (size, modified) = file_stats(&target);
+    let id = match context
+        .datastore
+        .restore_image(entry.id, name.clone(), size, modified)
+    {
+        Ok(x) => x,
+        Err(e) => {
+            move_back();
+            return Err(e.into());
+        }
+    };
+
+    let mut info = entry.image.clone();
+    info.id = id;
+    info.name = name;
+    info.size = size;
+    info.modified = modified;
+
+    let put_back = |e: ManageError| {
+        let mut image = entry.image.clone();
+        image.id = id;
+        let trashed = context.datastore.trash_image(TrashEntry {
+            id: 0,
+            image,
+            trash_file: entry.trash_file.clone(),
+            deleted_at: entry.deleted_at,
+            deleted_by: entry.deleted_by.clone(),
+        });
+        if trashed.is_err() {
+            eprintln!("Failed to put {:?} back in the trash", target);
+        }
+        move_back();
+        e
+    };
+
+    let meta = index_metadata(context, &info).map_err(|e| put_back(e.into()))?;
+    info.set_meta(&meta);
+    let info = Arc::new(info);
+
+    let parent = image_gallery_path(context, &info);
+    context
+        .modify_root_gallery(&parent, GalleryModification::Add(info.clone()))
+        .map_err(|e| put_back(e.into()))?;
+
+    println!("Restored {:?} from trash", target);
+
+    Ok(info)
+}
+
+/// Permanently removes images that have been in the trash for longer than
+/// the retention period. Thumbnails and previews go too, and the image is
+/// taken out of albums, unless another image still has the same content.
+pub fn purge_expired_trash(context: &ServerContext) -> std::result::Result<(), ManageError> {
+    let before = UTC::now().timestamp() - context.trash_retention_days * 24 * 3600;
+    for entry in context.datastore.find_expired_trash(before)? {
+        let file = context.trash_dir.join(&entry.trash_file);
+        if let Err(e) = fs::remove_file(&file) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+        let _ = fs::remove_file(own_sidecar(&file));
+
+        context.datastore.delete_trash_entry(entry.id)?;
+
+        if !context.datastore.is_hash_in_use(entry.image.hash.clone())? {
+            for format in &[OutputFormat::Jpeg, OutputFormat::Webp, OutputFormat::Avif] {
+                let derivative = format!("{}.{}", entry.image.hash, format.extension());
+                let _ = fs::remove_file(context.thumb_dir.join(&derivative));
+                let _ = fs::remove_file(context.preview_dir.join(&derivative));
+            }
+            let _ = remove_resized_images(context, &entry.image.hash);
+            context
+                .datastore
+                .remove_hash_from_albums(entry.image.hash.clone())?;
+            context
+                .datastore
+                .remove_hash_from_favourites(entry.image.hash.clone())?;
+        }
+
+        println!("Purged {} from trash", entry.image.name);
+    }
+
+    Ok(())
+}
+
+/// Starts a background thread that empties the trash of expired images
+/// once an hour.
+pub fn start_trash_reaper(context: ServerContext) {
+    thread::spawn(move || loop {
+        if let Err(e) = purge_expired_trash(&context) {
+            eprintln!("Failed to purge trash: {:?}", e);
+        }
+
+        thread::sleep(StdDuration::from_secs(3600));
+    });
+}
+
+pub struct TrashAction {}
+
+impl TrashAction {
+    pub fn new() -> TrashAction {
+        TrashAction {}
+    }
+
+    fn list(
+        &self,
+        request: Request,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        let entries = match context.datastore.find_trash() {
+            Ok(x) => x,
+            Err(_) => return error_response(request, "Failed to read trash"),
+        };
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("has_entries".to_string(), (!entries.is_empty()).to_json());
+        result_dict.insert(
+            "entries".to_string(),
+            Json::Array(entries.iter().map(|x| entry_to_json(&context, x)).collect()),
+        );
+        result_dict.insert(
+            "retention_days".to_string(),
+            context.trash_retention_days.to_json(),
+        );
+        let result_obj = Json::Object(result_dict);
+
+        let html_data = match handlebars.render("trash", &result_obj).ok() {
+            Some(x) => x,
+            None => return error_response(request, "Failed to encode response"),
+        };
+
+        html_response(request, html_data)
+    }
+
+    fn restore(&self, request: Request, id: u32, context: ServerContext) -> Result<()> {
+        if *request.method() != Method::Post {
+            let _ = request.respond(Response::empty(StatusCode(405)));
+            return Ok(());
+        }
+
+        if current_user(&request, &context).is_none() {
+            return forbidden_response(request, &context);
+        }
+
+        let entry = match context.datastore.find_trash_entry(id) {
+            Ok(Some(x)) => x,
+            Ok(None) => return not_found_response(request, "Trash entry not found"),
+            Err(_) => return error_response(request, "Failed to read trash"),
+        };
+
+        if let Err(e) = restore_image(&context, entry) {
+            return manage_response(request, Err(e));
+        }
+
+        let mut response = Response::empty(StatusCode(303));
+        response.add_header(Header {
+            field: "Location".parse::<HeaderField>().unwrap(),
+            value: "/trash".parse().unwrap(),
+        });
+        request.respond(response)
+    }
+}
+
+impl Action for TrashAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/trash/?$|^/trash/([0-9]+)/restore$").unwrap()
+    }
+
+    fn initialize(&self, server: &mut WebServer) -> Result<()> {
+        let tpl_data = include_str!("templates/trash.html").to_string();
+        server.register_template("trash", tpl_data);
+
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        handlebars: Arc<Handlebars>,
+    ) -> Result<()> {
+        match caps.get(1).and_then(|x| x.as_str().parse::<u32>().ok()) {
+            Some(id) => self.restore(request, id, context),
+            None => self.list(request, context, handlebars),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Result, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use handlebars::Handlebars;
+use regex::{Captures, Regex};
+use rustc_serialize::json::{Json, ToJson};
+use tiny_http::{Method, Request, Response, StatusCode};
+
+use crate::api::find_image_by_hash;
+use crate::auth::{current_user, forbidden_response};
+use crate::context::ServerContext;
+use crate::file::{hash_reader, is_media};
+use crate::gallery::image_relative_path;
+use crate::web::{
+    error_response, json_response, not_found_response, url_decode, Action, WebServer,
+};
+
+/// Largest request body accepted by the plain upload endpoint. Bigger files
+/// should go through the resumable upload protocol.
+pub const MAX_UPLOAD_SIZE: usize = 256 * 1024 * 1024;
+
+/// Resolves a gallery path relative to the gallery directory into an
+/// existing directory on disk, refusing anything that would escape it.
+pub fn gallery_directory(context: &ServerContext, gallery: &str) -> Option<PathBuf> {
+    let relative = Path::new(gallery);
+    if relative
+        .components()
+        .any(|x| !matches!(x, Component::Normal(_)))
+    {
+        return None;
+    }
+
+    let mut dir = context.gallery_dir.clone();
+    dir.push(relative);
+
+    if dir.is_dir() {
+        Some(dir)
+    } else {
+        None
+    }
+}
+
+/// Picks a path in `dir` for a file with the given client supplied name,
+/// stripping any directory components and appending a counter if a file
+/// with that name exists already.
+pub fn target_file(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    let base_name = Path::new(file_name.rsplit(|c| c == '/' || c == '\\').next()?);
+    if !is_media(base_name) {
+        return None;
+    }
+
+    let stem = base_name.file_stem().and_then(|x| x.to_str())?;
+    let extension = base_name.extension().and_then(|x| x.to_str())?;
+    if stem.is_empty() || stem.starts_with('.') {
+        return None;
+    }
+
+    let mut candidate = dir.join(base_name);
+    let mut counter = 1;
+    while candidate.exists() {
+        candidate = dir.join(format!("{} ({}).{}", stem, counter, extension));
+        counter += 1;
+    }
+
+    Some(candidate)
+}
+
+/// Returns the path of an indexed image with the same content, if any.
+pub fn find_duplicate(context: &ServerContext, hash: &str) -> Option<PathBuf> {
+    find_image_by_hash(context, hash).map(|x| image_relative_path(context, &x))
+}
+
+/// Hands a newly written file to the indexing thread, so that it shows up
+/// without waiting for the file system watcher.
+pub fn enqueue(context: &ServerContext, path: PathBuf) -> io::Result<()> {
+    context
+        .indexing_queue
+        .send(path)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Indexing thread has stopped"))
+}
+
+struct MultipartFile<'a> {
+    file_name: String,
+    data: &'a [u8],
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8], start: usize) -> Option<usize> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+
+    (start..haystack.len() - needle.len() + 1).find(|&i| &haystack[i..i + needle.len()] == needle)
+}
+
+/// Extracts a parameter such as `boundary` or `filename` from a header value
+/// of the form `type; key=value; key="value"`.
+fn header_param(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|param| {
+        let mut parts = param.trim().splitn(2, '=');
+        let key = parts.next()?.trim();
+        let value = parts.next()?.trim().trim_matches('"');
+        if key.eq_ignore_ascii_case(name) {
+            Some(value.to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Splits a `multipart/form-data` body into the files it contains. Parts
+/// that aren't files are ignored.
+fn parse_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<MultipartFile<'a>> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let separator = format!("\r\n--{}", boundary).into_bytes();
+
+    let mut files = Vec::new();
+    let mut pos = match find_bytes(body, &delimiter, 0) {
+        Some(x) => x + delimiter.len(),
+        None => return files,
+    };
+
+    loop {
+        // The final delimiter is followed by "--"
+        if body[pos..].starts_with(b"--") {
+            break;
+        }
+
+        let headers_start = pos + 2;
+        let headers_end = match find_bytes(body, b"\r\n\r\n", headers_start) {
+            Some(x) => x,
+            None => break,
+        };
+        let data_end = match find_bytes(body, &separator, headers_end + 4) {
+            Some(x) => x,
+            None => break,
+        };
+
+        let headers = String::from_utf8_lossy(&body[headers_start..headers_end]);
+        let file_name = headers
+            .lines()
+            .filter(|x| x.to_ascii_lowercase().starts_with("content-disposition:"))
+            .find_map(|x| header_param(x, "filename"));
+
+        if let Some(file_name) = file_name {
+            files.push(MultipartFile {
+                file_name,
+                data: &body[headers_end + 4..data_end],
+            });
+        }
+
+        pos = data_end + separator.len();
+    }
+
+    files
+}
+
+pub struct UploadAction {}
+
+impl UploadAction {
+    pub fn new() -> UploadAction {
+        UploadAction {}
+    }
+}
+
+impl Action for UploadAction {
+    fn get_regex(&self) -> Regex {
+        Regex::new(r"^/upload/?$|^/upload/(.*)$").unwrap()
+    }
+
+    fn initialize(&self, _: &mut WebServer) -> Result<()> {
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        mut request: Request,
+        caps: &Captures,
+        context: ServerContext,
+        _: Arc<Handlebars>,
+    ) -> Result<()> {
+        if *request.method() != Method::Post {
+            let _ = request.respond(Response::empty(StatusCode(405)));
+            return Ok(());
+        }
+
+        if current_user(&request, &context).is_none() {
+            return forbidden_response(request, &context);
+        }
+
+        let gallery = caps
+            .get(1)
+            .map(|x| url_decode(x.as_str().trim_end_matches('/')))
+            .unwrap_or_default();
+
+        let dir = match gallery_directory(&context, &gallery) {
+            Some(x) => x,
+            None => return not_found_response(request, "Gallery not found"),
+        };
+
+        let boundary = request
+            .headers()
+            .iter()
+            .find(|x| x.field.equiv("Content-Type"))
+            .and_then(|x| header_param(x.value.as_str(), "boundary"));
+        let boundary = match boundary {
+            Some(x) => x,
+            None => return error_response(request, "Expected a multipart/form-data body"),
+        };
+
+        if request.body_length().unwrap_or(0) > MAX_UPLOAD_SIZE {
+            let _ = request.respond(Response::empty(StatusCode(413)));
+            return Ok(());
+        }
+
+        // Chunked bodies don't say how long they are, so one byte more than
+        // allowed is read to tell whether they're too long
+        let mut body = Vec::new();
+        request
+            .as_reader()
+            .take(MAX_UPLOAD_SIZE as u64 + 1)
+            .read_to_end(&mut body)?;
+        if body.len() > MAX_UPLOAD_SIZE {
+            let _ = request.respond(Response::empty(StatusCode(413)));
+            return Ok(());
+        }
+
+        let mut uploaded = Vec::new();
+        let mut duplicates = Vec::new();
+        let mut rejected = Vec::new();
+        let mut received = BTreeMap::new();
+        for file in parse_multipart(&body, &boundary) {
+            let mut file_dict = BTreeMap::new();
+            file_dict.insert("name".to_string(), file.file_name.to_json());
+
+            let target = match target_file(&dir, &file.file_name) {
+                Some(x) => x,
+                None => {
+                    file_dict.insert("reason".to_string(), "Unsupported file".to_json());
+                    rejected.push(Json::Object(file_dict));
+                    continue;
+                }
+            };
+
+            let hash = hash_reader(file.data)?;
+            file_dict.insert("hash".to_string(), hash.to_json());
+
+            // Files from this request aren't indexed yet, so they're
+            // checked separately from the ones in the datastore.
+            let existing = find_duplicate(&context, &hash).or_else(|| received.get(&hash).cloned());
+            if let Some(existing) = existing {
+                file_dict.insert(
+                    "existing".to_string(),
+                    existing.to_str().unwrap_or("").to_json(),
+                );
+                duplicates.push(Json::Object(file_dict));
+                continue;
+            }
+
+            // A file that can't be written in full is removed again, so that
+            // it isn't indexed half way
+            let written = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&target)
+                .and_then(|mut output| {
+                    output.write_all(file.data).map_err(|e| {
+                        let _ = fs::remove_file(&target);
+                        e
+                    })
+                });
+            if let Err(e) = written {
+                eprintln!("Failed to write upload {:?}: {:?}", target, e);
+                file_dict.insert("reason".to_string(), "Failed to write file".to_json());
+                rejected.push(Json::Object(file_dict));
+                continue;
+            }
+
+            println!("Received upload {:?}", target);
+
+            let relative = target
+                .strip_prefix(&context.gallery_dir)
+                .unwrap_or(&target)
+                .to_path_buf();
+            file_dict.insert(
+                "path".to_string(),
+                relative.to_str().unwrap_or("").to_json(),
+            );
+            received.insert(hash, relative);
+
+            if let Err(e) = enqueue(&context, target.clone()) {
+                eprintln!("Failed to index upload {:?}: {:?}", target, e);
+            }
+            uploaded.push(Json::Object(file_dict));
+        }
+
+        let mut result_dict = BTreeMap::new();
+        result_dict.insert("gallery".to_string(), gallery.to_json());
+        result_dict.insert("uploaded".to_string(), Json::Array(uploaded));
+        result_dict.insert("duplicates".to_string(), Json::Array(duplicates));
+        result_dict.insert("rejected".to_string(), Json::Array(rejected));
+
+        json_response(request, &Json::Object(result_dict))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_param() {
+        let value = "multipart/form-data; boundary=----abc123";
+        assert_eq!(
+            header_param(value, "boundary"),
+            Some("----abc123".to_string())
+        );
+
+        let value = r#"form-data; name="file"; FILENAME="a b.jpg""#;
+        assert_eq!(header_param(value, "filename"), Some("a b.jpg".to_string()));
+        assert_eq!(header_param(value, "name"), Some("file".to_string()));
+        assert_eq!(header_param(value, "boundary"), None);
+        assert_eq!(header_param("form-data", "name"), None);
+    }
+
+    #[test]
+    fn test_parse_multipart() {
+        let body = b"preamble\r\n--XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.jpg\"\r\n\
+            Content-Type: image/jpeg\r\n\
+            \r\n\
+            JPEGDATA\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"gallery\"\r\n\
+            \r\n\
+            holiday\r\n\
+            --XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"b.jpg\"\r\n\
+            \r\n\
+            line1 --XyZ\r\nline2\r\n\
+            --XyZ--\r\n";
+
+        let files = parse_multipart(body, "XyZ");
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].file_name, "a.jpg");
+        assert_eq!(files[0].data, b"JPEGDATA");
+        assert_eq!(files[1].file_name, "b.jpg");
+        assert_eq!(files[1].data, b"line1 --XyZ\r\nline2");
+    }
+
+    #[test]
+    fn test_parse_multipart_truncated() {
+        let body = b"--XyZ\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"a.jpg\"\r\n\
+            \r\n\
+            JPEG";
+        assert!(parse_multipart(body, "XyZ").is_empty());
+        assert!(parse_multipart(b"no delimiter here", "XyZ").is_empty());
+        assert!(parse_multipart(b"--XyZ", "XyZ").is_empty());
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use rustc_serialize::json::Json;
+
+/// Containers of the videos phones and cameras record, with the media type
+/// they're served as.
+const VIDEO_TYPES: &[(&str, &str)] = &[
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mov", "video/quicktime"),
+    ("3gp", "video/3gpp"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("avi", "video/x-msvideo"),
+];
+
+/// Clips longer than this get their poster frame from this far in, past
+/// the blurry or black frames many start with.
+const POSTER_OFFSET: f64 = 1.0;
+
+/// What ffprobe reports about a video.
+pub struct VideoProbe {
+    /// Length in seconds.
+    pub duration: Option<f64>,
+    /// Tags of the container, e.g. `creation_time`, by lower case name.
+    pub tags: BTreeMap<String, String>,
+}
+
+pub fn content_type(file: &Path) -> Option<&'static str> {
+    let extension = file.extension()?.to_str()?.to_ascii_lowercase();
+    VIDEO_TYPES.iter().find(|x| x.0 == extension).map(|x| x.1)
+}
+
+pub fn is_video(file: &Path) -> bool {
+    content_type(file).is_some()
+}
+
+/// Checks whether ffmpeg and ffprobe are installed, which reading videos
+/// requires.
+pub fn ffmpeg_available() -> bool {
+    ["ffmpeg", "ffprobe"].iter().all(|program| {
+        Command::new(program)
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|x| x.success())
+            .unwrap_or(false)
+    })
+}
+
+pub fn probe(file: &Path) -> io::Result<VideoProbe> {
+    let output = Command::new("ffprobe")
+        .args(&["-v", "error", "-print_format", "json", "-show_format"])
+        .arg(file)
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("ffprobe failed with {}", output.status),
+        ));
+    }
+
+    let json = Json::from_str(&String::from_utf8_lossy(&output.stdout))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("{:?}", e)))?;
+    let format = json.find("format");
+
+    // ffprobe reports numbers as strings
+    let duration = format
+        .and_then(|x| x.find("duration"))
+        .and_then(|x| x.as_string())
+        .and_then(|x| x.parse::<f64>().ok());
+
+    let tags = format
+        .and_then(|x| x.find("tags"))
+        .and_then(|x| x.as_object())
+        .map(|x| {
+            x.iter()
+                .filter_map(|(k, v)| Some((k.to_lowercase(), v.as_string()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(VideoProbe { duration, tags })
+}
+
+/// Extracts a frame to stand in for a video as an image. ffmpeg applies the
+/// rotation recorded by phones, so the frame is upright. `output` must end
+/// in `.jpg`.
+pub fn poster_frame(file: &Path, duration: Option<f64>, output: &Path) -> io::Result<()> {
+    let offset = match duration {
+        Some(x) if x > 2.0 * POSTER_OFFSET => POSTER_OFFSET,
+        _ => 0.0,
+    };
+
+    let status = Command::new("ffmpeg")
+        .args(&["-v", "error", "-y", "-ss", &offset.to_string(), "-i"])
+        .arg(file)
+        .args(&["-frames:v", "1", "-q:v", "2"])
+        .arg(output)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() || !output.exists() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("ffmpeg failed with {}", status),
+        ));
+    }
+
+    Ok(())
+}
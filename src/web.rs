@@ -1,11 +1,17 @@
+use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind, Result};
 use std::sync::Arc;
 use std::thread;
 
+use chrono::prelude::*;
 use handlebars::Handlebars;
 use regex::{Captures, Regex};
-use tiny_http::{Request, Response, Server, StatusCode};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+use sha2::Digest;
+use tiny_http::{Header, HeaderField, Method, Request, Response, Server, StatusCode};
 
+use crate::auth;
 use crate::context::ServerContext;
 
 fn hex_to_num(c: char) -> u8 {
@@ -22,14 +28,13 @@ pub fn url_decode(instr: &str) -> String {
 
     let mut pos = 0;
     let len = instr.len();
-    let mut buffer = String::new();
+    let mut buffer = Vec::new();
     while pos < len {
-        let cur = src_buffer[pos] as char;
-        if cur == '%' {
+        let cur = src_buffer[pos];
+        if cur == b'%' && pos + 2 < len {
             let a = hex_to_num(src_buffer[pos + 1] as char);
             let b = hex_to_num(src_buffer[pos + 2] as char);
-            let new_char = ((a << 4) | b) as char;
-            buffer.push(new_char);
+            buffer.push((a << 4) | b);
             pos += 2;
         } else {
             buffer.push(cur);
@@ -38,7 +43,64 @@ pub fn url_decode(instr: &str) -> String {
         pos += 1;
     }
 
-    buffer
+    String::from_utf8_lossy(&buffer).into_owned()
+}
+
+/// Percent encodes everything but unreserved characters, for use in a path
+/// segment or a query string.
+pub fn url_encode(instr: &str) -> String {
+    instr
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Builds a query string from a set of parameters, leaving out empty ones.
+pub fn query_string(params: &BTreeMap<String, String>) -> String {
+    params
+        .iter()
+        .filter(|&(_, value)| !value.is_empty())
+        .map(|(key, value)| format!("{}={}", url_encode(key), url_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+pub fn get_header(request: &Request, field: &'static str) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|x| x.field.equiv(field))
+        .map(|x| x.value.as_str().to_string())
+}
+
+/// Returns the path component of a request url, without the query string.
+pub fn url_path(url: &str) -> &str {
+    url.splitn(2, '?').next().unwrap_or("")
+}
+
+/// Parses the query string of a request url into a map of decoded keys and
+/// values. Repeated keys keep the last value.
+pub fn query_params(url: &str) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+
+    let query = match url.splitn(2, '?').nth(1) {
+        Some(x) => x,
+        None => return params,
+    };
+
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next().unwrap_or("").replace('+', " ");
+        let value = parts.next().unwrap_or("").replace('+', " ");
+        params.insert(url_decode(&key), url_decode(&value));
+    }
+
+    params
 }
 
 pub trait Action {
@@ -77,6 +139,9 @@ impl WebServer {
         let tpl_data = include_str!("templates/layout.html").to_string();
         server.register_template("layout", tpl_data);
 
+        let tpl_data = include_str!("templates/images.html").to_string();
+        server.register_template("images", tpl_data);
+
         Ok(server)
     }
 
@@ -136,9 +201,19 @@ impl WebServer {
                 println!("HTTP {:?} {:?}", request.method(), request.url());
 
                 let context = context.clone();
+                if !auth::is_allowed(&request, &context) {
+                    let _ = auth::unauthorized_response(request);
+                    continue;
+                }
+                if !auth::is_same_origin(&request) {
+                    let _ = auth::cross_origin_response(request);
+                    continue;
+                }
+
+                let path = url_path(request.url()).to_string();
                 let matching_actions: Vec<Arc<ThreadsafeAction>> = actions
                     .iter()
-                    .filter(|x| x.get_regex().is_match(&request.url()))
+                    .filter(|x| x.get_regex().is_match(&path))
                     .cloned()
                     .collect();
 
@@ -147,7 +222,7 @@ impl WebServer {
                     let _ = request.respond(response);
                 } else {
                     let action = &matching_actions[0];
-                    if let Some(caps) = action.get_regex().captures(&request.url().to_string()) {
+                    if let Some(caps) = action.get_regex().captures(&path) {
                         let _ = action.handle(request, &caps, context, handlebars.clone());
                     }
                 }
@@ -169,3 +244,95 @@ pub fn error_response(request: Request, error: &str) -> Result<()> {
     let _ = request.respond(response);
     Err(Error::new(ErrorKind::InvalidInput, error))
 }
+
+pub fn not_found_response(request: Request, error: &str) -> Result<()> {
+    let response = Response::empty(StatusCode(404));
+    let _ = request.respond(response);
+    Err(Error::new(ErrorKind::NotFound, error))
+}
+
+pub fn http_date(timestamp: i64) -> String {
+    NaiveDateTime::from_timestamp(timestamp, 0)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// A weak ETag for generated content, which may differ in ways that don't
+/// matter, e.g. in the order of JSON keys.
+fn weak_etag(data: &[u8]) -> String {
+    let mut hasher = sha2::Sha256::new();
+    hasher.input(data);
+    format!("W/\"{}\"", hasher.result()[..16].to_hex())
+}
+
+/// Checks the conditional headers of a GET or HEAD request against the
+/// current version of a resource. `If-None-Match` takes precedence over
+/// `If-Modified-Since`, and is compared weakly as the standard requires.
+pub fn is_not_modified(request: &Request, etag: &str, modified: Option<i64>) -> bool {
+    if *request.method() != Method::Get && *request.method() != Method::Head {
+        return false;
+    }
+
+    if let Some(if_none_match) = get_header(request, "If-None-Match") {
+        let opaque = |x: &str| x.trim().trim_start_matches("W/").to_string();
+        return if_none_match
+            .split(',')
+            .any(|x| x.trim() == "*" || opaque(x) == opaque(etag));
+    }
+
+    let since = get_header(request, "If-Modified-Since")
+        .and_then(|x| DateTime::parse_from_rfc2822(&x).ok())
+        .map(|x| x.timestamp());
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Answers a conditional request with 304. `headers` should be the ones a
+/// full response would have had for caching, e.g. the ETag.
+pub fn not_modified_response(request: Request, headers: Vec<Header>) -> Result<()> {
+    let mut response = Response::empty(StatusCode(304));
+    for header in headers {
+        response.add_header(header);
+    }
+    request.respond(response)
+}
+
+/// Sends generated content with a weak ETag, so that clients revalidating
+/// their copy only get it again if it changed.
+fn generated_response(request: Request, data: String, content_type: &str) -> Result<()> {
+    let etag = weak_etag(data.as_bytes());
+    let headers = vec![
+        Header {
+            field: "ETag".parse::<HeaderField>().unwrap(),
+            value: etag.parse().unwrap(),
+        },
+        Header {
+            field: "Cache-Control".parse::<HeaderField>().unwrap(),
+            value: "no-cache".parse().unwrap(),
+        },
+    ];
+
+    if is_not_modified(&request, &etag, None) {
+        return not_modified_response(request, headers);
+    }
+
+    let mut response = Response::from_string(data);
+    for header in headers {
+        response.add_header(header);
+    }
+    response.add_header(Header {
+        field: "Content-Type".parse::<HeaderField>().unwrap(),
+        value: content_type.parse().unwrap(),
+    });
+    request.respond(response)
+}
+
+pub fn html_response(request: Request, html_data: String) -> Result<()> {
+    generated_response(request, html_data, "text/html")
+}
+
+pub fn json_response(request: Request, data: &Json) -> Result<()> {
+    generated_response(request, data.to_string(), "application/json")
+}
@@ -0,0 +1,551 @@
+use std::fs::{self, read_dir, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use regex::{self, Regex};
+
+use crate::db::ImageMeta;
+use crate::file::is_media;
+
+/// Namespaces of the properties written to sidecars, which are expected to
+/// use their usual prefixes.
+const XMP_NAMESPACE: (&str, &str) = ("xmlns:xmp", "http://ns.adobe.com/xap/1.0/");
+const DC_NAMESPACE: (&str, &str) = ("xmlns:dc", "http://purl.org/dc/elements/1.1/");
+
+/// Colour labels as Lightroom names them, in the order darktable numbers
+/// them in its `colorlabels`.
+pub const LABELS: &[&str] = &["Red", "Yellow", "Green", "Blue", "Purple"];
+
+const EMPTY_SIDECAR: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="">
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+/// The metadata shared with desktop tools through sidecars. Caption and
+/// tags are `None` if the sidecar doesn't have them, in which case those
+/// embedded in the image apply.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct XmpData {
+    pub caption: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Stars from 1 to 5, unrated and rejected images have none.
+    pub rating: Option<u32>,
+    pub label: Option<String>,
+}
+
+impl XmpData {
+    pub fn from_meta(meta: &ImageMeta) -> XmpData {
+        XmpData {
+            caption: meta.caption.clone(),
+            tags: Some(meta.tags.clone()),
+            rating: meta.rating,
+            label: meta.label.clone(),
+        }
+    }
+}
+
+pub fn is_sidecar(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .map(|x| x.eq_ignore_ascii_case("xmp"))
+        .unwrap_or(false)
+}
+
+/// The sidecar darktable keeps for a file, e.g. `IMG_0001.CR2.xmp`.
+pub fn own_sidecar(file: &Path) -> PathBuf {
+    let mut name = file.as_os_str().to_owned();
+    name.push(".xmp");
+    PathBuf::from(name)
+}
+
+/// Finds the sidecar of a file, preferring darktable's over Lightroom's,
+/// which replaces the extension and is shared by RAW and JPEG.
+pub fn find_sidecar(file: &Path) -> Option<PathBuf> {
+    vec![
+        own_sidecar(file),
+        file.with_extension("xmp"),
+        file.with_extension("XMP"),
+    ]
+    .into_iter()
+    .find(|x| x.is_file())
+}
+
+/// Lists the files a sidecar may belong to.
+pub fn sidecar_owners(sidecar: &Path) -> Vec<PathBuf> {
+    let file = sidecar.with_extension("");
+    if is_media(&file) {
+        return vec![file];
+    }
+
+    let dir = match sidecar.parent() {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let entries = match read_dir(dir) {
+        Ok(x) => x,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
+        .filter(|x| x.file_stem() == file.file_name() && is_media(x))
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape(value: &str) -> String {
+    let entity = Regex::new(r"&(#x[0-9A-Fa-f]+|#[0-9]+|[a-z]+);").unwrap();
+    entity
+        .replace_all(value, |caps: &regex::Captures| {
+            let name = &caps[1];
+            let code = if name.starts_with("#x") {
+                u32::from_str_radix(&name[2..], 16).ok()
+            } else if name.starts_with('#') {
+                name[1..].parse::<u32>().ok()
+            } else {
+                None
+            };
+
+            match (name, code.and_then(std::char::from_u32)) {
+                (_, Some(c)) => c.to_string(),
+                ("amp", _) => "&".to_string(),
+                ("lt", _) => "<".to_string(),
+                ("gt", _) => ">".to_string(),
+                ("quot", _) => "\"".to_string(),
+                ("apos", _) => "'".to_string(),
+                _ => caps[0].to_string(),
+            }
+        })
+        .into_owned()
+}
+
+/// Matches a property written as an attribute of a description, including
+/// the whitespace before it.
+fn attribute_regex(name: &str) -> Regex {
+    Regex::new(&format!(
+        r#"(\s+){}\s*=\s*(?:"([^"]*)"|'([^']*)')"#,
+        regex::escape(name)
+    ))
+    .unwrap()
+}
+
+/// Matches a property written as an element, including the whitespace
+/// before it.
+fn element_regex(name: &str) -> Regex {
+    Regex::new(&format!(
+        r"(?s)(\s*)(?:<{0}\s*>(.*?)</{0}\s*>|<{0}\s*/>)",
+        regex::escape(name)
+    ))
+    .unwrap()
+}
+
+fn simple_property(xml: &str, name: &str) -> Option<String> {
+    let value = match attribute_regex(name).captures(xml) {
+        Some(caps) => caps.get(2).or_else(|| caps.get(3))?.as_str().to_string(),
+        None => element_regex(name)
+            .captures(xml)?
+            .get(2)?
+            .as_str()
+            .to_string(),
+    };
+
+    Some(unescape(value.trim()))
+}
+
+/// Reads the items of a property holding a bag, sequence or alternatives.
+fn list_property(xml: &str, name: &str) -> Option<Vec<String>> {
+    let caps = element_regex(name).captures(xml)?;
+    let item = Regex::new(r"(?s)<rdf:li(?:\s[^>]*)?>(.*?)</rdf:li>").unwrap();
+
+    Some(match caps.get(2) {
+        Some(body) => item
+            .captures_iter(body.as_str())
+            .filter_map(|x| x.get(1))
+            .map(|x| unescape(x.as_str().trim()))
+            .collect(),
+        None => Vec::new(),
+    })
+}
+
+pub fn parse(xml: &str) -> XmpData {
+    let darktable_label = || {
+        list_property(xml, "darktable:colorlabels")?
+            .iter()
+            .filter_map(|x| x.parse::<usize>().ok())
+            .filter_map(|x| LABELS.get(x))
+            .map(|x| x.to_string())
+            .next()
+    };
+
+    XmpData {
+        caption: list_property(xml, "dc:description")
+            .map(|x| x.into_iter().next().unwrap_or_default()),
+        tags: list_property(xml, "dc:subject")
+            .map(|x| x.into_iter().filter(|x| !x.is_empty()).collect()),
+        rating: simple_property(xml, "xmp:Rating")
+            .and_then(|x| x.parse::<i32>().ok())
+            .filter(|&x| x >= 1 && x <= 5)
+            .map(|x| x as u32),
+        label: simple_property(xml, "xmp:Label")
+            .filter(|x| !x.is_empty())
+            .or_else(darktable_label),
+    }
+}
+
+/// Finds the start tag of the first description, which new properties are
+/// added to. A self closing one is given an end tag first.
+fn first_description(xml: &mut String) -> Option<(usize, usize)> {
+    let start_tag = Regex::new(r"(?s)<rdf:Description\b[^>]*?(/?)>").unwrap();
+    let (start, end, self_closing) = {
+        let caps = start_tag.captures(xml)?;
+        let tag = caps.get(0)?;
+        (tag.start(), tag.end(), !caps[1].is_empty())
+    };
+
+    if self_closing {
+        let tag = format!("{}>\n  </rdf:Description>", xml[start..end - 2].trim_end());
+        xml.replace_range(start..end, &tag);
+    }
+
+    let end = xml[start..].find('>')? + start + 1;
+    Some((start, end))
+}
+
+fn add_attribute(xml: &mut String, name: &str, value: &str) -> Option<()> {
+    let (_, end) = first_description(xml)?;
+    let attribute = format!("\n    {}=\"{}\"", name, escape(value));
+    xml.insert_str(end - 1, &attribute);
+    Some(())
+}
+
+fn ensure_namespace(xml: &mut String, namespace: (&str, &str)) -> Option<()> {
+    if xml.contains(&format!("{}=", namespace.0)) {
+        return Some(());
+    }
+
+    add_attribute(xml, namespace.0, namespace.1)
+}
+
+/// Replaces, adds or removes a property with a single value. New ones are
+/// added as attributes of the first description.
+fn set_simple_property(xml: &mut String, name: &str, value: Option<&str>) -> Option<()> {
+    // The whitespace before the property is kept, so the layout is too
+    let attribute = attribute_regex(name).captures(xml).and_then(|caps| {
+        let x = caps.get(0)?;
+        let prefix = format!("{}{}=\"", &caps[1], name);
+        Some((x.start(), x.end(), prefix, "\"".to_string()))
+    });
+    let found = attribute.or_else(|| {
+        let caps = element_regex(name).captures(xml)?;
+        let x = caps.get(0)?;
+        let prefix = format!("{}<{}>", &caps[1], name);
+        Some((x.start(), x.end(), prefix, format!("</{}>", name)))
+    });
+
+    match (found, value) {
+        (Some((start, end, prefix, suffix)), Some(value)) => {
+            let property = format!("{}{}{}", prefix, escape(value), suffix);
+            xml.replace_range(start..end, &property)
+        }
+        (Some((start, end, _, _)), None) => xml.replace_range(start..end, ""),
+        (None, Some(value)) => add_attribute(xml, name, value)?,
+        (None, None) => {}
+    }
+
+    Some(())
+}
+
+/// Replaces, adds or removes a property holding a list in the given kind
+/// of container, e.g. `rdf:Bag`.
+fn set_list_property(
+    xml: &mut String,
+    name: &str,
+    container: &str,
+    items: Option<&[String]>,
+) -> Option<()> {
+    if let Some(x) = element_regex(name).find(xml) {
+        xml.replace_range(x.start()..x.end(), "");
+    }
+
+    let items = match items {
+        Some(x) => x,
+        None => return Some(()),
+    };
+
+    // Alternatives are the same text in different languages
+    let item_tag = if container == "rdf:Alt" {
+        "<rdf:li xml:lang=\"x-default\">"
+    } else {
+        "<rdf:li>"
+    };
+    let element = if items.is_empty() {
+        format!("\n   <{0}>\n    <{1}/>\n   </{0}>", name, container)
+    } else {
+        let lines: Vec<String> = items
+            .iter()
+            .map(|x| format!("\n     {}{}</rdf:li>", item_tag, escape(x)))
+            .collect();
+        format!(
+            "\n   <{0}>\n    <{1}>{2}\n    </{1}>\n   </{0}>",
+            name,
+            container,
+            lines.concat()
+        )
+    };
+
+    let (start, _) = first_description(xml)?;
+    let end_tag = xml[start..].find("</rdf:Description>")? + start;
+    let insert_at = xml[..end_tag].trim_end().len();
+    xml.insert_str(insert_at, &element);
+
+    Some(())
+}
+
+/// Writes the metadata into a sidecar, leaving everything else in it as it
+/// was. Returns `None` if the sidecar has no description to add it to.
+pub fn update(xml: &str, data: &XmpData) -> Option<String> {
+    let mut xml = xml.to_string();
+    ensure_namespace(&mut xml, XMP_NAMESPACE)?;
+    ensure_namespace(&mut xml, DC_NAMESPACE)?;
+
+    let rating = data.rating.map(|x| x.to_string());
+    set_simple_property(&mut xml, "xmp:Rating", rating.as_ref().map(|x| x.as_str()))?;
+    set_simple_property(
+        &mut xml,
+        "xmp:Label",
+        data.label.as_ref().map(|x| x.as_str()),
+    )?;
+
+    // darktable keeps colour labels of its own
+    if xml.contains("xmlns:darktable=") {
+        let labels: Vec<String> = data
+            .label
+            .as_ref()
+            .and_then(|label| LABELS.iter().position(|x| x == label))
+            .map(|x| vec![x.to_string()])
+            .unwrap_or_default();
+        set_list_property(&mut xml, "darktable:colorlabels", "rdf:Seq", Some(&labels))?;
+    }
+
+    let caption = data.caption.as_ref().map(|x| vec![x.clone()]);
+    set_list_property(
+        &mut xml,
+        "dc:description",
+        "rdf:Alt",
+        caption.as_ref().map(|x| x.as_slice()),
+    )?;
+    set_list_property(
+        &mut xml,
+        "dc:subject",
+        "rdf:Bag",
+        data.tags.as_ref().map(|x| x.as_slice()),
+    )?;
+
+    Some(xml)
+}
+
+/// Reads the sidecar of a file, if it has one.
+pub fn read_sidecar(file: &Path) -> Option<XmpData> {
+    let xml = fs::read_to_string(find_sidecar(file)?).ok()?;
+    Some(parse(&xml))
+}
+
+/// Writes metadata to the sidecar of a file, creating one if there is none.
+/// Returns the path of the sidecar.
+pub fn write_sidecar(file: &Path, data: &XmpData) -> io::Result<PathBuf> {
+    let path = find_sidecar(file).unwrap_or_else(|| own_sidecar(file));
+    let xml = if path.exists() {
+        fs::read_to_string(&path)?
+    } else {
+        EMPTY_SIDECAR.to_string()
+    };
+
+    let xml = update(&xml, data).ok_or(io::Error::new(
+        ErrorKind::InvalidData,
+        "Sidecar has no description",
+    ))?;
+
+    // Written next to the sidecar and renamed over it, so that a failed
+    // write never leaves a truncated sidecar behind
+    let file_name = path
+        .file_name()
+        .and_then(|x| x.to_str())
+        .ok_or(io::Error::new(ErrorKind::InvalidInput, "Invalid file name"))?;
+    let temp = path.with_file_name(format!(".{}.tmp", file_name));
+    let result = File::create(&temp)
+        .and_then(|mut x| x.write_all(xml.as_bytes()).and_then(|_| x.sync_all()))
+        .and_then(|_| fs::rename(&temp, &path));
+    if let Err(e) = result {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIGHTROOM_SIDECAR: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/"
+    xmp:Rating="4"
+    xmp:Label="Green">
+   <photoshop:City>Oslo</photoshop:City>
+   <dc:description>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">Sunset &amp; sea &#x263A;</rdf:li>
+    </rdf:Alt>
+   </dc:description>
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>beach</rdf:li>
+     <rdf:li>holiday</rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+    const DARKTABLE_SIDECAR: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:darktable="http://darktable.sf.net/"
+    darktable:xmp_version="3">
+   <xmp:Rating>-1</xmp:Rating>
+   <darktable:colorlabels>
+    <rdf:Seq>
+     <rdf:li>1</rdf:li>
+    </rdf:Seq>
+   </darktable:colorlabels>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(LIGHTROOM_SIDECAR),
+            XmpData {
+                caption: Some("Sunset & sea \u{263A}".to_string()),
+                tags: Some(vec!["beach".to_string(), "holiday".to_string()]),
+                rating: Some(4),
+                label: Some("Green".to_string()),
+            }
+        );
+
+        // Rejected images have a rating of -1, and the label comes from
+        // darktable's own list
+        assert_eq!(
+            parse(DARKTABLE_SIDECAR),
+            XmpData {
+                caption: None,
+                tags: None,
+                rating: None,
+                label: Some("Yellow".to_string()),
+            }
+        );
+
+        assert_eq!(parse(EMPTY_SIDECAR), XmpData::default());
+    }
+
+    #[test]
+    fn test_update_round_trip() {
+        let data = XmpData {
+            caption: Some("Fish & <chips>".to_string()),
+            tags: Some(vec!["a".to_string(), "b \"c\"".to_string()]),
+            rating: Some(5),
+            label: Some("Red".to_string()),
+        };
+
+        let xml = update(EMPTY_SIDECAR, &data).unwrap();
+        assert_eq!(parse(&xml), data);
+
+        // Updating again with the same data changes nothing
+        assert_eq!(update(&xml, &data).unwrap(), xml);
+    }
+
+    #[test]
+    fn test_update_existing() {
+        let data = XmpData {
+            caption: None,
+            tags: Some(Vec::new()),
+            rating: None,
+            label: Some("Blue".to_string()),
+        };
+
+        let xml = update(LIGHTROOM_SIDECAR, &data).unwrap();
+        assert_eq!(parse(&xml), data);
+        assert!(!xml.contains("xmp:Rating"));
+        assert!(!xml.contains("dc:description"));
+
+        // Properties hostimg doesn't know about are left alone
+        assert!(xml.contains("<photoshop:City>Oslo</photoshop:City>"));
+        assert!(xml.contains("xmlns:photoshop="));
+    }
+
+    #[test]
+    fn test_update_darktable_labels() {
+        let mut data = parse(DARKTABLE_SIDECAR);
+        data.label = Some("Purple".to_string());
+        let xml = update(DARKTABLE_SIDECAR, &data).unwrap();
+        assert_eq!(parse(&xml), data);
+        assert_eq!(
+            list_property(&xml, "darktable:colorlabels"),
+            Some(vec!["4".to_string()])
+        );
+        assert!(xml.contains(r#"darktable:xmp_version="3""#));
+
+        data.label = None;
+        let xml = update(&xml, &data).unwrap();
+        assert_eq!(parse(&xml), data);
+        assert_eq!(
+            list_property(&xml, "darktable:colorlabels"),
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_update_self_closing_description() {
+        let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="2"/>
+ </rdf:RDF>
+</x:xmpmeta>
+"#;
+        let data = XmpData {
+            caption: None,
+            tags: Some(vec!["x".to_string()]),
+            rating: Some(2),
+            label: None,
+        };
+
+        let xml = update(xml, &data).unwrap();
+        assert_eq!(parse(&xml), data);
+        assert!(xml.contains("</rdf:Description>"));
+    }
+
+    #[test]
+    fn test_update_without_description() {
+        assert!(update("<x:xmpmeta/>", &XmpData::default()).is_none());
+    }
+}